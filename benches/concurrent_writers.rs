@@ -0,0 +1,71 @@
+//! Write throughput of [`VehicleShadow`] with 1 to 8 threads, each writing
+//! its own signal. Without a global lock the throughput grows with the
+//! number of writers.
+//!
+//! Run with `cargo bench --bench concurrent_writers`.
+
+use std::time::{Duration, Instant};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use vehicle_signal_shadow::signal::{Config, LeafType, Signal, State, Value, ValueType};
+use vehicle_signal_shadow::vehicle_shadow::VehicleShadow;
+
+const WRITES_PER_WRITER: u64 = 1_000;
+
+fn create_signal(path: &str) -> Signal {
+    Signal {
+        path: path.to_string(),
+        id: 0,
+        state: State {
+            value: Value::Uint32(0),
+            capability: true,
+            availability: true,
+            ..Default::default()
+        },
+        config: Config {
+            leaf_type: LeafType::Sensor,
+            data_type: ValueType::TypeUint32,
+            ..Default::default()
+        },
+    }
+}
+
+// Time until every writer has written its signal WRITES_PER_WRITER times.
+fn write_concurrently(vehicle_shadow: &VehicleShadow, paths: &[String]) -> Duration {
+    let started = Instant::now();
+    std::thread::scope(|scope| {
+        for path in paths {
+            scope.spawn(move || {
+                for count in 0..WRITES_PER_WRITER {
+                    vehicle_shadow
+                        .update_signal(path, &None, |signal| {
+                            signal.state.value = Value::Uint32(count as u32);
+                            Ok(())
+                        })
+                        .unwrap();
+                }
+            });
+        }
+    });
+    started.elapsed()
+}
+
+fn concurrent_writers(c: &mut Criterion) {
+    let mut group = c.benchmark_group("concurrent_writers");
+    for writers in [1, 2, 4, 8] {
+        let vehicle_shadow = VehicleShadow::create().unwrap();
+        let paths: Vec<String> = (0..writers).map(|writer| format!("Vehicle.Bench.Signal{}", writer)).collect();
+        for path in &paths {
+            vehicle_shadow.set_signal(create_signal(path), &None).unwrap();
+        }
+
+        group.throughput(Throughput::Elements(writers * WRITES_PER_WRITER));
+        group.bench_with_input(BenchmarkId::from_parameter(writers), &paths, |b, paths| {
+            b.iter_custom(|iterations| (0..iterations).map(|_| write_concurrently(&vehicle_shadow, paths)).sum());
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, concurrent_writers);
+criterion_main!(benches);
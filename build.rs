@@ -1,7 +1,7 @@
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     tonic_build::configure()
         .build_server(true)
-        .compile(&["external/vehicle-protocol/proto/vehicle-shadow/signal.proto"], &["external/vehicle-protocol/proto"])?;
+        .compile(&["proto/vehicle-shadow/signal.proto"], &["proto"])?;
 
     Ok(())
 }
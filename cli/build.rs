@@ -1,6 +1,6 @@
 fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     tonic_build::configure()
         .build_client(true)
-        .compile(&["../external/vehicle-protocol/proto/vehicle-shadow/signal.proto"], &["../external/vehicle-protocol/proto"])?;
+        .compile(&["../proto/vehicle-shadow/signal.proto"], &["../proto"])?;
     Ok(())
-} 
\ No newline at end of file
+}
@@ -0,0 +1,425 @@
+use anyhow::Result;
+use tonic::transport::Channel;
+use tonic::Streaming;
+
+// 生成されたprotoファイルをインポート
+pub mod vehicle_shadow {
+    #![allow(clippy::enum_variant_names)]
+    tonic::include_proto!("vehicle_shadow");
+}
+
+pub use vehicle_shadow::*;
+use vehicle_shadow::signal_service_client::SignalServiceClient;
+
+// サーバーの番号と、そのサーバーへ送るパスごとの要素
+type ServerGroup<T> = (usize, Vec<(String, T)>);
+
+// パスのプレフィックスごとに接続先のサーバーを持つクライアント
+// パスを持たない要求やどのプレフィックスにも当たらないパスは最初に接続したサーバーへ送る
+pub struct VehicleShadowClient {
+    servers: Vec<(String, SignalServiceClient<Channel>)>,
+}
+
+impl VehicleShadowClient {
+    pub async fn create() -> Result<Self> {
+        Ok(Self { servers: Vec::new() })
+    }
+
+    pub async fn connect(&mut self, endpoint: &str, prefix: String) -> Result<()> {
+        let client = SignalServiceClient::connect(endpoint.to_string()).await?;
+        self.servers.push((prefix, client));
+        Ok(())
+    }
+
+    fn server_index(&self, path: &str) -> Result<usize> {
+        if self.servers.is_empty() {
+            return Err(anyhow::anyhow!("Not connected to any server"));
+        }
+        let index = self.servers.iter().position(|(prefix, _)| {
+            path == prefix || path.strip_prefix(prefix.as_str()).is_some_and(|rest| rest.starts_with('.'))
+        });
+        Ok(index.unwrap_or(0))
+    }
+
+    fn server_for(&mut self, path: &str) -> Result<&mut SignalServiceClient<Channel>> {
+        let index = self.server_index(path)?;
+        Ok(&mut self.servers[index].1)
+    }
+
+    fn default_server(&mut self) -> Result<&mut SignalServiceClient<Channel>> {
+        self.server_for("")
+    }
+
+    // パスをサーバーごとに振り分ける (順序はサーバー内で保つ)
+    fn group_by_server<T>(&self, items: Vec<(String, T)>) -> Result<Vec<ServerGroup<T>>> {
+        let mut groups: Vec<ServerGroup<T>> = Vec::new();
+        for (path, item) in items {
+            let index = self.server_index(&path)?;
+            match groups.iter_mut().find(|(group, _)| *group == index) {
+                Some((_, group)) => group.push((path, item)),
+                None => groups.push((index, vec![(path, item)])),
+            }
+        }
+        Ok(groups)
+    }
+
+    pub async fn get_signals(&mut self, paths: Vec<String>) -> Result<GetResponse> {
+        let mut merged = GetResponse { signals: Vec::new(), success: true, error_message: String::new() };
+        let groups = self.group_by_server(paths.into_iter().map(|path| (path, ())).collect())?;
+        for (index, group) in groups {
+            let request = GetRequest { paths: group.into_iter().map(|(path, _)| path).collect(), ids: Vec::new() };
+            let response = self.servers[index].1.get(request).await?.into_inner();
+            merged.signals.extend(response.signals);
+            if !response.success {
+                merged.success = false;
+                merged.error_message = response.error_message;
+            }
+        }
+        Ok(merged)
+    }
+
+    pub async fn set_signals(&mut self, signals: Vec<(String, State)>, token: String) -> Result<SetResponse> {
+        let mut merged = SetResponse { results: Vec::new(), success: true, error_message: String::new() };
+        for (index, group) in self.group_by_server(signals)? {
+            let request = SetRequest {
+                signals: group
+                    .into_iter()
+                    .map(|(path, state)| SetSignalRequest { path, state: Some(state), id: 0 })
+                    .collect(),
+                token: token.clone(),
+                atomic: false,
+            };
+            let response = self.servers[index].1.set(request).await?.into_inner();
+            merged.results.extend(response.results);
+            if !response.success {
+                merged.success = false;
+                merged.error_message = response.error_message;
+            }
+        }
+        Ok(merged)
+    }
+
+    pub async fn subscribe(&mut self, path: String) -> Result<Streaming<SubscribeResponse>> {
+        let request = SubscribeRequest { paths: vec![path.clone()], ..Default::default() };
+        Ok(self.server_for(&path)?.subscribe(request).await?.into_inner())
+    }
+
+    // 購読IDやトークンからは発行したサーバーが分からないので、受け付けられるまで順に試す
+    pub async fn unsubscribe(&mut self, subscription_id: String) -> Result<UnsubscribeResponse> {
+        let mut response = UnsubscribeResponse { success: false, error_message: "Not connected to any server".to_string() };
+        for (_, server) in self.servers.iter_mut() {
+            let request = UnsubscribeRequest { subscription_id: subscription_id.clone() };
+            response = server.unsubscribe(request).await?.into_inner();
+            if response.success {
+                break;
+            }
+        }
+        Ok(response)
+    }
+
+    pub async fn lock(&mut self, paths: Vec<String>) -> Result<LockResponse> {
+        let path = paths.first().cloned().unwrap_or_default();
+        let request = LockRequest { paths, ..Default::default() };
+        Ok(self.server_for(&path)?.lock(request).await?.into_inner())
+    }
+
+    pub async fn unlock(&mut self, token: String) -> Result<UnlockResponse> {
+        let mut response = UnlockResponse { success: false };
+        for (_, server) in self.servers.iter_mut() {
+            response = server.unlock(UnlockRequest { token: token.clone() }).await?.into_inner();
+            if response.success {
+                break;
+            }
+        }
+        Ok(response)
+    }
+
+    pub async fn get_history(
+        &mut self,
+        path: String,
+        from: Option<u64>,
+        to: Option<u64>,
+        limit: Option<u32>,
+    ) -> Result<GetHistoryResponse> {
+        let request = GetHistoryRequest { path: path.clone(), from, to, limit };
+        Ok(self.server_for(&path)?.get_history(request).await?.into_inner())
+    }
+
+    // ロックはサーバーごとに管理されるので全サーバー分をまとめる
+    pub async fn list_locks(&mut self) -> Result<ListLocksResponse> {
+        let mut merged = ListLocksResponse { locks: Vec::new(), success: true, error_message: String::new() };
+        for (_, server) in self.servers.iter_mut() {
+            let response = server.list_locks(ListLocksRequest {}).await?.into_inner();
+            merged.locks.extend(response.locks);
+            if !response.success {
+                merged.success = false;
+                merged.error_message = response.error_message;
+            }
+        }
+        Ok(merged)
+    }
+
+    pub async fn force_unlock(&mut self, paths: Vec<String>) -> Result<ForceUnlockResponse> {
+        let mut merged = ForceUnlockResponse { released: Vec::new(), success: true, error_message: String::new() };
+        let groups = self.group_by_server(paths.into_iter().map(|path| (path, ())).collect())?;
+        for (index, group) in groups {
+            let request = ForceUnlockRequest { paths: group.into_iter().map(|(path, _)| path).collect() };
+            let response = self.servers[index].1.force_unlock(request).await?.into_inner();
+            merged.released.extend(response.released);
+            if !response.success {
+                merged.success = false;
+                merged.error_message = response.error_message;
+            }
+        }
+        Ok(merged)
+    }
+
+    pub async fn list_signals(
+        &mut self,
+        prefix: String,
+        recursive: bool,
+        page_size: u32,
+        page_token: String,
+    ) -> Result<ListSignalsResponse> {
+        let request = ListSignalsRequest { prefix: prefix.clone(), recursive, page_size, page_token };
+        Ok(self.server_for(&prefix)?.list_signals(request).await?.into_inner())
+    }
+
+    pub async fn apply_overlay(&mut self, overlay_json: String) -> Result<ApplyOverlayResponse> {
+        let request = ApplyOverlayRequest { overlay_json };
+        Ok(self.default_server()?.apply_overlay(request).await?.into_inner())
+    }
+
+    pub async fn put_alert_rule(&mut self, rule: AlertRule) -> Result<PutAlertRuleResponse> {
+        let path = rule.path.clone();
+        let request = PutAlertRuleRequest { rule: Some(rule) };
+        Ok(self.server_for(&path)?.put_alert_rule(request).await?.into_inner())
+    }
+
+    // ルールIDからは登録先のサーバーが分からないので、削除できるまで順に試す
+    pub async fn remove_alert_rule(&mut self, id: String) -> Result<RemoveAlertRuleResponse> {
+        let mut response = RemoveAlertRuleResponse { success: false, error_message: "Not connected to any server".to_string() };
+        for (_, server) in self.servers.iter_mut() {
+            response = server.remove_alert_rule(RemoveAlertRuleRequest { id: id.clone() }).await?.into_inner();
+            if response.success {
+                break;
+            }
+        }
+        Ok(response)
+    }
+
+    pub async fn list_alert_rules(&mut self) -> Result<ListAlertRulesResponse> {
+        let mut merged = ListAlertRulesResponse { rules: Vec::new(), success: true, error_message: String::new() };
+        for (_, server) in self.servers.iter_mut() {
+            let response = server.list_alert_rules(ListAlertRulesRequest {}).await?.into_inner();
+            merged.rules.extend(response.rules);
+            if !response.success {
+                merged.success = false;
+                merged.error_message = response.error_message;
+            }
+        }
+        Ok(merged)
+    }
+
+    // 各サーバーの履歴を時刻順に並べ直し、新しいものからlimit件を残す
+    pub async fn list_alerts(&mut self, limit: u32) -> Result<ListAlertsResponse> {
+        let mut merged = ListAlertsResponse { active: Vec::new(), history: Vec::new(), success: true, error_message: String::new() };
+        for (_, server) in self.servers.iter_mut() {
+            let response = server.list_alerts(ListAlertsRequest { limit }).await?.into_inner();
+            merged.active.extend(response.active);
+            merged.history.extend(response.history);
+            if !response.success {
+                merged.success = false;
+                merged.error_message = response.error_message;
+            }
+        }
+        merged.history.sort_by_key(|alert| alert.timestamp.as_ref().map_or(0, |timestamp| timestamp.wall_clock_ns));
+        if limit > 0 {
+            let excess = merged.history.len().saturating_sub(limit as usize);
+            merged.history.drain(..excess);
+        }
+        Ok(merged)
+    }
+
+    pub async fn subscribe_alerts(&mut self) -> Result<Streaming<Alert>> {
+        Ok(self.default_server()?.subscribe_alerts(SubscribeAlertsRequest {}).await?.into_inner())
+    }
+
+    pub async fn reset_signals(&mut self, paths: Vec<String>) -> Result<ResetSignalsResponse> {
+        let mut merged = ResetSignalsResponse { paths: Vec::new(), success: true, error_message: String::new() };
+        let groups = self.group_by_server(paths.into_iter().map(|path| (path, ())).collect())?;
+        for (index, group) in groups {
+            let request = ResetSignalsRequest { paths: group.into_iter().map(|(path, _)| path).collect(), token: String::new() };
+            let response = self.servers[index].1.reset_signals(request).await?.into_inner();
+            merged.paths.extend(response.paths);
+            if !response.success {
+                merged.success = false;
+                merged.error_message = response.error_message;
+            }
+        }
+        Ok(merged)
+    }
+
+    pub async fn factory_reset(&mut self) -> Result<FactoryResetResponse> {
+        let mut merged = FactoryResetResponse { success: true, error_message: String::new(), reset: 0 };
+        for (_, server) in self.servers.iter_mut() {
+            let response = server.factory_reset(FactoryResetRequest {}).await?.into_inner();
+            merged.reset += response.reset;
+            if !response.success {
+                merged.success = false;
+                merged.error_message = response.error_message;
+            }
+        }
+        Ok(merged)
+    }
+
+    // スナップショットは1つのサーバーの内容なので最初に接続したサーバーとやり取りする
+    pub async fn export(&mut self, encoding: SnapshotEncoding) -> Result<ExportResponse> {
+        let request = ExportRequest { encoding: encoding as i32 };
+        Ok(self.default_server()?.export(request).await?.into_inner())
+    }
+
+    pub async fn import(&mut self, snapshot: Vec<u8>) -> Result<ImportResponse> {
+        Ok(self.default_server()?.import(ImportRequest { snapshot }).await?.into_inner())
+    }
+}
+
+pub fn format_value(value: &Value) -> String {
+    use value::Value as V;
+
+    fn join<T: ToString>(values: &[T]) -> String {
+        let values: Vec<String> = values.iter().map(ToString::to_string).collect();
+        format!("[{}]", values.join(", "))
+    }
+
+    match &value.value {
+        None => "NaN".to_string(),
+        Some(V::BoolValue(v)) => v.to_string(),
+        Some(V::StringValue(v)) => format!("{:?}", v),
+        Some(V::Int8Value(v)) | Some(V::Int16Value(v)) | Some(V::Int32Value(v)) => v.to_string(),
+        Some(V::Int64Value(v)) => v.to_string(),
+        Some(V::Uint8Value(v)) | Some(V::Uint16Value(v)) | Some(V::Uint32Value(v)) => v.to_string(),
+        Some(V::Uint64Value(v)) => v.to_string(),
+        Some(V::FloatValue(v)) => v.to_string(),
+        Some(V::DoubleValue(v)) => v.to_string(),
+        Some(V::BoolArrayValue(v)) => join(&v.values),
+        Some(V::StringArrayValue(v)) => {
+            let values: Vec<String> = v.values.iter().map(|s| format!("{:?}", s)).collect();
+            format!("[{}]", values.join(", "))
+        }
+        Some(V::Int8ArrayValue(v)) => join(&v.values),
+        Some(V::Int16ArrayValue(v)) => join(&v.values),
+        Some(V::Int32ArrayValue(v)) => join(&v.values),
+        Some(V::Int64ArrayValue(v)) => join(&v.values),
+        Some(V::Uint8ArrayValue(v)) => join(&v.values),
+        Some(V::Uint16ArrayValue(v)) => join(&v.values),
+        Some(V::Uint32ArrayValue(v)) => join(&v.values),
+        Some(V::Uint64ArrayValue(v)) => join(&v.values),
+        Some(V::FloatArrayValue(v)) => join(&v.values),
+        Some(V::DoubleArrayValue(v)) => join(&v.values),
+    }
+}
+
+pub fn format_signal(signal: &Signal) -> String {
+    let mut text = signal.path.clone();
+    if let Some(state) = &signal.state {
+        if let Some(value) = &state.value {
+            text.push_str(&format!("\n  Value: {}", format_value(value)));
+        }
+        if let Some(target) = &state.target {
+            text.push_str(&format!("\n  Target: {}", format_value(target)));
+        }
+        text.push_str(&format!("\n  Capability: {}", state.capability.unwrap_or(false)));
+        text.push_str(&format!("\n  Availability: {}", state.availability.unwrap_or(false)));
+        if let Some(reserved) = &state.reserved {
+            text.push_str(&format!("\n  Reserved: {}", reserved));
+        }
+    }
+    if let Some(unit) = signal.config.as_ref().and_then(|config| config.unit.as_ref()) {
+        text.push_str(&format!("\n  Unit: {}", unit));
+    }
+    text
+}
+
+// JSONの値をシグナルの型に合わせて変換する (サーバーは型の違う値を受け付けない)
+pub fn parse_value_from_json(json: &str, data_type: ValueType) -> Result<Value> {
+    let json: serde_json::Value = serde_json::from_str(json)?;
+    json_to_value(&json, data_type)
+}
+
+// {"value": ..., "capability": ..., "availability": ..., "reserved": ...} の形式。省略したフィールドは更新しない
+pub fn parse_state_from_json(json: &str, data_type: ValueType) -> Result<State> {
+    let json: serde_json::Value = serde_json::from_str(json)?;
+    let object = json.as_object().ok_or_else(|| anyhow::anyhow!("State must be a JSON object"))?;
+    const FIELDS: [&str; 4] = ["value", "capability", "availability", "reserved"];
+    if object.is_empty() || object.keys().any(|key| !FIELDS.contains(&key.as_str())) {
+        return Err(anyhow::anyhow!("State fields must be one of {:?}", FIELDS));
+    }
+
+    let flag = |key: &str| -> Result<Option<bool>> {
+        object
+            .get(key)
+            .map(|v| v.as_bool().ok_or_else(|| anyhow::anyhow!("{} must be a boolean", key)))
+            .transpose()
+    };
+    Ok(State {
+        value: object.get("value").map(|v| json_to_value(v, data_type)).transpose()?,
+        capability: flag("capability")?,
+        availability: flag("availability")?,
+        reserved: object
+            .get("reserved")
+            .map(|v| v.as_str().map(str::to_string).ok_or_else(|| anyhow::anyhow!("reserved must be a string")))
+            .transpose()?,
+        ..Default::default()
+    })
+}
+
+fn json_to_value(json: &serde_json::Value, data_type: ValueType) -> Result<Value> {
+    use value::Value as V;
+
+    let mismatch = || anyhow::anyhow!("{} is not a valid {}", json, data_type.as_str_name());
+    let int = |json: &serde_json::Value| json.as_i64().ok_or_else(mismatch);
+    let uint = |json: &serde_json::Value| json.as_u64().ok_or_else(mismatch);
+    let float = |json: &serde_json::Value| json.as_f64().ok_or_else(mismatch);
+    let narrow_int = |json: &serde_json::Value| -> Result<i32> { Ok(i32::try_from(int(json)?)?) };
+    let narrow_uint = |json: &serde_json::Value| -> Result<u32> { Ok(u32::try_from(uint(json)?)?) };
+    let elements = || json.as_array().ok_or_else(mismatch);
+
+    let value = match data_type {
+        ValueType::TypeNan => return Err(mismatch()),
+        ValueType::TypeBool => V::BoolValue(json.as_bool().ok_or_else(mismatch)?),
+        ValueType::TypeString => V::StringValue(json.as_str().ok_or_else(mismatch)?.to_string()),
+        ValueType::TypeInt8 => V::Int8Value(narrow_int(json)?),
+        ValueType::TypeInt16 => V::Int16Value(narrow_int(json)?),
+        ValueType::TypeInt32 => V::Int32Value(narrow_int(json)?),
+        ValueType::TypeInt64 => V::Int64Value(int(json)?),
+        ValueType::TypeUint8 => V::Uint8Value(narrow_uint(json)?),
+        ValueType::TypeUint16 => V::Uint16Value(narrow_uint(json)?),
+        ValueType::TypeUint32 => V::Uint32Value(narrow_uint(json)?),
+        ValueType::TypeUint64 => V::Uint64Value(uint(json)?),
+        ValueType::TypeFloat => V::FloatValue(float(json)? as f32),
+        ValueType::TypeDouble => V::DoubleValue(float(json)?),
+        ValueType::TypeBoolArray => V::BoolArrayValue(BoolArray {
+            values: elements()?.iter().map(|v| v.as_bool().ok_or_else(mismatch)).collect::<Result<_>>()?,
+        }),
+        ValueType::TypeStringArray => V::StringArrayValue(StringArray {
+            values: elements()?
+                .iter()
+                .map(|v| v.as_str().map(str::to_string).ok_or_else(mismatch))
+                .collect::<Result<_>>()?,
+        }),
+        ValueType::TypeInt8Array => V::Int8ArrayValue(Int8Array { values: elements()?.iter().map(narrow_int).collect::<Result<_>>()? }),
+        ValueType::TypeInt16Array => V::Int16ArrayValue(Int16Array { values: elements()?.iter().map(narrow_int).collect::<Result<_>>()? }),
+        ValueType::TypeInt32Array => V::Int32ArrayValue(Int32Array { values: elements()?.iter().map(narrow_int).collect::<Result<_>>()? }),
+        ValueType::TypeInt64Array => V::Int64ArrayValue(Int64Array { values: elements()?.iter().map(int).collect::<Result<_>>()? }),
+        ValueType::TypeUint8Array => V::Uint8ArrayValue(Uint8Array { values: elements()?.iter().map(narrow_uint).collect::<Result<_>>()? }),
+        ValueType::TypeUint16Array => V::Uint16ArrayValue(Uint16Array { values: elements()?.iter().map(narrow_uint).collect::<Result<_>>()? }),
+        ValueType::TypeUint32Array => V::Uint32ArrayValue(Uint32Array { values: elements()?.iter().map(narrow_uint).collect::<Result<_>>()? }),
+        ValueType::TypeUint64Array => V::Uint64ArrayValue(Uint64Array { values: elements()?.iter().map(uint).collect::<Result<_>>()? }),
+        ValueType::TypeFloatArray => V::FloatArrayValue(FloatArray {
+            values: elements()?.iter().map(|v| float(v).map(|f| f as f32)).collect::<Result<_>>()?,
+        }),
+        ValueType::TypeDoubleArray => V::DoubleArrayValue(DoubleArray { values: elements()?.iter().map(float).collect::<Result<_>>()? }),
+    };
+    Ok(Value { value: Some(value) })
+}
@@ -3,9 +3,10 @@ use anyhow::Result;
 use log::{info, error};
 use std::collections::HashMap;
 
-use tokio::io::unix::AsyncFdTryNewError;
-use vehicle_shadow_client::{
-    format_signal, format_value, parse_state_from_json, parse_value_from_json, GetResponse, SetResponse, State, UnsubscribeResponse, VehicleShadowClient
+mod client;
+
+use client::{
+    format_signal, format_value, parse_state_from_json, parse_value_from_json, Alert, AlertRule, AlertSeverity, Comparator, GetResponse, LeafType, SetResponse, SignalMetadata, SnapshotEncoding, State, UnsubscribeResponse, ValueType, VehicleShadowClient
 };
 
 #[derive(Parser)]
@@ -48,11 +49,101 @@ enum Commands {
         #[arg(required = true)]
         paths: Vec<String>,
     },
+    /// Get past values of a signal
+    History {
+        /// Signal path
+        #[arg(required = true)]
+        path: String,
+        /// Only entries written within the last N seconds
+        #[arg(long, conflicts_with = "from")]
+        last: Option<u64>,
+        /// Start of the range (UNIX time in nanoseconds)
+        #[arg(long)]
+        from: Option<u64>,
+        /// End of the range (UNIX time in nanoseconds)
+        #[arg(long)]
+        to: Option<u64>,
+        /// Maximum number of entries (most recent first kept)
+        #[arg(short, long)]
+        limit: Option<u32>,
+    },
+    /// List the branches and signals directly below a path
+    Ls {
+        /// Branch path (omit to list from the root)
+        #[arg(default_value = "")]
+        prefix: String,
+    },
+    /// Show the whole signal tree below a path
+    Tree {
+        /// Branch path (omit to show from the root)
+        #[arg(default_value = "")]
+        prefix: String,
+    },
+    /// List active locks
+    Locks,
+    /// Release locks on the given paths regardless of holder (admin)
+    ForceUnlock {
+        /// Signal paths to unlock
+        #[arg(required = true)]
+        paths: Vec<String>,
+    },
+    /// Apply a VSS overlay file to the running signal tree (admin)
+    Overlay {
+        /// VSS overlay JSON file
+        #[arg(required = true)]
+        file: String,
+    },
+    /// Put signal values back to their VSS defaults
+    Reset {
+        /// Signal paths (wildcards allowed)
+        #[arg(required = true)]
+        paths: Vec<String>,
+    },
+    /// Reset the whole shadow to the state loaded from the VSS file (admin)
+    FactoryReset,
+    /// Save a snapshot of all signals (config and state) to a file
+    Export {
+        /// Output file
+        #[arg(required = true)]
+        file: String,
+        /// Write the compact binary format instead of JSON
+        #[arg(long)]
+        binary: bool,
+    },
+    /// Replace all signals with a snapshot file written by export (admin)
+    Import {
+        /// Snapshot file (JSON or binary)
+        #[arg(required = true)]
+        file: String,
+    },
+    /// Show active alerts and recent alert history
+    Alerts {
+        /// Maximum number of history entries
+        #[arg(short, long, default_value_t = 20)]
+        limit: u32,
+        /// Keep streaming alerts as they are raised or cleared
+        #[arg(short, long)]
+        follow: bool,
+    },
+    /// List the registered alert rules
+    AlertRules,
+    /// Register alert rules from a JSON file, replacing rules with the same id
+    PutAlertRules {
+        /// JSON array of rules, in the server's --alert-rules format
+        #[arg(required = true)]
+        file: String,
+    },
+    /// Remove alert rules
+    RemoveAlertRules {
+        /// Rule ids
+        #[arg(required = true)]
+        ids: Vec<String>,
+    },
     /// Unsubscribe from signal changes
     Unsubscribe {
-        /// Signal paths to unsubscribe
+        /// Subscription IDs printed by the subscribe command
         #[arg(required = true)]
-        paths: Vec<String>,
+        subscription_ids: Vec<String>,
     },
 }
 
@@ -76,8 +167,51 @@ async fn main() -> Result<()> {
         Commands::Subscribe { paths } => {
             subscribe_signals(&mut client, paths).await?;
         }
-        Commands::Unsubscribe { paths } => {
-            unsubscribe_signals(&mut client, paths).await?;
+        Commands::History { path, last, from, to, limit } => {
+            let from = last.map(|secs| now_ns().saturating_sub(secs * 1_000_000_000)).or(from);
+            get_history(&mut client, path, from, to, limit).await?;
+        }
+        Commands::Ls { prefix } => {
+            list_nodes(&mut client, prefix).await?;
+        }
+        Commands::Tree { prefix } => {
+            show_tree(&mut client, prefix).await?;
+        }
+        Commands::Locks => {
+            list_locks(&mut client).await?;
+        }
+        Commands::ForceUnlock { paths } => {
+            force_unlock(&mut client, paths).await?;
+        }
+        Commands::Overlay { file } => {
+            apply_overlay(&mut client, file).await?;
+        }
+        Commands::Reset { paths } => {
+            reset_signals(&mut client, paths).await?;
+        }
+        Commands::FactoryReset => {
+            factory_reset(&mut client).await?;
+        }
+        Commands::Export { file, binary } => {
+            export_snapshot(&mut client, file, binary).await?;
+        }
+        Commands::Import { file } => {
+            import_snapshot(&mut client, file).await?;
+        }
+        Commands::Alerts { limit, follow } => {
+            list_alerts(&mut client, limit, follow).await?;
+        }
+        Commands::AlertRules => {
+            list_alert_rules(&mut client).await?;
+        }
+        Commands::PutAlertRules { file } => {
+            put_alert_rules(&mut client, file).await?;
+        }
+        Commands::RemoveAlertRules { ids } => {
+            remove_alert_rules(&mut client, ids).await?;
+        }
+        Commands::Unsubscribe { subscription_ids } => {
+            unsubscribe_signals(&mut client, subscription_ids).await?;
         }
     }
 
@@ -101,18 +235,14 @@ async fn get_signals(client: &mut VehicleShadowClient, paths: Vec<String>) -> Re
 async fn set_signal(client: &mut VehicleShadowClient, path: String, value_json: String) -> Result<()> {
     let token = get_lock(client, path.clone()).await?;
 
-    let state = parse_state_from_json(&value_json).unwrap_or_else(|_| { 
-        println!("{}", value_json);
-        let json_value: serde_json::Value = serde_json::from_str(&value_json).unwrap();
-        println!("{}", json_value);
-        let value = parse_value_from_json(&value_json).unwrap();
-        State {
-            value: Some(value),
-            capability: None,
-            availability: None,
-            reserved: None,
-        }
-    });
+    let data_type = get_data_type(client, &path).await?;
+    let state = match parse_state_from_json(&value_json, data_type) {
+        Ok(state) => state,
+        Err(_) => State {
+            value: Some(parse_value_from_json(&value_json, data_type)?),
+            ..Default::default()
+        },
+    };
     let response = client.set_signals([(path.clone(), state)].to_vec(), token.clone()).await?;
     if response.success {
         println!("Successfully set signal: {}", path);
@@ -133,6 +263,16 @@ async fn set_signal(client: &mut VehicleShadowClient, path: String, value_json:
     Ok(())
 }
 
+// 値をシグナルの型で送るため、先に設定を取得する
+async fn get_data_type(client: &mut VehicleShadowClient, path: &str) -> Result<ValueType> {
+    let response = client.get_signals(vec![path.to_string()]).await?;
+    let config = response.signals.into_iter().next().and_then(|signal| signal.config);
+    match config {
+        Some(config) => Ok(ValueType::try_from(config.data_type).unwrap_or(ValueType::TypeNan)),
+        None => Err(anyhow::anyhow!("Failed to get signal {}: {}", path, response.error_message)),
+    }
+}
+
 async fn subscribe_signals(client: &mut VehicleShadowClient, paths: Vec<String>) -> Result<()> {
     println!("Subscribed to signals. Waiting for updates...");
     println!("Press Ctrl+C to stop");
@@ -144,6 +284,9 @@ async fn subscribe_signals(client: &mut VehicleShadowClient, paths: Vec<String>)
         let mut stream = client.subscribe(path).await?;
         let task = tokio::spawn(async move {
             while let Ok(Some(response)) = stream.message().await {
+                if response.signal.is_none() && !response.subscription_id.is_empty() {
+                    println!("Subscription ID: {}", response.subscription_id);
+                }
                 if let Some(signal) = response.signal {
                     println!("Update for signal: {}", signal.path);
                     if let Some(state) = signal.state {
@@ -152,6 +295,10 @@ async fn subscribe_signals(client: &mut VehicleShadowClient, paths: Vec<String>)
                         }
                         println!("  Capability: {}", state.capability.unwrap_or(false));
                         println!("  Availability: {}", state.availability.unwrap_or(false));
+                        println!("  Sequence: {}", state.sequence.unwrap_or(0));
+                        if let Some(timestamp) = state.sink_timestamp {
+                            println!("  Received at: {} ns", timestamp.wall_clock_ns);
+                        }
                     }
                     println!();
                 }
@@ -177,8 +324,339 @@ async fn subscribe_signals(client: &mut VehicleShadowClient, paths: Vec<String>)
     Ok(())
 }
 
-async fn unsubscribe_signals(client: &mut VehicleShadowClient, paths: Vec<String>) -> Result<()> {
-    // TODO
+async fn get_history(
+    client: &mut VehicleShadowClient,
+    path: String,
+    from: Option<u64>,
+    to: Option<u64>,
+    limit: Option<u32>,
+) -> Result<()> {
+    let response = client.get_history(path.clone(), from, to, limit).await?;
+    if !response.success {
+        error!("Failed to get history: {}", response.error_message);
+        return Err(anyhow::anyhow!("Failed to get history: {}", response.error_message));
+    }
+
+    println!("{} entries for {}:", response.states.len(), path);
+    for state in response.states {
+        let time = state.sink_timestamp.map(|t| t.wall_clock_ns).unwrap_or(0);
+        let value = state.value.map(|v| format_value(&v)).unwrap_or_default();
+        println!("  [{}] #{} {}", time, state.sequence.unwrap_or(0), value);
+    }
+    Ok(())
+}
+
+async fn list_nodes(client: &mut VehicleShadowClient, prefix: String) -> Result<()> {
+    for node in fetch_nodes(client, &prefix, false).await? {
+        println!("{}", describe_node(&node, &node.path));
+    }
+    Ok(())
+}
+
+async fn show_tree(client: &mut VehicleShadowClient, prefix: String) -> Result<()> {
+    let base_depth = prefix.split('.').filter(|segment| !segment.is_empty()).count();
+    for node in fetch_nodes(client, &prefix, true).await? {
+        let depth = node.path.split('.').count().saturating_sub(base_depth + 1);
+        let name = node.path.rsplit('.').next().unwrap_or_default();
+        println!("{}{}", "  ".repeat(depth), describe_node(&node, name));
+    }
+    Ok(())
+}
+
+// ページを最後まで辿って全ノードを集める
+async fn fetch_nodes(client: &mut VehicleShadowClient, prefix: &str, recursive: bool) -> Result<Vec<SignalMetadata>> {
+    let mut nodes = Vec::new();
+    let mut page_token = String::new();
+    loop {
+        let response = client.list_signals(prefix.to_string(), recursive, 0, page_token).await?;
+        if !response.success {
+            return Err(anyhow::anyhow!("Failed to list signals: {}", response.error_message));
+        }
+        nodes.extend(response.nodes);
+        if response.next_page_token.is_empty() {
+            return Ok(nodes);
+        }
+        page_token = response.next_page_token;
+    }
+}
+
+fn describe_node(node: &SignalMetadata, name: &str) -> String {
+    let Some(config) = node.config.as_ref() else {
+        return name.to_string();
+    };
+    let leaf_type = LeafType::try_from(config.leaf_type).unwrap_or(LeafType::Branch);
+    if leaf_type == LeafType::Branch {
+        return format!("{}/", name);
+    }
+
+    let data_type = ValueType::try_from(config.data_type).map(|t| t.as_str_name()).unwrap_or("?");
+    let mut line = format!("{} [{} {}] id={}", name, leaf_type.as_str_name(), data_type, node.id);
+    if let Some(unit) = &config.unit {
+        line.push_str(&format!(" unit={}", unit));
+    }
+    if !config.allowd.is_empty() {
+        let allowed: Vec<String> = config.allowd.iter().map(format_value).collect();
+        line.push_str(&format!(" allowed=[{}]", allowed.join(", ")));
+    }
+    if let Some(expression) = &config.expression {
+        line.push_str(&format!(" = {}", expression));
+    }
+    if let Some(description) = &config.description {
+        line.push_str(&format!(" - {}", description));
+    }
+    line
+}
+
+async fn list_locks(client: &mut VehicleShadowClient) -> Result<()> {
+    let response = client.list_locks().await?;
+    if !response.success {
+        return Err(anyhow::anyhow!("Failed to list locks: {}", response.error_message));
+    }
+
+    let now = now_ns();
+    for lock in response.locks {
+        let remaining_ms = lock.expires_at_ns.saturating_sub(now) / 1_000_000;
+        println!("{} holder={} expires_in={}ms", lock.token, lock.holder, remaining_ms);
+        for path in lock.paths {
+            println!("  {}", path);
+        }
+    }
+    Ok(())
+}
+
+async fn force_unlock(client: &mut VehicleShadowClient, paths: Vec<String>) -> Result<()> {
+    let response = client.force_unlock(paths).await?;
+    if !response.success {
+        return Err(anyhow::anyhow!("Failed to force unlock: {}", response.error_message));
+    }
+
+    for lock in response.released {
+        println!("Released {} held by {}: {:?}", lock.token, lock.holder, lock.paths);
+    }
+    Ok(())
+}
+
+async fn apply_overlay(client: &mut VehicleShadowClient, file: String) -> Result<()> {
+    let overlay_json = std::fs::read_to_string(&file)?;
+    let response = client.apply_overlay(overlay_json).await?;
+    if !response.success {
+        return Err(anyhow::anyhow!("Failed to apply overlay: {}", response.error_message));
+    }
+
+    for (label, paths) in [("added", response.added), ("updated", response.updated), ("removed", response.removed)] {
+        for path in paths {
+            println!("{:8} {}", label, path);
+        }
+    }
+    Ok(())
+}
+
+async fn reset_signals(client: &mut VehicleShadowClient, paths: Vec<String>) -> Result<()> {
+    let response = client.reset_signals(paths).await?;
+    if !response.success {
+        return Err(anyhow::anyhow!("Failed to reset signals: {}", response.error_message));
+    }
+
+    for path in response.paths {
+        println!("Reset: {}", path);
+    }
+    Ok(())
+}
+
+async fn factory_reset(client: &mut VehicleShadowClient) -> Result<()> {
+    let response = client.factory_reset().await?;
+    if !response.success {
+        return Err(anyhow::anyhow!("Failed to reset to factory state: {}", response.error_message));
+    }
+
+    println!("Reset {} signals to factory state", response.reset);
+    Ok(())
+}
+
+async fn export_snapshot(client: &mut VehicleShadowClient, file: String, binary: bool) -> Result<()> {
+    let encoding = if binary { SnapshotEncoding::SnapshotBinary } else { SnapshotEncoding::SnapshotJson };
+    let response = client.export(encoding).await?;
+    if !response.success {
+        return Err(anyhow::anyhow!("Failed to export snapshot: {}", response.error_message));
+    }
+
+    std::fs::write(&file, &response.snapshot)?;
+    println!("Wrote {} bytes to {}", response.snapshot.len(), file);
+    Ok(())
+}
+
+async fn import_snapshot(client: &mut VehicleShadowClient, file: String) -> Result<()> {
+    let snapshot = std::fs::read(&file)?;
+    let response = client.import(snapshot).await?;
+    if !response.success {
+        return Err(anyhow::anyhow!("Failed to import snapshot: {}", response.error_message));
+    }
+
+    for (label, paths) in [("added", response.added), ("updated", response.updated), ("removed", response.removed)] {
+        for path in paths {
+            println!("{:8} {}", label, path);
+        }
+    }
+    Ok(())
+}
+
+async fn list_alerts(client: &mut VehicleShadowClient, limit: u32, follow: bool) -> Result<()> {
+    let response = client.list_alerts(limit).await?;
+    if !response.success {
+        return Err(anyhow::anyhow!("Failed to list alerts: {}", response.error_message));
+    }
+
+    println!("History:");
+    for alert in &response.history {
+        println!("  {}", describe_alert(alert));
+    }
+    println!("Active:");
+    for alert in &response.active {
+        println!("  {}", describe_alert(alert));
+    }
+    if !follow {
+        return Ok(());
+    }
+
+    // 購読開始時点で有効なアラートがもう一度届くので、そこから表示を続ける
+    println!("Waiting for alerts... (Ctrl+C to stop)");
+    let mut stream = client.subscribe_alerts().await?;
+    let mut ctrl_c = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::interrupt())?;
+    loop {
+        tokio::select! {
+            _ = ctrl_c.recv() => break,
+            message = stream.message() => match message? {
+                Some(alert) => println!("{}", describe_alert(&alert)),
+                None => break,
+            },
+        }
+    }
+    Ok(())
+}
+
+fn describe_alert(alert: &Alert) -> String {
+    let severity = AlertSeverity::try_from(alert.severity).map_or("unknown", |severity| severity.as_str_name());
+    let timestamp = alert.timestamp.as_ref().map_or(0, |timestamp| timestamp.wall_clock_ns);
+    let mut line = format!(
+        "{} {} [{}] {} #{} {}",
+        timestamp,
+        if alert.active { "RAISED " } else { "CLEARED" },
+        severity,
+        alert.rule_id,
+        alert.sequence,
+        alert.message
+    );
+    if let Some(value) = &alert.value {
+        line.push_str(&format!(" (value: {})", format_value(value)));
+    }
+    line
+}
+
+async fn list_alert_rules(client: &mut VehicleShadowClient) -> Result<()> {
+    let response = client.list_alert_rules().await?;
+    if !response.success {
+        return Err(anyhow::anyhow!("Failed to list alert rules: {}", response.error_message));
+    }
+
+    for rule in response.rules {
+        let comparator = Comparator::try_from(rule.comparator).map_or("?", comparator_symbol);
+        let mut line = format!("{}: ", rule.id);
+        if !rule.path.is_empty() {
+            line.push_str(&format!("{} {} {} (hysteresis {})", rule.path, comparator, rule.threshold, rule.hysteresis));
+        }
+        if let Some(condition) = &rule.condition {
+            line.push_str(&format!(" when {}", condition));
+        }
+        println!("{}", line);
+    }
+    Ok(())
+}
+
+async fn put_alert_rules(client: &mut VehicleShadowClient, file: String) -> Result<()> {
+    let rules: Vec<serde_json::Value> = serde_json::from_str(&std::fs::read_to_string(&file)?)?;
+    for rule in rules {
+        let rule = parse_alert_rule(&rule)?;
+        let id = rule.id.clone();
+        let response = client.put_alert_rule(rule).await?;
+        if response.success {
+            println!("Put alert rule: {}", id);
+        } else {
+            error!("Failed to put alert rule {}: {}", id, response.error_message);
+        }
+    }
+    Ok(())
+}
+
+// サーバーの--alert-rulesと同じ形式 ({"id", "path", "comparator": "<", "threshold", ...})
+fn parse_alert_rule(rule: &serde_json::Value) -> Result<AlertRule> {
+    let text = |key: &str| rule.get(key).and_then(|value| value.as_str()).map(str::to_string);
+    let number = |key: &str| rule.get(key).and_then(|value| value.as_f64()).unwrap_or(0.0);
+    let comparator = match text("comparator").as_deref().unwrap_or("<") {
+        "<" => Comparator::Less,
+        "<=" => Comparator::LessEqual,
+        ">" => Comparator::Greater,
+        ">=" => Comparator::GreaterEqual,
+        "==" => Comparator::Equal,
+        "!=" => Comparator::NotEqual,
+        other => return Err(anyhow::anyhow!("Unknown comparator: {}", other)),
+    };
+    let severity = match text("severity").as_deref().unwrap_or("info") {
+        "info" => AlertSeverity::Info,
+        "warning" => AlertSeverity::Warning,
+        "critical" => AlertSeverity::Critical,
+        other => return Err(anyhow::anyhow!("Unknown severity: {}", other)),
+    };
+    Ok(AlertRule {
+        id: text("id").ok_or_else(|| anyhow::anyhow!("Alert rule without id: {}", rule))?,
+        path: text("path").unwrap_or_default(),
+        comparator: comparator as i32,
+        threshold: number("threshold"),
+        hysteresis: number("hysteresis"),
+        condition: text("condition"),
+        severity: severity as i32,
+        message: text("message").unwrap_or_default(),
+    })
+}
+
+fn comparator_symbol(comparator: Comparator) -> &'static str {
+    match comparator {
+        Comparator::Less => "<",
+        Comparator::LessEqual => "<=",
+        Comparator::Greater => ">",
+        Comparator::GreaterEqual => ">=",
+        Comparator::Equal => "==",
+        Comparator::NotEqual => "!=",
+    }
+}
+
+async fn remove_alert_rules(client: &mut VehicleShadowClient, ids: Vec<String>) -> Result<()> {
+    for id in ids {
+        let response = client.remove_alert_rule(id.clone()).await?;
+        if response.success {
+            println!("Removed alert rule: {}", id);
+        } else {
+            error!("Failed to remove alert rule {}: {}", id, response.error_message);
+        }
+    }
+    Ok(())
+}
+
+fn now_ns() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0)
+}
+
+async fn unsubscribe_signals(client: &mut VehicleShadowClient, subscription_ids: Vec<String>) -> Result<()> {
+    for subscription_id in subscription_ids {
+        let response: UnsubscribeResponse = client.unsubscribe(subscription_id.clone()).await?;
+        if response.success {
+            println!("Unsubscribed: {}", subscription_id);
+        } else {
+            error!("Failed to unsubscribe {}: {}", subscription_id, response.error_message);
+        }
+    }
     Ok(())
 }
 
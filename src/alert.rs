@@ -0,0 +1,206 @@
+// Alert rules watched by the shadow, e.g. "battery state of charge below 10%"
+// or "a door open while the speed is above 5". A rule raises an alert when
+// its threshold and condition hold, and clears it once they stop holding
+// with the hysteresis applied to the threshold.
+
+use bincode::{Decode, Encode};
+use std::fs;
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Result, VehicleShadowError};
+use crate::expression::Expression;
+use crate::path_pattern;
+use crate::signal;
+
+#[derive(Encode, Decode, Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AlertRule {
+    pub id: String,
+    /// Signal compared against `threshold`. May be empty for rules that
+    /// only have a condition.
+    #[serde(default)]
+    pub path: String,
+    #[serde(default)]
+    pub comparator: Comparator,
+    #[serde(default)]
+    pub threshold: f64,
+    /// Distance the value has to move back past the threshold to clear.
+    #[serde(default)]
+    pub hysteresis: f64,
+    /// Expression (see [`crate::expression`]) that has to hold as well.
+    #[serde(default)]
+    pub condition: Option<String>,
+    #[serde(default)]
+    pub severity: Severity,
+    #[serde(default)]
+    pub message: String,
+}
+
+#[derive(Encode, Decode, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub enum Comparator {
+    #[default]
+    #[serde(rename = "<")]
+    Less,
+    #[serde(rename = "<=")]
+    LessEqual,
+    #[serde(rename = ">")]
+    Greater,
+    #[serde(rename = ">=")]
+    GreaterEqual,
+    #[serde(rename = "==")]
+    Equal,
+    #[serde(rename = "!=")]
+    NotEqual,
+}
+
+#[derive(Encode, Decode, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    #[default]
+    Info,
+    Warning,
+    Critical,
+}
+
+/// A rule raising or clearing its alert. `sequence` counts the changes of
+/// one rule, so they can be delivered in order.
+#[derive(Encode, Decode, Debug, Clone, PartialEq)]
+pub struct Alert {
+    pub rule_id: String,
+    pub active: bool,
+    pub sequence: u64,
+    pub timestamp: signal::Timestamp,
+    pub severity: Severity,
+    pub message: String,
+    /// Value of the rule's signal at the time, NAN for condition-only rules.
+    pub value: signal::Value,
+}
+
+impl Comparator {
+    fn symbol(self) -> &'static str {
+        match self {
+            Comparator::Less => "<",
+            Comparator::LessEqual => "<=",
+            Comparator::Greater => ">",
+            Comparator::GreaterEqual => ">=",
+            Comparator::Equal => "==",
+            Comparator::NotEqual => "!=",
+        }
+    }
+
+    // The threshold an active alert is held at: moved back by the hysteresis
+    // so a value hovering around the threshold does not toggle the alert.
+    fn hold_threshold(self, threshold: f64, hysteresis: f64) -> f64 {
+        match self {
+            Comparator::Less | Comparator::LessEqual => threshold + hysteresis,
+            Comparator::Greater | Comparator::GreaterEqual => threshold - hysteresis,
+            Comparator::Equal | Comparator::NotEqual => threshold,
+        }
+    }
+}
+
+/// A rule with its expressions parsed: `raise` turns the alert on, and the
+/// alert stays on while `hold` is true.
+#[derive(Debug, Clone)]
+pub struct CompiledRule {
+    pub rule: AlertRule,
+    pub raise: Expression,
+    pub hold: Expression,
+}
+
+impl CompiledRule {
+    pub fn compile(rule: AlertRule) -> Result<CompiledRule> {
+        let invalid = |message: &str| {
+            VehicleShadowError::InvalidInput(format!("Invalid alert rule {:?}: {}", rule.id, message))
+        };
+        if rule.id.is_empty() {
+            return Err(invalid("id is missing"));
+        }
+        if rule.path.is_empty() && rule.condition.is_none() {
+            return Err(invalid("a path or a condition is required"));
+        }
+        if path_pattern::is_pattern(&rule.path) {
+            return Err(invalid("the path cannot be a pattern; use any() or all() in the condition"));
+        }
+        if !rule.threshold.is_finite() || !rule.hysteresis.is_finite() || rule.hysteresis < 0.0 {
+            return Err(invalid("threshold and hysteresis must be finite, hysteresis not negative"));
+        }
+
+        let source = |threshold: f64| {
+            let comparison = (!rule.path.is_empty())
+                .then(|| format!("{} {} {}", rule.path, rule.comparator.symbol(), threshold));
+            let condition = rule.condition.as_ref().map(|condition| format!("({})", condition));
+            comparison.into_iter().chain(condition).collect::<Vec<_>>().join(" && ")
+        };
+        let hold_threshold = rule.comparator.hold_threshold(rule.threshold, rule.hysteresis);
+        Ok(CompiledRule {
+            raise: Expression::parse(&source(rule.threshold))?,
+            hold: Expression::parse(&source(hold_threshold))?,
+            rule,
+        })
+    }
+
+    pub fn depends_on(&self, path: &str) -> bool {
+        self.raise.depends_on(path)
+    }
+}
+
+/// Reads a JSON array of rules, e.g. the file given with `--alert-rules`.
+pub fn read_rules(path: &str) -> Result<Vec<AlertRule>> {
+    Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::expression::Operand;
+    use crate::signal::Value;
+
+    fn rule() -> AlertRule {
+        serde_json::from_value(serde_json::json!({
+            "id": "low_soc",
+            "path": "Vehicle.Battery.SoC",
+            "comparator": "<",
+            "threshold": 10,
+            "hysteresis": 2,
+            "severity": "warning"
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_hysteresis() {
+        let compiled = CompiledRule::compile(rule()).unwrap();
+        let at = |soc: f64| {
+            let lookup = move |_: &str| Ok(vec![Value::Double(soc)]);
+            let raise = compiled.raise.evaluate(&lookup).unwrap();
+            let hold = compiled.hold.evaluate(&lookup).unwrap();
+            (raise, hold)
+        };
+        assert_eq!(at(11.0), (Some(Operand::Bool(false)), Some(Operand::Bool(true))));
+        assert_eq!(at(9.0), (Some(Operand::Bool(true)), Some(Operand::Bool(true))));
+        assert_eq!(at(12.5), (Some(Operand::Bool(false)), Some(Operand::Bool(false))));
+        assert_eq!(compiled.rule.severity, Severity::Warning);
+    }
+
+    #[test]
+    fn test_invalid_rules() {
+        let mut no_input = rule();
+        no_input.path = String::new();
+        let mut pattern = rule();
+        pattern.path = "Vehicle.*.SoC".to_string();
+        let mut bad_condition = rule();
+        bad_condition.condition = Some("Vehicle.Speed >".to_string());
+        let mut negative = rule();
+        negative.hysteresis = -1.0;
+
+        for rule in [no_input, pattern, bad_condition, negative] {
+            assert!(CompiledRule::compile(rule).is_err());
+        }
+
+        let mut combined = rule();
+        combined.condition = Some("Vehicle.Speed > 5".to_string());
+        let compiled = CompiledRule::compile(combined).unwrap();
+        assert!(compiled.depends_on("Vehicle.Speed"));
+        assert!(compiled.depends_on("Vehicle.Battery.SoC"));
+    }
+}
@@ -1,5 +1,10 @@
 use clap::Parser;
 use std::env;
+use std::time::Duration;
+
+use crate::error::Result;
+use crate::rpc::federation::Route;
+use crate::vehicle_shadow::HistoryConfig;
 
 #[derive(Parser, Debug, Clone)]
 #[command(
@@ -8,9 +13,9 @@ use std::env;
     about = "A vehicle shadow signal service"
 )]
 pub struct Config {
-    /// Path to VSS JSON file
-    #[arg(short, long)]
-    pub vss: String,
+    /// Path to VSS JSON file (not used in mirror mode)
+    #[arg(short, long, required_unless_present = "mirrors")]
+    pub vss: Option<String>,
     
     /// Server address to bind to
     #[arg(short, long, default_value = "[::1]:50051")]
@@ -23,6 +28,41 @@ pub struct Config {
     /// Database path (optional, uses temporary if not specified)
     #[arg(long)]
     pub db_path: Option<String>,
+
+    /// Number of past states kept per signal (history is disabled if neither limit is set)
+    #[arg(long)]
+    pub history_max_entries: Option<u64>,
+
+    /// Maximum age of past states kept per signal, in seconds
+    #[arg(long)]
+    pub history_max_age: Option<u64>,
+
+    /// Default lock lease duration in seconds, used when a Lock request has no TTL
+    #[arg(long, default_value_t = 30)]
+    pub lock_ttl: u64,
+
+    /// Forward a path prefix to another shadow instance, as PREFIX=ENDPOINT
+    /// (e.g. Vehicle.Cabin=http://[::1]:50052). May be repeated
+    #[arg(long = "route")]
+    pub routes: Vec<String>,
+
+    /// Run as a read-only mirror of the given shadow instance (e.g.
+    /// http://[::1]:50051). May be repeated to mirror several instances
+    #[arg(long = "mirror")]
+    pub mirrors: Vec<String>,
+
+    /// Number of updates queued per subscriber when the request does not set one
+    #[arg(long, default_value_t = 100)]
+    pub subscriber_queue_size: usize,
+
+    /// Number of update periods a signal may go unwritten before it is
+    /// marked unavailable (for signals whose VSS config has update_period)
+    #[arg(long, default_value_t = 3)]
+    pub stale_periods: u32,
+
+    /// JSON file with alert rules put at startup (not used in mirror mode)
+    #[arg(long)]
+    pub alert_rules: Option<String>,
 }
 
 impl Config {
@@ -45,10 +85,72 @@ impl Config {
         if let Ok(db_path) = env::var("VSS_DB_PATH") {
             config.db_path = Some(db_path);
         }
+
+        if let Ok(max_entries) = env::var("VSS_HISTORY_MAX_ENTRIES") {
+            config.history_max_entries = max_entries.parse().ok();
+        }
+
+        if let Ok(max_age) = env::var("VSS_HISTORY_MAX_AGE") {
+            config.history_max_age = max_age.parse().ok();
+        }
+
+        if let Ok(lock_ttl) = env::var("VSS_LOCK_TTL")
+            && let Ok(lock_ttl) = lock_ttl.parse()
+        {
+            config.lock_ttl = lock_ttl;
+        }
+
+        // カンマ区切りで複数指定できる
+        if let Ok(routes) = env::var("VSS_ROUTES") {
+            config.routes = routes
+                .split(',')
+                .filter(|route| !route.is_empty())
+                .map(str::to_string)
+                .collect();
+        }
+
+        if let Ok(queue_size) = env::var("VSS_SUBSCRIBER_QUEUE_SIZE")
+            && let Ok(queue_size) = queue_size.parse()
+        {
+            config.subscriber_queue_size = queue_size;
+        }
+
+        if let Ok(stale_periods) = env::var("VSS_STALE_PERIODS")
+            && let Ok(stale_periods) = stale_periods.parse()
+        {
+            config.stale_periods = stale_periods;
+        }
+
+        if let Ok(alert_rules) = env::var("VSS_ALERT_RULES") {
+            config.alert_rules = Some(alert_rules);
+        }
+
+        if let Ok(mirrors) = env::var("VSS_MIRRORS") {
+            config.mirrors = mirrors
+                .split(',')
+                .filter(|mirror| !mirror.is_empty())
+                .map(str::to_string)
+                .collect();
+        }
         
         config
     }
     
+    pub fn history_config(&self) -> HistoryConfig {
+        HistoryConfig {
+            max_entries: self.history_max_entries,
+            max_age: self.history_max_age.map(Duration::from_secs),
+        }
+    }
+
+    pub fn is_mirror(&self) -> bool {
+        !self.mirrors.is_empty()
+    }
+
+    pub fn routes(&self) -> Result<Vec<Route>> {
+        self.routes.iter().map(|route| Route::parse(route)).collect()
+    }
+
     pub fn setup_logging(&self) {
         unsafe {
             env::set_var("RUST_LOG", &self.log_level);
@@ -60,10 +162,18 @@ impl Config {
 impl Default for Config {
     fn default() -> Self {
         Self {
-            vss: String::new(),
+            vss: None,
             server_addr: "[::1]:50051".to_string(),
             log_level: "info".to_string(),
             db_path: None,
+            history_max_entries: None,
+            history_max_age: None,
+            lock_ttl: 30,
+            routes: Vec::new(),
+            mirrors: Vec::new(),
+            subscriber_queue_size: 100,
+            stale_periods: 3,
+            alert_rules: None,
         }
     }
 } 
\ No newline at end of file
@@ -2,6 +2,10 @@ use std::fmt;
 use std::io;
 use std::net::AddrParseError;
 
+use sled::transaction::TransactionError;
+
+use crate::signal::ConstraintViolation;
+
 #[derive(Debug)]
 pub enum VehicleShadowError {
     Io(io::Error),
@@ -12,6 +16,15 @@ pub enum VehicleShadowError {
     Configuration(String),
     Rpc(String),
     Network(String),
+    ConstraintViolation {
+        path: String,
+        violation: ConstraintViolation,
+    },
+    TransactionAborted {
+        path: String,
+        cause: Box<VehicleShadowError>,
+    },
+    ReadOnly,
 }
 
 impl fmt::Display for VehicleShadowError {
@@ -25,6 +38,15 @@ impl fmt::Display for VehicleShadowError {
             VehicleShadowError::Configuration(e) => write!(f, "Configuration error: {}", e),
             VehicleShadowError::Rpc(e) => write!(f, "RPC error: {}", e),
             VehicleShadowError::Network(e) => write!(f, "Network error: {}", e),
+            VehicleShadowError::ConstraintViolation { path, violation } => {
+                write!(f, "Constraint violation on {}: {}", path, violation)
+            }
+            VehicleShadowError::TransactionAborted { path, cause } => {
+                write!(f, "Transaction aborted by {}: {}", path, cause)
+            }
+            VehicleShadowError::ReadOnly => {
+                write!(f, "Read-only mirror: write to the upstream shadow instead")
+            }
         }
     }
 }
@@ -55,6 +77,15 @@ impl From<sled::Error> for VehicleShadowError {
     }
 }
 
+impl From<TransactionError<VehicleShadowError>> for VehicleShadowError {
+    fn from(err: TransactionError<VehicleShadowError>) -> Self {
+        match err {
+            TransactionError::Abort(e) => e,
+            TransactionError::Storage(e) => e.into(),
+        }
+    }
+}
+
 impl From<serde_json::Error> for VehicleShadowError {
     fn from(err: serde_json::Error) -> Self {
         VehicleShadowError::Serialization(err.to_string())
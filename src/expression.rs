@@ -0,0 +1,439 @@
+// Expressions computing derived signals from other signals, e.g.
+//   any(Vehicle.Cabin.Door.*.IsOpen)
+//   avg(Vehicle.Chassis.Axle.*.Wheel.*.Tire.Pressure)
+//   Vehicle.Speed > 100 && !Vehicle.Body.Hood.IsOpen
+// Paths may use the wildcards of path_pattern. A path matching several
+// signals (or an array signal) is only allowed as a function argument.
+//
+// Operators, loosest first: ||, &&, comparisons, + -, * /, unary ! -.
+// Functions: any, all, count (of true values), sum, avg, min, max.
+
+use crate::error::{Result, VehicleShadowError};
+use crate::path_pattern;
+use crate::signal::{Value, ValueType};
+
+#[derive(Debug, Clone)]
+pub struct Expression {
+    root: Node,
+    inputs: Vec<String>,
+}
+
+/// Result of an evaluation. Booleans count as 1 and 0 in arithmetic and
+/// numbers as true when non-zero in logic.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operand {
+    Number(f64),
+    Bool(bool),
+}
+
+#[derive(Debug, Clone)]
+enum Node {
+    Number(f64),
+    Bool(bool),
+    Path(String),
+    Not(Box<Node>),
+    Negate(Box<Node>),
+    Binary(BinaryOp, Box<Node>, Box<Node>),
+    Call(Function, Vec<Node>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum BinaryOp {
+    Or,
+    And,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Function {
+    Any,
+    All,
+    Count,
+    Sum,
+    Avg,
+    Min,
+    Max,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(f64),
+    Ident(String),
+    Op(&'static str),
+}
+
+const OPERATORS: [&str; 16] = [
+    "||", "&&", "==", "!=", "<=", ">=", "<", ">", "+", "-", "*", "/", "!", "(", ")", ",",
+];
+
+impl Expression {
+    pub fn parse(source: &str) -> Result<Expression> {
+        let invalid = |message: String| {
+            VehicleShadowError::InvalidInput(format!("Invalid expression {:?}: {}", source, message))
+        };
+        let tokens = tokenize(source).map_err(invalid)?;
+        let mut parser = Parser { tokens, position: 0, inputs: Vec::new() };
+        let root = parser.parse_binary(0).map_err(invalid)?;
+        if let Some(token) = parser.tokens.get(parser.position) {
+            return Err(invalid(format!("unexpected {:?}", token)));
+        }
+        Ok(Expression { root, inputs: parser.inputs })
+    }
+
+    /// Paths and patterns the expression reads.
+    pub fn inputs(&self) -> &[String] {
+        &self.inputs
+    }
+
+    pub fn depends_on(&self, path: &str) -> bool {
+        self.inputs.iter().any(|input| path_pattern::matches(input, path))
+    }
+
+    /// Evaluates the expression with `lookup` returning the values of the
+    /// signals matching a path or pattern. Returns None if a value is
+    /// missing, e.g. an input has never been set or a division by zero.
+    pub fn evaluate(&self, lookup: &dyn Fn(&str) -> Result<Vec<Value>>) -> Result<Option<Operand>> {
+        evaluate(&self.root, lookup)
+    }
+}
+
+impl Operand {
+    fn from_value(value: &Value) -> Option<Operand> {
+        match value {
+            Value::Bool(b) => Some(Operand::Bool(*b)),
+            value => value.as_f64().map(Operand::Number),
+        }
+    }
+
+    fn number(self) -> f64 {
+        match self {
+            Operand::Number(n) => n,
+            Operand::Bool(b) => b as u8 as f64,
+        }
+    }
+
+    pub fn truth(self) -> bool {
+        match self {
+            Operand::Number(n) => n != 0.0,
+            Operand::Bool(b) => b,
+        }
+    }
+
+    /// Converts the result to a value of `data_type`, rounding for integer
+    /// types. Returns None if it does not fit.
+    pub fn to_value(self, data_type: &ValueType) -> Option<Value> {
+        let json = match (data_type, self) {
+            (ValueType::TypeBool, operand) => serde_json::json!(operand.truth()),
+            (ValueType::TypeFloat | ValueType::TypeDouble, operand) => serde_json::json!(operand.number()),
+            (_, operand) if operand.number().is_finite() => serde_json::json!(operand.number().round() as i64),
+            _ => return None,
+        };
+        match data_type.build_value(&json) {
+            Value::NAN => None,
+            value => Some(value),
+        }
+    }
+}
+
+fn evaluate(node: &Node, lookup: &dyn Fn(&str) -> Result<Vec<Value>>) -> Result<Option<Operand>> {
+    let operand = match node {
+        Node::Number(n) => Operand::Number(*n),
+        Node::Bool(b) => Operand::Bool(*b),
+        Node::Path(path) => match lookup(path)?.as_slice() {
+            [value] => match Operand::from_value(value) {
+                Some(operand) => operand,
+                None => return Ok(None),
+            },
+            _ => return Ok(None),
+        },
+        Node::Not(operand) => match evaluate(operand, lookup)? {
+            Some(operand) => Operand::Bool(!operand.truth()),
+            None => return Ok(None),
+        },
+        Node::Negate(operand) => match evaluate(operand, lookup)? {
+            Some(operand) => Operand::Number(-operand.number()),
+            None => return Ok(None),
+        },
+        Node::Binary(op, left, right) => {
+            let (Some(left), Some(right)) = (evaluate(left, lookup)?, evaluate(right, lookup)?) else {
+                return Ok(None);
+            };
+            match apply(*op, left, right) {
+                Some(operand) => operand,
+                None => return Ok(None),
+            }
+        }
+        Node::Call(function, args) => {
+            let mut operands = Vec::new();
+            for arg in args {
+                match arg {
+                    Node::Path(path) => operands.extend(
+                        lookup(path)?
+                            .iter()
+                            .flat_map(Value::elements)
+                            .filter_map(|value| Operand::from_value(&value)),
+                    ),
+                    arg => match evaluate(arg, lookup)? {
+                        Some(operand) => operands.push(operand),
+                        None => return Ok(None),
+                    },
+                }
+            }
+            match call(*function, &operands) {
+                Some(operand) => operand,
+                None => return Ok(None),
+            }
+        }
+    };
+    Ok(Some(operand))
+}
+
+fn apply(op: BinaryOp, left: Operand, right: Operand) -> Option<Operand> {
+    let (l, r) = (left.number(), right.number());
+    let operand = match op {
+        BinaryOp::Or => Operand::Bool(left.truth() || right.truth()),
+        BinaryOp::And => Operand::Bool(left.truth() && right.truth()),
+        BinaryOp::Eq => Operand::Bool(l == r),
+        BinaryOp::Ne => Operand::Bool(l != r),
+        BinaryOp::Lt => Operand::Bool(l < r),
+        BinaryOp::Le => Operand::Bool(l <= r),
+        BinaryOp::Gt => Operand::Bool(l > r),
+        BinaryOp::Ge => Operand::Bool(l >= r),
+        BinaryOp::Add => Operand::Number(l + r),
+        BinaryOp::Sub => Operand::Number(l - r),
+        BinaryOp::Mul => Operand::Number(l * r),
+        BinaryOp::Div if r == 0.0 => return None,
+        BinaryOp::Div => Operand::Number(l / r),
+    };
+    Some(operand)
+}
+
+fn call(function: Function, operands: &[Operand]) -> Option<Operand> {
+    let numbers = operands.iter().map(|operand| operand.number());
+    let operand = match function {
+        Function::Any => Operand::Bool(operands.iter().any(|operand| operand.truth())),
+        Function::All => Operand::Bool(operands.iter().all(|operand| operand.truth())),
+        Function::Count => Operand::Number(operands.iter().filter(|operand| operand.truth()).count() as f64),
+        Function::Sum => Operand::Number(numbers.sum()),
+        Function::Avg if operands.is_empty() => return None,
+        Function::Avg => Operand::Number(numbers.sum::<f64>() / operands.len() as f64),
+        Function::Min => Operand::Number(numbers.reduce(f64::min)?),
+        Function::Max => Operand::Number(numbers.reduce(f64::max)?),
+    };
+    Some(operand)
+}
+
+fn tokenize(source: &str) -> std::result::Result<Vec<Token>, String> {
+    let chars: Vec<char> = source.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+        } else if c.is_ascii_digit() {
+            let start = i;
+            while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.') {
+                i += 1;
+            }
+            let text: String = chars[start..i].iter().collect();
+            let number = text.parse().map_err(|_| format!("bad number {}", text))?;
+            tokens.push(Token::Number(number));
+        } else if c.is_alphabetic() || c == '_' {
+            // `*` belongs to a path only as a whole wildcard segment
+            let start = i;
+            while i < chars.len()
+                && (chars[i].is_alphanumeric()
+                    || chars[i] == '_'
+                    || chars[i] == '.'
+                    || (chars[i] == '*' && matches!(chars[i - 1], '.' | '*')))
+            {
+                i += 1;
+            }
+            tokens.push(Token::Ident(chars[start..i].iter().collect()));
+        } else {
+            let rest: String = chars[i..].iter().take(2).collect();
+            let Some(op) = OPERATORS.iter().find(|op| rest.starts_with(**op)) else {
+                return Err(format!("unexpected character {:?}", c));
+            };
+            tokens.push(Token::Op(op));
+            i += op.len();
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+    inputs: Vec<String>,
+}
+
+// binary operators by precedence level, loosest first
+const PRECEDENCE: [&[(&str, BinaryOp)]; 5] = [
+    &[("||", BinaryOp::Or)],
+    &[("&&", BinaryOp::And)],
+    &[
+        ("==", BinaryOp::Eq),
+        ("!=", BinaryOp::Ne),
+        ("<=", BinaryOp::Le),
+        (">=", BinaryOp::Ge),
+        ("<", BinaryOp::Lt),
+        (">", BinaryOp::Gt),
+    ],
+    &[("+", BinaryOp::Add), ("-", BinaryOp::Sub)],
+    &[("*", BinaryOp::Mul), ("/", BinaryOp::Div)],
+];
+
+impl Parser {
+    fn parse_binary(&mut self, level: usize) -> std::result::Result<Node, String> {
+        let Some(operators) = PRECEDENCE.get(level) else {
+            return self.parse_unary();
+        };
+        let mut left = self.parse_binary(level + 1)?;
+        while let Some(Token::Op(symbol)) = self.tokens.get(self.position)
+            && let Some((_, op)) = operators.iter().find(|(s, _)| s == symbol)
+        {
+            self.position += 1;
+            let right = self.parse_binary(level + 1)?;
+            left = Node::Binary(*op, Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn parse_unary(&mut self) -> std::result::Result<Node, String> {
+        match self.next()? {
+            Token::Op("!") => Ok(Node::Not(Box::new(self.parse_unary()?))),
+            Token::Op("-") => Ok(Node::Negate(Box::new(self.parse_unary()?))),
+            Token::Op("(") => {
+                let node = self.parse_binary(0)?;
+                self.expect(")")?;
+                Ok(node)
+            }
+            Token::Number(n) => Ok(Node::Number(n)),
+            Token::Ident(ident) if ident == "true" => Ok(Node::Bool(true)),
+            Token::Ident(ident) if ident == "false" => Ok(Node::Bool(false)),
+            Token::Ident(ident) if self.tokens.get(self.position) == Some(&Token::Op("(")) => {
+                self.position += 1;
+                let function = parse_function(&ident)?;
+                let mut args = Vec::new();
+                if self.tokens.get(self.position) != Some(&Token::Op(")")) {
+                    loop {
+                        args.push(self.parse_binary(0)?);
+                        if self.tokens.get(self.position) != Some(&Token::Op(",")) {
+                            break;
+                        }
+                        self.position += 1;
+                    }
+                }
+                self.expect(")")?;
+                Ok(Node::Call(function, args))
+            }
+            Token::Ident(path) => {
+                if !self.inputs.contains(&path) {
+                    self.inputs.push(path.clone());
+                }
+                Ok(Node::Path(path))
+            }
+            token => Err(format!("unexpected {:?}", token)),
+        }
+    }
+
+    fn next(&mut self) -> std::result::Result<Token, String> {
+        let token = self.tokens.get(self.position).cloned().ok_or("unexpected end")?;
+        self.position += 1;
+        Ok(token)
+    }
+
+    fn expect(&mut self, op: &str) -> std::result::Result<(), String> {
+        match self.next()? {
+            Token::Op(found) if found == op => Ok(()),
+            token => Err(format!("expected {:?}, found {:?}", op, token)),
+        }
+    }
+}
+
+fn parse_function(name: &str) -> std::result::Result<Function, String> {
+    match name {
+        "any" => Ok(Function::Any),
+        "all" => Ok(Function::All),
+        "count" => Ok(Function::Count),
+        "sum" => Ok(Function::Sum),
+        "avg" => Ok(Function::Avg),
+        "min" => Ok(Function::Min),
+        "max" => Ok(Function::Max),
+        _ => Err(format!("unknown function {}", name)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lookup(path: &str) -> Result<Vec<Value>> {
+        let signals = [
+            ("Vehicle.Cabin.Door.Row1.IsOpen", Value::Bool(false)),
+            ("Vehicle.Cabin.Door.Row2.IsOpen", Value::Bool(true)),
+            ("Vehicle.Speed", Value::Float(120.0)),
+            ("Vehicle.Tire.Pressure", Value::Uint16Array(vec![200, 210, 220, 230])),
+            ("Vehicle.Unset", Value::NAN),
+        ];
+        Ok(signals
+            .iter()
+            .filter(|(signal, _)| path_pattern::matches(path, signal))
+            .map(|(_, value)| value.clone())
+            .collect())
+    }
+
+    fn evaluate(source: &str) -> Option<Operand> {
+        Expression::parse(source).unwrap().evaluate(&lookup).unwrap()
+    }
+
+    #[test]
+    fn test_functions_over_patterns() {
+        assert_eq!(evaluate("any(Vehicle.Cabin.Door.*.IsOpen)"), Some(Operand::Bool(true)));
+        assert_eq!(evaluate("all(Vehicle.Cabin.Door.*.IsOpen)"), Some(Operand::Bool(false)));
+        assert_eq!(evaluate("count(Vehicle.Cabin.Door.**)"), Some(Operand::Number(1.0)));
+        assert_eq!(evaluate("avg(Vehicle.Tire.Pressure)"), Some(Operand::Number(215.0)));
+        assert_eq!(evaluate("max(Vehicle.Tire.Pressure, 250)"), Some(Operand::Number(250.0)));
+        assert_eq!(evaluate("avg(Vehicle.Unset)"), None);
+    }
+
+    #[test]
+    fn test_operators() {
+        assert_eq!(evaluate("Vehicle.Speed > 100 && !false"), Some(Operand::Bool(true)));
+        assert_eq!(evaluate("Vehicle.Speed*2 - 40 / (1 + 1)"), Some(Operand::Number(220.0)));
+        assert_eq!(evaluate("-Vehicle.Speed + 20 == -100"), Some(Operand::Bool(true)));
+        // a path matching several signals is not a single value
+        assert_eq!(evaluate("Vehicle.Cabin.Door.*.IsOpen"), None);
+        assert_eq!(evaluate("Vehicle.Speed / 0"), None);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        for source in ["", "Vehicle.Speed >", "median(Vehicle.Speed)", "(1 + 2", "1 $ 2", "1 2"] {
+            assert!(Expression::parse(source).is_err(), "{}", source);
+        }
+        let expression = Expression::parse("any(Vehicle.Cabin.Door.*.IsOpen) || Vehicle.Speed > 0").unwrap();
+        assert_eq!(expression.inputs(), ["Vehicle.Cabin.Door.*.IsOpen", "Vehicle.Speed"]);
+        assert!(expression.depends_on("Vehicle.Cabin.Door.Row1.IsOpen"));
+        assert!(!expression.depends_on("Vehicle.Cabin.Door.Row1.IsLocked"));
+    }
+
+    #[test]
+    fn test_to_value() {
+        assert_eq!(Operand::Number(2.6).to_value(&ValueType::TypeUint8), Some(Value::Uint8(3)));
+        assert_eq!(Operand::Number(300.0).to_value(&ValueType::TypeUint8), None);
+        assert_eq!(Operand::Bool(true).to_value(&ValueType::TypeBool), Some(Value::Bool(true)));
+        assert_eq!(Operand::Number(1.5).to_value(&ValueType::TypeFloat), Some(Value::Float(1.5)));
+    }
+}
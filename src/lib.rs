@@ -0,0 +1,10 @@
+pub mod alert;
+pub mod config;
+pub mod error;
+pub mod expression;
+pub mod path_pattern;
+pub mod rpc;
+pub mod signal;
+pub mod snapshot;
+pub mod vehicle_shadow;
+pub mod vss_json_loader;
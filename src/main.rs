@@ -1,17 +1,11 @@
-mod config;
-mod error;
-mod rpc;
-pub mod signal;
-pub mod vehicle_shadow;
-pub mod vss_json_loader;
+use log::{error, info, warn};
+use std::time::Duration;
 
-use log::{error, info};
-use tokio;
-
-use crate::config::Config;
-use crate::error::Result;
-use crate::rpc::databroker_server::run_server;
-use crate::vehicle_shadow::VehicleShadow;
+use vehicle_signal_shadow::config::Config;
+use vehicle_signal_shadow::error::{Result, VehicleShadowError};
+use vehicle_signal_shadow::rpc::databroker_server::run_server;
+use vehicle_signal_shadow::vehicle_shadow::VehicleShadow;
+use vehicle_signal_shadow::{alert, vss_json_loader};
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -21,11 +15,14 @@ async fn main() -> Result<()> {
     info!("vehicle-signal-shadow service started");
     info!("Server address: {}", config.server_addr);
     info!("Log level: {}", config.log_level);
+    for mirror in &config.mirrors {
+        info!("Mirroring: {}", mirror);
+    }
     
     let vehicle_shadow = initialize(&config)?;
 
     let main_loop = async {
-        if let Err(e) = run_server(vehicle_shadow, &config.server_addr).await {
+        if let Err(e) = run_server(vehicle_shadow, &config).await {
             error!("Server error: {}", e);
         }
     };
@@ -49,27 +46,90 @@ async fn main() -> Result<()> {
 }
 
 fn initialize(config: &Config) -> Result<VehicleShadow> {
-    let signals = vss_json_loader::load_vss_json(config.vss.clone())?;
-    let vehicle_shadow = VehicleShadow::create()?;
+    // ミラーはVSSを読み込まず、上流から複製したシグナルだけを持つ
+    // 内容は接続のたびに上流のスナップショットから作り直すので、永続化はしない
+    if config.is_mirror() {
+        if config.db_path.is_some() {
+            return Err(VehicleShadowError::Configuration(
+                "--db-path cannot be used with --mirror".to_string(),
+            ));
+        }
+        let vehicle_shadow = VehicleShadow::create()?
+            .with_history(config.history_config())
+            .with_read_only(true);
+        return Ok(vehicle_shadow);
+    }
+
+    let vss = config
+        .vss
+        .clone()
+        .ok_or_else(|| VehicleShadowError::Configuration("--vss is required".to_string()))?;
+    let mut signals = vss_json_loader::load_vss_json(vss)?;
+
+    if let Some(db_path) = &config.db_path {
+        info!("Opening persistent database: {}", db_path);
+        let vehicle_shadow = VehicleShadow::create_with_path(db_path)?
+            .with_history(config.history_config())
+            .with_lock_ttl(Duration::from_secs(config.lock_ttl));
+        // 実行中に適用したオーバーレイをVSSに重ねてから復元する
+        for e in vehicle_shadow.apply_recorded_overlays(&mut signals)? {
+            warn!("Skipping overlay that no longer applies to the VSS: {}", e);
+        }
+        let report = vehicle_shadow.reconcile(signals)?;
+        info!("Restored shadow state ({})", report);
+        for path in &report.added {
+            info!("  added: {}", path);
+        }
+        for path in &report.updated {
+            info!("  updated: {}", path);
+        }
+        for path in &report.retired {
+            info!("  retired: {}", path);
+        }
+        vehicle_shadow.refresh_derived()?;
+        put_alert_rules(&vehicle_shadow, config)?;
+        return Ok(vehicle_shadow);
+    }
+
+    let vehicle_shadow = VehicleShadow::create()?
+        .with_history(config.history_config())
+        .with_lock_ttl(Duration::from_secs(config.lock_ttl));
     
+    let mut paths = Vec::new();
     for signal in signals {
-        if let Err(e) = vehicle_shadow.set_signal(signal, &None) {
-            error!("Failed to set signal: {}", e);
+        let path = signal.path.clone();
+        match vehicle_shadow.set_signal(signal, &None) {
+            Ok(_) => paths.push(path),
+            Err(e) => error!("Failed to set signal: {}", e),
         }
     }
+    vehicle_shadow.assign_ids(&paths)?;
+    vehicle_shadow.refresh_derived()?;
+    put_alert_rules(&vehicle_shadow, config)?;
     
     Ok(vehicle_shadow)
 }
 
+// 設定ファイルのルールは同じIDの登録済みルールを置き換える
+fn put_alert_rules(vehicle_shadow: &VehicleShadow, config: &Config) -> Result<()> {
+    let Some(path) = &config.alert_rules else {
+        return Ok(());
+    };
+    for rule in alert::read_rules(path)? {
+        info!("Alert rule: {}", rule.id);
+        vehicle_shadow.put_alert_rule(rule)?;
+    }
+    Ok(())
+}
+
 async fn cleanup() {
     info!("cleaning up resources...");
 }
 
 #[cfg(test)]
 mod tests {
-    use super::*;
-
     #[test]
+    #[allow(clippy::assertions_on_constants)]
     fn test_initialization() {
         // TODO: Add proper tests
         assert!(true);
@@ -0,0 +1,106 @@
+// VSS style path patterns:
+//   `*`  matches exactly one segment (e.g. Vehicle.Cabin.Door.*.IsOpen)
+//   `**` matches zero or more segments (e.g. Vehicle.Body.**)
+// Wildcards must span a whole segment.
+
+const SEPARATOR: char = '.';
+const SINGLE_WILDCARD: &str = "*";
+const MULTI_WILDCARD: &str = "**";
+
+pub fn is_pattern(path: &str) -> bool {
+    path.split(SEPARATOR)
+        .any(|segment| segment == SINGLE_WILDCARD || segment == MULTI_WILDCARD)
+}
+
+/// Returns the part of `pattern` before its first wildcard, suitable for a
+/// prefix scan over the ordered keyspace. Ends with a separator unless empty.
+pub fn literal_prefix(pattern: &str) -> String {
+    let mut prefix = String::new();
+    for segment in pattern.split(SEPARATOR) {
+        if segment == SINGLE_WILDCARD || segment == MULTI_WILDCARD {
+            break;
+        }
+        prefix.push_str(segment);
+        prefix.push(SEPARATOR);
+    }
+    prefix
+}
+
+pub fn matches(pattern: &str, path: &str) -> bool {
+    let pattern: Vec<&str> = pattern.split(SEPARATOR).collect();
+    let path: Vec<&str> = path.split(SEPARATOR).collect();
+    matches_segments(&pattern, &path)
+}
+
+/// Returns true if `pattern` may match a path below `prefix`, e.g.
+/// `Vehicle.*.Door.**` may match within `Vehicle.Cabin`.
+pub fn may_match_within(pattern: &str, prefix: &str) -> bool {
+    let mut pattern = pattern.split(SEPARATOR);
+    for expected in prefix.split(SEPARATOR) {
+        match pattern.next() {
+            Some(MULTI_WILDCARD) => return true,
+            Some(SINGLE_WILDCARD) => continue,
+            Some(segment) if segment == expected => continue,
+            _ => return false,
+        }
+    }
+    pattern.next().is_some()
+}
+
+fn matches_segments(pattern: &[&str], path: &[&str]) -> bool {
+    match (pattern.first(), path.first()) {
+        (None, None) => true,
+        (Some(&MULTI_WILDCARD), _) => {
+            matches_segments(&pattern[1..], path)
+                || (!path.is_empty() && matches_segments(pattern, &path[1..]))
+        }
+        (Some(&SINGLE_WILDCARD), Some(_)) => matches_segments(&pattern[1..], &path[1..]),
+        (Some(expected), Some(actual)) if expected == actual => {
+            matches_segments(&pattern[1..], &path[1..])
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_pattern() {
+        assert!(is_pattern("Vehicle.Cabin.Door.*.IsOpen"));
+        assert!(is_pattern("Vehicle.Body.**"));
+        assert!(!is_pattern("Vehicle.Speed"));
+    }
+
+    #[test]
+    fn test_literal_prefix() {
+        assert_eq!(literal_prefix("Vehicle.Cabin.Door.*.IsOpen"), "Vehicle.Cabin.Door.");
+        assert_eq!(literal_prefix("**"), "");
+    }
+
+    #[test]
+    fn test_single_wildcard() {
+        let pattern = "Vehicle.Cabin.Door.*.IsOpen";
+        assert!(matches(pattern, "Vehicle.Cabin.Door.Row1.IsOpen"));
+        assert!(!matches(pattern, "Vehicle.Cabin.Door.Row1.DriverSide.IsOpen"));
+        assert!(!matches(pattern, "Vehicle.Cabin.Door.Row1.IsLocked"));
+    }
+
+    #[test]
+    fn test_multi_wildcard() {
+        assert!(matches("Vehicle.Body.**", "Vehicle.Body.Hood.IsOpen"));
+        assert!(matches("Vehicle.Body.**", "Vehicle.Body.BodyType"));
+        assert!(matches("Vehicle.**.IsOpen", "Vehicle.Body.Hood.IsOpen"));
+        assert!(!matches("Vehicle.Body.**", "Vehicle.Cabin.Door.Row1.IsOpen"));
+    }
+
+    #[test]
+    fn test_may_match_within() {
+        assert!(may_match_within("Vehicle.**", "Vehicle.Cabin"));
+        assert!(may_match_within("Vehicle.*.Door.IsOpen", "Vehicle.Cabin"));
+        assert!(may_match_within("Vehicle.Cabin.*", "Vehicle.Cabin"));
+        assert!(!may_match_within("Vehicle.Body.**", "Vehicle.Cabin"));
+        assert!(!may_match_within("Vehicle.*", "Vehicle.Cabin.Door"));
+    }
+}
@@ -1,54 +1,102 @@
-use crate::rpc::databroker_server::vehicle_shadow::{LockRequest, LockResponse, UnlockRequest, UnlockResponse};
+use crate::alert::{Alert, AlertRule, Comparator, Severity};
+use crate::config::Config;
+use crate::error::{Result, VehicleShadowError};
+use crate::rpc::databroker_server::vehicle_shadow::{
+    ForceUnlockRequest, ForceUnlockResponse, ListLocksRequest, ListLocksResponse, LockRequest,
+    LockResponse, RenewLockRequest, RenewLockResponse, UnlockRequest, UnlockResponse,
+};
+use crate::path_pattern;
+use crate::rpc::federation::Federation;
+use crate::rpc::mirror::Mirror;
+use crate::rpc::subscriber_queue::SubscriberQueue;
+use crate::rpc::subscription_filter::{Decision, Notified, SubscriptionFilter};
 use crate::signal::{LeafType, Value, ValueType};
-use crate::vehicle_shadow::VehicleShadow;
-use uuid::{uuid, Uuid};
+use crate::snapshot::{Encoding, Snapshot};
+use crate::vehicle_shadow::{Lease, SignalNode, VehicleShadow};
+use crate::vss_json_loader;
+use uuid::Uuid;
 
-use log::{error, info};
-use std::collections::HashMap;
+use log::{error, info, warn};
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::sync::RwLock;
 use tonic::{Request, Response, Status};
 
 // 生成されたprotoファイルをインポート
 pub mod vehicle_shadow {
+    #![allow(clippy::enum_variant_names)]
     tonic::include_proto!("vehicle_shadow");
 }
 
 use vehicle_shadow::signal_service_server::{SignalService, SignalServiceServer};
 use vehicle_shadow::{
-    GetRequest, GetResponse, SetRequest, SetResponse, SetResult, SubscribeRequest,
-    SubscribeResponse, UnsubscribeRequest, UnsubscribeResponse,
+    provider_request, provider_response, ActuationRequest, ProviderRequest, ProviderResponse,
+    ProviderUpdate, RegistrationResult, UpdateResult,
+};
+use vehicle_shadow::{
+    ListAlertRulesRequest, ListAlertRulesResponse, ListAlertsRequest, ListAlertsResponse,
+    PutAlertRuleRequest, PutAlertRuleResponse, RemoveAlertRuleRequest, RemoveAlertRuleResponse,
+    SubscribeAlertsRequest,
+};
+use vehicle_shadow::{FactoryResetRequest, FactoryResetResponse, ResetSignalsRequest, ResetSignalsResponse};
+use vehicle_shadow::{ExportRequest, ExportResponse, ImportRequest, ImportResponse};
+use vehicle_shadow::{
+    ApplyOverlayRequest, ApplyOverlayResponse, GetHistoryRequest, GetHistoryResponse, GetMetadataRequest, GetMetadataResponse, GetRequest,
+    GetResponse, ListSignalsRequest, ListSignalsResponse, SignalMetadata, SetCurrentRequest,
+    SetCurrentResponse, SetRequest, SetResponse, SetResult, SetSignalRequest, SetTargetRequest, SetTargetResponse,
+    SubscribeRequest, SubscribeResponse, UnsubscribeRequest, UnsubscribeResponse,
 };
 
 // 変換関数: protoのValue -> RustのValue
-fn convert_proto_value_to_rust(proto_value: &vehicle_shadow::Value) -> crate::signal::Value {
-    match &proto_value.value {
+// protoには8/16bit整数型がないため、範囲外の値は切り捨てずにエラーにする
+fn convert_proto_value_to_rust(proto_value: &vehicle_shadow::Value) -> Result<crate::signal::Value> {
+    let value = match &proto_value.value {
         Some(vehicle_shadow::value::Value::BoolValue(v)) => crate::signal::Value::Bool(*v),
         Some(vehicle_shadow::value::Value::StringValue(v)) => crate::signal::Value::String(v.clone()),
-        Some(vehicle_shadow::value::Value::Int8Value(v)) => crate::signal::Value::Int8(*v as i8),
-        Some(vehicle_shadow::value::Value::Int16Value(v)) => crate::signal::Value::Int16(*v as i16),
+        Some(vehicle_shadow::value::Value::Int8Value(v)) => crate::signal::Value::Int8(narrow(*v, "int8")?),
+        Some(vehicle_shadow::value::Value::Int16Value(v)) => crate::signal::Value::Int16(narrow(*v, "int16")?),
         Some(vehicle_shadow::value::Value::Int32Value(v)) => crate::signal::Value::Int32(*v),
         Some(vehicle_shadow::value::Value::Int64Value(v)) => crate::signal::Value::Int64(*v),
-        Some(vehicle_shadow::value::Value::Uint8Value(v)) => crate::signal::Value::Uint8(*v as u8),
-        Some(vehicle_shadow::value::Value::Uint16Value(v)) => crate::signal::Value::Uint16(*v as u16),
+        Some(vehicle_shadow::value::Value::Uint8Value(v)) => crate::signal::Value::Uint8(narrow(*v, "uint8")?),
+        Some(vehicle_shadow::value::Value::Uint16Value(v)) => crate::signal::Value::Uint16(narrow(*v, "uint16")?),
         Some(vehicle_shadow::value::Value::Uint32Value(v)) => crate::signal::Value::Uint32(*v),
         Some(vehicle_shadow::value::Value::Uint64Value(v)) => crate::signal::Value::Uint64(*v),
         Some(vehicle_shadow::value::Value::FloatValue(v)) => crate::signal::Value::Float(*v),
         Some(vehicle_shadow::value::Value::DoubleValue(v)) => crate::signal::Value::Double(*v),
         Some(vehicle_shadow::value::Value::BoolArrayValue(v)) => crate::signal::Value::BoolArray(v.values.clone()),
         Some(vehicle_shadow::value::Value::StringArrayValue(v)) => crate::signal::Value::StringArray(v.values.clone()),
-        Some(vehicle_shadow::value::Value::Int8ArrayValue(v)) => crate::signal::Value::Int8Array(v.values.iter().map(|&x| x as i8).collect()),
-        Some(vehicle_shadow::value::Value::Int16ArrayValue(v)) => crate::signal::Value::Int16Array(v.values.iter().map(|&x| x as i16).collect()),
+        Some(vehicle_shadow::value::Value::Int8ArrayValue(v)) => crate::signal::Value::Int8Array(narrow_all(&v.values, "int8")?),
+        Some(vehicle_shadow::value::Value::Int16ArrayValue(v)) => crate::signal::Value::Int16Array(narrow_all(&v.values, "int16")?),
         Some(vehicle_shadow::value::Value::Int32ArrayValue(v)) => crate::signal::Value::Int32Array(v.values.clone()),
         Some(vehicle_shadow::value::Value::Int64ArrayValue(v)) => crate::signal::Value::Int64Array(v.values.clone()),
-        Some(vehicle_shadow::value::Value::Uint8ArrayValue(v)) => crate::signal::Value::Uint8Array(v.values.iter().map(|&x| x as u8).collect()),
-        Some(vehicle_shadow::value::Value::Uint16ArrayValue(v)) => crate::signal::Value::Uint16Array(v.values.iter().map(|&x| x as u16).collect()),
+        Some(vehicle_shadow::value::Value::Uint8ArrayValue(v)) => crate::signal::Value::Uint8Array(narrow_all(&v.values, "uint8")?),
+        Some(vehicle_shadow::value::Value::Uint16ArrayValue(v)) => crate::signal::Value::Uint16Array(narrow_all(&v.values, "uint16")?),
         Some(vehicle_shadow::value::Value::Uint32ArrayValue(v)) => crate::signal::Value::Uint32Array(v.values.clone()),
         Some(vehicle_shadow::value::Value::Uint64ArrayValue(v)) => crate::signal::Value::Uint64Array(v.values.clone()),
         Some(vehicle_shadow::value::Value::FloatArrayValue(v)) => crate::signal::Value::FloatArray(v.values.clone()),
         Some(vehicle_shadow::value::Value::DoubleArrayValue(v)) => crate::signal::Value::DoubleArray(v.values.clone()),
         None => crate::signal::Value::NAN,
-    }
+    };
+    Ok(value)
+}
+
+fn narrow<F, T>(value: F, type_name: &str) -> Result<T>
+where
+    F: Copy + std::fmt::Display,
+    T: TryFrom<F>,
+{
+    T::try_from(value).map_err(|_| {
+        VehicleShadowError::InvalidInput(format!("{} is out of range for {}", value, type_name))
+    })
+}
+
+fn narrow_all<F, T>(values: &[F], type_name: &str) -> Result<Vec<T>>
+where
+    F: Copy + std::fmt::Display,
+    T: TryFrom<F>,
+{
+    values.iter().map(|v| narrow(*v, type_name)).collect()
 }
 
 // 変換関数: RustのValue -> protoのValue
@@ -193,6 +241,37 @@ fn convert_value_type_to_proto(value_type: &ValueType) -> vehicle_shadow::ValueT
     }
 }
 
+// 変換関数: protoのValueType -> RustのValueType
+fn convert_proto_value_type_to_rust(value_type: vehicle_shadow::ValueType) -> ValueType {
+    match value_type {
+        vehicle_shadow::ValueType::TypeNan => ValueType::TypeNAN,
+        vehicle_shadow::ValueType::TypeBool => ValueType::TypeBool,
+        vehicle_shadow::ValueType::TypeString => ValueType::TypeString,
+        vehicle_shadow::ValueType::TypeInt8 => ValueType::TypeInt8,
+        vehicle_shadow::ValueType::TypeInt16 => ValueType::TypeInt16,
+        vehicle_shadow::ValueType::TypeInt32 => ValueType::TypeInt32,
+        vehicle_shadow::ValueType::TypeInt64 => ValueType::TypeInt64,
+        vehicle_shadow::ValueType::TypeUint8 => ValueType::TypeUint8,
+        vehicle_shadow::ValueType::TypeUint16 => ValueType::TypeUint16,
+        vehicle_shadow::ValueType::TypeUint32 => ValueType::TypeUint32,
+        vehicle_shadow::ValueType::TypeUint64 => ValueType::TypeUint64,
+        vehicle_shadow::ValueType::TypeFloat => ValueType::TypeFloat,
+        vehicle_shadow::ValueType::TypeDouble => ValueType::TypeDouble,
+        vehicle_shadow::ValueType::TypeBoolArray => ValueType::TypeBoolArray,
+        vehicle_shadow::ValueType::TypeStringArray => ValueType::TypeStringArray,
+        vehicle_shadow::ValueType::TypeInt8Array => ValueType::TypeInt8Array,
+        vehicle_shadow::ValueType::TypeInt16Array => ValueType::TypeInt16Array,
+        vehicle_shadow::ValueType::TypeInt32Array => ValueType::TypeInt32Array,
+        vehicle_shadow::ValueType::TypeInt64Array => ValueType::TypeInt64Array,
+        vehicle_shadow::ValueType::TypeUint8Array => ValueType::TypeUint8Array,
+        vehicle_shadow::ValueType::TypeUint16Array => ValueType::TypeUint16Array,
+        vehicle_shadow::ValueType::TypeUint32Array => ValueType::TypeUint32Array,
+        vehicle_shadow::ValueType::TypeUint64Array => ValueType::TypeUint64Array,
+        vehicle_shadow::ValueType::TypeFloatArray => ValueType::TypeFloatArray,
+        vehicle_shadow::ValueType::TypeDoubleArray => ValueType::TypeDoubleArray,
+    }
+}
+
 // 変換関数: RustのLeafType -> protoのLeafType
 fn convert_leaf_type_to_proto(leaf_type: &LeafType) -> vehicle_shadow::LeafType {
     match leaf_type {
@@ -203,102 +282,885 @@ fn convert_leaf_type_to_proto(leaf_type: &LeafType) -> vehicle_shadow::LeafType
     }
 }
 
+// 変換関数: protoのLeafType -> RustのLeafType
+fn convert_proto_leaf_type_to_rust(leaf_type: vehicle_shadow::LeafType) -> LeafType {
+    match leaf_type {
+        vehicle_shadow::LeafType::Branch => LeafType::Branch,
+        vehicle_shadow::LeafType::Sensor => LeafType::Sensor,
+        vehicle_shadow::LeafType::Attribute => LeafType::Attribute,
+        vehicle_shadow::LeafType::Actuator => LeafType::Actuator,
+    }
+}
+
+// 変換関数: RustのTimestamp <-> protoのTimestamp
+fn convert_timestamp_to_proto(timestamp: &crate::signal::Timestamp) -> vehicle_shadow::Timestamp {
+    vehicle_shadow::Timestamp {
+        wall_clock_ns: timestamp.wall_clock_ns,
+        monotonic_ns: timestamp.monotonic_ns,
+    }
+}
+
+fn convert_proto_timestamp_to_rust(timestamp: &vehicle_shadow::Timestamp) -> crate::signal::Timestamp {
+    crate::signal::Timestamp {
+        wall_clock_ns: timestamp.wall_clock_ns,
+        monotonic_ns: timestamp.monotonic_ns,
+    }
+}
+
+// 変換関数: RustのState -> protoのState
+fn convert_state_to_proto(state: &crate::signal::State) -> vehicle_shadow::State {
+    vehicle_shadow::State {
+        value: Some(convert_value_to_proto(&state.value)),
+        capability: Some(state.capability),
+        availability: Some(state.availability),
+        reserved: Some(state.reserved.clone()),
+        sequence: Some(state.sequence),
+        source_timestamp: state.source_timestamp.as_ref().map(convert_timestamp_to_proto),
+        sink_timestamp: state.sink_timestamp.as_ref().map(convert_timestamp_to_proto),
+        target: state.target.as_ref().map(convert_value_to_proto),
+    }
+}
+
+// 変換関数: RustのLease -> protoのLockInfo
+fn convert_lease_to_proto(lease: &Lease) -> vehicle_shadow::LockInfo {
+    vehicle_shadow::LockInfo {
+        token: lease.token.clone(),
+        holder: lease.holder.clone(),
+        paths: lease.paths.clone(),
+        expires_at_ns: lease.expires_at_ns,
+    }
+}
+
+// IDで指定されたシグナルはIDと状態だけを返す。パスと設定はGetMetadataで取得済みとする
+fn convert_signal_to_compact_proto(signal: &crate::signal::Signal) -> vehicle_shadow::Signal {
+    vehicle_shadow::Signal {
+        path: String::new(),
+        id: signal.id,
+        state: Some(convert_state_to_proto(&signal.state)),
+        config: None,
+    }
+}
+
 // 変換関数: RustのSignal -> protoのSignal
 fn convert_signal_to_proto(signal: &crate::signal::Signal) -> vehicle_shadow::Signal {
     vehicle_shadow::Signal {
         path: signal.path.clone(),
-        state: Some(vehicle_shadow::State {
-            value: Some(convert_value_to_proto(&signal.state.value)),
-            capability: Some(signal.state.capability),
-            availability: Some(signal.state.availability),
-            reserved: Some(signal.state.reserved.clone()),
-        }),
-        config: Some(vehicle_shadow::Config {
-            leaf_type: convert_leaf_type_to_proto(&signal.config.leaf_type) as i32,
-            data_type: convert_value_type_to_proto(&signal.config.data_type) as i32,
-            deprecation: signal.config.deprecation.clone(),
-            unit: signal.config.unit.clone(),
-            min: signal.config.min.as_ref().map(convert_value_to_proto),
-            max: signal.config.max.as_ref().map(convert_value_to_proto),
-            description: signal.config.description.clone(),
-            comment: signal.config.comment.clone(),
-            allowd: signal
-                .config
+        id: signal.id,
+        state: Some(convert_state_to_proto(&signal.state)),
+        config: Some(convert_config_to_proto(&signal.config)),
+    }
+}
+
+// 変換関数: RustのConfig -> protoのConfig
+fn convert_config_to_proto(config: &crate::signal::Config) -> vehicle_shadow::Config {
+    vehicle_shadow::Config {
+        leaf_type: convert_leaf_type_to_proto(&config.leaf_type) as i32,
+        data_type: convert_value_type_to_proto(&config.data_type) as i32,
+        deprecation: config.deprecation.clone(),
+        unit: config.unit.clone(),
+        min: config.min.as_ref().map(convert_value_to_proto),
+        max: config.max.as_ref().map(convert_value_to_proto),
+        description: config.description.clone(),
+        comment: config.comment.clone(),
+        allowd: config
+            .allowd
+            .as_ref()
+            .map(|v| v.iter().map(convert_value_to_proto).collect())
+            .unwrap_or_default(),
+        default: config.default.as_ref().map(convert_value_to_proto),
+        end_point: config.end_point.clone(),
+        expression: config.expression.clone(),
+        update_period: config.update_period,
+    }
+}
+
+fn convert_signal_to_metadata(signal: &crate::signal::Signal) -> SignalMetadata {
+    SignalMetadata {
+        path: signal.path.clone(),
+        id: signal.id,
+        config: Some(convert_config_to_proto(&signal.config)),
+    }
+}
+
+// ブランチはデータベースに保存されていないため、IDを持たずleaf_typeだけがBRANCHになる
+fn convert_node_to_proto(node: &SignalNode) -> SignalMetadata {
+    match node {
+        SignalNode::Branch(path) => SignalMetadata {
+            path: path.clone(),
+            id: 0,
+            config: Some(vehicle_shadow::Config {
+                leaf_type: vehicle_shadow::LeafType::Branch as i32,
+                ..Default::default()
+            }),
+        },
+        SignalNode::Leaf(signal) => convert_signal_to_metadata(signal),
+    }
+}
+
+// page_tokenは前のページの最後のノードのパス。途中でシグナルが増減してもその続きから返せる
+fn page_nodes(nodes: Vec<SignalNode>, page_token: &str, page_size: usize) -> (Vec<SignalNode>, String) {
+    let mut remaining = nodes.into_iter().skip_while(|node| {
+        !page_token.is_empty() && SignalNode::compare_paths(node.path(), page_token).is_le()
+    });
+    let page: Vec<SignalNode> = remaining.by_ref().take(page_size).collect();
+    let next_page_token = match (remaining.next(), page.last()) {
+        (Some(_), Some(last)) => last.path().to_string(),
+        _ => String::new(),
+    };
+    (page, next_page_token)
+}
+
+fn convert_comparator_to_proto(comparator: Comparator) -> vehicle_shadow::Comparator {
+    match comparator {
+        Comparator::Less => vehicle_shadow::Comparator::Less,
+        Comparator::LessEqual => vehicle_shadow::Comparator::LessEqual,
+        Comparator::Greater => vehicle_shadow::Comparator::Greater,
+        Comparator::GreaterEqual => vehicle_shadow::Comparator::GreaterEqual,
+        Comparator::Equal => vehicle_shadow::Comparator::Equal,
+        Comparator::NotEqual => vehicle_shadow::Comparator::NotEqual,
+    }
+}
+
+fn convert_proto_comparator_to_rust(comparator: vehicle_shadow::Comparator) -> Comparator {
+    match comparator {
+        vehicle_shadow::Comparator::Less => Comparator::Less,
+        vehicle_shadow::Comparator::LessEqual => Comparator::LessEqual,
+        vehicle_shadow::Comparator::Greater => Comparator::Greater,
+        vehicle_shadow::Comparator::GreaterEqual => Comparator::GreaterEqual,
+        vehicle_shadow::Comparator::Equal => Comparator::Equal,
+        vehicle_shadow::Comparator::NotEqual => Comparator::NotEqual,
+    }
+}
+
+fn convert_severity_to_proto(severity: Severity) -> vehicle_shadow::AlertSeverity {
+    match severity {
+        Severity::Info => vehicle_shadow::AlertSeverity::Info,
+        Severity::Warning => vehicle_shadow::AlertSeverity::Warning,
+        Severity::Critical => vehicle_shadow::AlertSeverity::Critical,
+    }
+}
+
+fn convert_proto_severity_to_rust(severity: vehicle_shadow::AlertSeverity) -> Severity {
+    match severity {
+        vehicle_shadow::AlertSeverity::Info => Severity::Info,
+        vehicle_shadow::AlertSeverity::Warning => Severity::Warning,
+        vehicle_shadow::AlertSeverity::Critical => Severity::Critical,
+    }
+}
+
+fn convert_alert_rule_to_proto(rule: &AlertRule) -> vehicle_shadow::AlertRule {
+    vehicle_shadow::AlertRule {
+        id: rule.id.clone(),
+        path: rule.path.clone(),
+        comparator: convert_comparator_to_proto(rule.comparator) as i32,
+        threshold: rule.threshold,
+        hysteresis: rule.hysteresis,
+        condition: rule.condition.clone(),
+        severity: convert_severity_to_proto(rule.severity) as i32,
+        message: rule.message.clone(),
+    }
+}
+
+fn convert_proto_alert_rule_to_rust(rule: &vehicle_shadow::AlertRule) -> Result<AlertRule> {
+    let comparator = vehicle_shadow::Comparator::try_from(rule.comparator)
+        .map_err(|_| VehicleShadowError::InvalidInput(format!("Unknown comparator: {}", rule.comparator)))?;
+    let severity = vehicle_shadow::AlertSeverity::try_from(rule.severity)
+        .map_err(|_| VehicleShadowError::InvalidInput(format!("Unknown severity: {}", rule.severity)))?;
+    Ok(AlertRule {
+        id: rule.id.clone(),
+        path: rule.path.clone(),
+        comparator: convert_proto_comparator_to_rust(comparator),
+        threshold: rule.threshold,
+        hysteresis: rule.hysteresis,
+        condition: rule.condition.clone(),
+        severity: convert_proto_severity_to_rust(severity),
+        message: rule.message.clone(),
+    })
+}
+
+fn convert_alert_to_proto(alert: &Alert) -> vehicle_shadow::Alert {
+    vehicle_shadow::Alert {
+        rule_id: alert.rule_id.clone(),
+        active: alert.active,
+        sequence: alert.sequence,
+        timestamp: Some(convert_timestamp_to_proto(&alert.timestamp)),
+        severity: convert_severity_to_proto(alert.severity) as i32,
+        message: alert.message.clone(),
+        value: (alert.value != Value::NAN).then(|| convert_value_to_proto(&alert.value)),
+    }
+}
+
+// 変換関数: protoのSignal -> RustのSignal
+// ミラーが上流のシグナルを採番やタイムスタンプを含めてそのまま保存するために使う
+pub(crate) fn convert_proto_signal_to_rust(signal: &vehicle_shadow::Signal) -> Result<crate::signal::Signal> {
+    let missing = |field: &str| VehicleShadowError::InvalidInput(format!("{} is missing: {}", field, signal.path));
+    let proto_state = signal.state.as_ref().ok_or_else(|| missing("state"))?;
+    let proto_config = signal.config.as_ref().ok_or_else(|| missing("config"))?;
+    let convert_optional = |value: &Option<vehicle_shadow::Value>| value.as_ref().map(convert_proto_value_to_rust).transpose();
+
+    let data_type = vehicle_shadow::ValueType::try_from(proto_config.data_type)
+        .map_err(|_| VehicleShadowError::InvalidInput(format!("Unknown data type: {}", proto_config.data_type)))?;
+    let leaf_type = vehicle_shadow::LeafType::try_from(proto_config.leaf_type)
+        .map_err(|_| VehicleShadowError::InvalidInput(format!("Unknown leaf type: {}", proto_config.leaf_type)))?;
+    let allowd = if proto_config.allowd.is_empty() {
+        None
+    } else {
+        Some(
+            proto_config
                 .allowd
-                .as_ref()
-                .map(|v| v.iter().map(convert_value_to_proto).collect())
-                .unwrap_or_default(),
-            default: signal.config.default.as_ref().map(convert_value_to_proto),
-            end_point: signal.config.end_point.clone(),
-        }),
+                .iter()
+                .map(convert_proto_value_to_rust)
+                .collect::<Result<Vec<_>>>()?,
+        )
+    };
+
+    Ok(crate::signal::Signal {
+        path: signal.path.clone(),
+        id: signal.id,
+        state: crate::signal::State {
+            value: convert_optional(&proto_state.value)?.unwrap_or(Value::NAN),
+            capability: proto_state.capability.unwrap_or_default(),
+            availability: proto_state.availability.unwrap_or_default(),
+            lock_uuid: None,
+            reserved: proto_state.reserved.clone().unwrap_or_default(),
+            sequence: proto_state.sequence.unwrap_or_default(),
+            source_timestamp: proto_state.source_timestamp.as_ref().map(convert_proto_timestamp_to_rust),
+            sink_timestamp: proto_state.sink_timestamp.as_ref().map(convert_proto_timestamp_to_rust),
+            target: convert_optional(&proto_state.target)?,
+        },
+        config: crate::signal::Config {
+            leaf_type: convert_proto_leaf_type_to_rust(leaf_type),
+            data_type: convert_proto_value_type_to_rust(data_type),
+            deprecation: proto_config.deprecation.clone(),
+            unit: proto_config.unit.clone(),
+            min: convert_optional(&proto_config.min)?,
+            max: convert_optional(&proto_config.max)?,
+            description: proto_config.description.clone(),
+            comment: proto_config.comment.clone(),
+            allowd,
+            default: convert_optional(&proto_config.default)?,
+            end_point: proto_config.end_point.clone(),
+            expression: proto_config.expression.clone(),
+            update_period: proto_config.update_period,
+        },
+    })
+}
+
+const LOCK_REAPER_INTERVAL: Duration = Duration::from_secs(1);
+const STALENESS_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+const SUBSCRIPTION_FLUSH_INTERVAL: Duration = Duration::from_millis(10);
+// 配送タスクとgRPCストリームの間のバッファ。溜まった通知はSubscriberQueueで扱う
+const SUBSCRIBER_STREAM_BUFFER: usize = 4;
+const MAX_SUBSCRIBER_QUEUE_SIZE: usize = 10_000;
+const ALERT_STREAM_BUFFER: usize = 100;
+const DEFAULT_ALERT_LOG_LIMIT: usize = 100;
+const DEFAULT_LIST_PAGE_SIZE: usize = 100;
+const MAX_LIST_PAGE_SIZE: usize = 1_000;
+
+struct Subscription {
+    paths: Vec<String>,
+    queue: SubscriberQueue,
+    filter: SubscriptionFilter,
+    // IDだけで購読した場合はIDと状態だけを通知する
+    compact: bool,
+    // パスごとに最後に通知した状態と、最小間隔のため保留中の最新の状態
+    notified: HashMap<String, Notified>,
+    pending: HashMap<String, crate::signal::Signal>,
+    // 現在の値として送った状態の通し番号。これ以前の状態の通知は送らない
+    initial: HashMap<String, u64>,
+    // 購読解除時にdropされ、監視タスクを終了させる
+    _cancel: tokio::sync::oneshot::Sender<()>,
+}
+
+impl Subscription {
+    // 同時に変更されたシグナルは1つの応答にまとめて送る
+    fn send(&mut self, id: &str, signals: &[crate::signal::Signal], now: Instant) {
+        if signals.is_empty() {
+            return;
+        }
+        for signal in signals {
+            self.notified.insert(signal.path.clone(), Notified::new(signal, now));
+        }
+        self.queue.push(create_subscribe_response(id, signals, self.compact));
+    }
+}
+
+// シグナルが1つならsignalに、複数ならsignalsに入れる
+fn create_subscribe_response(id: &str, signals: &[crate::signal::Signal], compact: bool) -> SubscribeResponse {
+    let convert = if compact {
+        convert_signal_to_compact_proto
+    } else {
+        convert_signal_to_proto
+    };
+    let mut signals: Vec<vehicle_shadow::Signal> = signals.iter().map(convert).collect();
+    let signal = if signals.len() == 1 { signals.pop() } else { None };
+    SubscribeResponse {
+        signal,
+        signals,
+        error_message: String::new(),
+        subscription_id: id.to_string(),
+        dropped: 0,
     }
 }
 
 // 購読管理用の構造体
+// 購読はSubscribe呼び出しごとにサーバーが払い出すIDで管理する
+// ワイルドカードを含むパスはパターンとして保持し、通知時に照合する
 #[derive(Default)]
 pub struct SubscriptionManager {
-    subscriptions: HashMap<String, Vec<tokio::sync::mpsc::Sender<SubscribeResponse>>>,
+    subscriptions: HashMap<String, Subscription>,
+    path_index: HashMap<String, HashSet<String>>,
+    pattern_index: HashMap<String, HashSet<String>>,
+    // 購読されているパスごとに最後に振り分けた状態の通し番号
+    sequences: HashMap<String, u64>,
+    alert_subscribers: Vec<AlertSubscriber>,
+}
+
+type AlertSender = tokio::sync::mpsc::Sender<std::result::Result<vehicle_shadow::Alert, Status>>;
+
+// アラートの購読者。ルールごとに最後に送った通し番号以前の通知は送らない
+struct AlertSubscriber {
+    sender: AlertSender,
+    sequences: HashMap<String, u64>,
+}
+
+impl AlertSubscriber {
+    // 送れなくなった(切断した、またはバッファが溢れた)場合はfalse
+    fn send(&mut self, alerts: &[Alert]) -> bool {
+        for alert in alerts {
+            let last = self.sequences.entry(alert.rule_id.clone()).or_insert(0);
+            if alert.sequence <= *last {
+                continue;
+            }
+            *last = alert.sequence;
+            match self.sender.try_send(Ok(convert_alert_to_proto(alert))) {
+                Ok(()) => {}
+                Err(tokio::sync::mpsc::error::TrySendError::Full(_)) => {
+                    warn!("Alert subscriber is not keeping up, disconnecting it");
+                    return false;
+                }
+                Err(tokio::sync::mpsc::error::TrySendError::Closed(_)) => return false,
+            }
+        }
+        true
+    }
 }
 
 impl SubscriptionManager {
     pub fn new() -> Self {
         Self {
             subscriptions: HashMap::new(),
+            path_index: HashMap::new(),
+            pattern_index: HashMap::new(),
+            sequences: HashMap::new(),
+            alert_subscribers: Vec::new(),
+        }
+    }
+
+    /// Registers an alert stream and sends it the `active` alerts first.
+    pub fn subscribe_alerts(&mut self, sender: AlertSender, active: &[Alert]) {
+        let mut subscriber = AlertSubscriber {
+            sender,
+            sequences: HashMap::new(),
+        };
+        if subscriber.send(active) {
+            self.alert_subscribers.push(subscriber);
         }
     }
 
+    pub fn notify_alerts(&mut self, alerts: &[Alert]) {
+        self.alert_subscribers.retain_mut(|subscriber| subscriber.send(alerts));
+    }
+
     pub fn subscribe(
         &mut self,
-        path: String,
-    ) -> tokio::sync::mpsc::Receiver<SubscribeResponse> {
-        let (tx, rx) = tokio::sync::mpsc::channel(100);
-        self.subscriptions
-            .entry(path)
-            .or_insert_with(Vec::new)
-            .push(tx);
-        rx
+        paths: Vec<String>,
+        filter: SubscriptionFilter,
+        compact: bool,
+        queue: SubscriberQueue,
+    ) -> (String, tokio::sync::oneshot::Receiver<()>) {
+        let id = Uuid::new_v4().to_string();
+        let (cancel_tx, cancel_rx) = tokio::sync::oneshot::channel();
+        for path in &paths {
+            let index = if path_pattern::is_pattern(path) {
+                &mut self.pattern_index
+            } else {
+                &mut self.path_index
+            };
+            index.entry(path.clone()).or_default().insert(id.clone());
+        }
+        self.subscriptions.insert(
+            id.clone(),
+            Subscription {
+                paths,
+                queue,
+                filter,
+                compact,
+                notified: HashMap::new(),
+                pending: HashMap::new(),
+                initial: HashMap::new(),
+                _cancel: cancel_tx,
+            },
+        );
+        (id, cancel_rx)
+    }
+
+    pub fn unsubscribe(&mut self, id: &str) -> bool {
+        let Some(subscription) = self.subscriptions.remove(id) else {
+            return false;
+        };
+        for path in &subscription.paths {
+            for index in [&mut self.path_index, &mut self.pattern_index] {
+                if let Some(ids) = index.get_mut(path) {
+                    ids.remove(id);
+                    if ids.is_empty() {
+                        index.remove(path);
+                    }
+                }
+            }
+        }
+        let unsubscribed: Vec<String> = self
+            .sequences
+            .keys()
+            .filter(|path| self.subscribers_of(path).is_empty())
+            .cloned()
+            .collect();
+        for path in unsubscribed {
+            self.sequences.remove(&path);
+        }
+        true
+    }
+
+    fn subscribers_of(&self, path: &str) -> HashSet<String> {
+        let pattern_ids = self
+            .pattern_index
+            .iter()
+            .filter(|(pattern, _)| path_pattern::matches(pattern, path))
+            .flat_map(|(_, ids)| ids);
+        self.path_index
+            .get(path)
+            .into_iter()
+            .flatten()
+            .chain(pattern_ids)
+            .cloned()
+            .collect()
+    }
+
+    // 購読ごとのフィルターを通してから通知する
+    // 1回の書き込みで変更されたシグナルは、購読ごとに1つの応答にまとめる
+    // 書き込みは並行して行われるので、同じパスのより新しい状態の後に届いた通知は
+    // 送らずに、取りこぼした更新としてdroppedに数える
+    pub fn notify(&mut self, signals: &[crate::signal::Signal]) {
+        let now = Instant::now();
+        let mut batches: HashMap<String, Vec<crate::signal::Signal>> = HashMap::new();
+        for signal in signals {
+            let path = &signal.path;
+            let subscribers = self.subscribers_of(path);
+            if subscribers.is_empty() {
+                continue;
+            }
+            let out_of_order = self
+                .sequences
+                .get(path)
+                .is_some_and(|sequence| *sequence >= signal.state.sequence);
+            if !out_of_order {
+                self.sequences.insert(path.clone(), signal.state.sequence);
+            }
+            for id in subscribers {
+                let Some(subscription) = self.subscriptions.get_mut(&id) else {
+                    continue;
+                };
+                if subscription
+                    .initial
+                    .get(path)
+                    .is_some_and(|sequence| *sequence >= signal.state.sequence)
+                {
+                    continue;
+                }
+                if out_of_order {
+                    subscription.queue.count_dropped();
+                    continue;
+                }
+                let last = subscription.notified.get(path);
+                // 同じ応答に入る前の値ではなく、最後に通知した値と比べる
+                let last = batches
+                    .get(&id)
+                    .and_then(|batch| batch.iter().rev().find(|sent| sent.path == *path))
+                    .map(|sent| Notified::new(sent, now))
+                    .or_else(|| last.cloned());
+                match subscription.filter.decide(last.as_ref(), signal, now) {
+                    Decision::Send => {
+                        subscription.pending.remove(path);
+                        batches.entry(id).or_default().push(signal.clone());
+                    }
+                    Decision::Defer => {
+                        subscription.pending.insert(path.clone(), signal.clone());
+                    }
+                    Decision::Drop => {
+                        // 最後に通知した値から変化していないので、保留中の古い値も送らない
+                        subscription.pending.remove(path);
+                    }
+                }
+            }
+        }
+        for (id, batch) in batches {
+            if let Some(subscription) = self.subscriptions.get_mut(&id) {
+                subscription.send(&id, &batch, now);
+            }
+        }
+    }
+
+    /// Notifies states whose sequence numbers may restart, such as replicas
+    /// of a restarted upstream, without dropping them as out of order.
+    /// The `removed` signals are notified once as removed and then
+    /// forgotten.
+    pub fn notify_replaced(&mut self, signals: &[crate::signal::Signal], removed: &[crate::signal::Signal]) {
+        let notices = removal_notices(removed.to_vec());
+        for signal in signals.iter().chain(&notices) {
+            self.forget(&signal.path);
+        }
+        self.notify(&[signals, &notices].concat());
+        for signal in &notices {
+            self.forget(&signal.path);
+        }
+    }
+
+    fn forget(&mut self, path: &str) {
+        self.sequences.remove(path);
+        for subscription in self.subscriptions.values_mut() {
+            subscription.initial.remove(path);
+        }
+    }
+
+    /// Records a state sent outside `notify`, such as the initial values.
+    /// Notifications of that state or older ones are not sent afterwards.
+    pub fn mark_notified(&mut self, id: &str, signal: &crate::signal::Signal) {
+        if let Some(subscription) = self.subscriptions.get_mut(id) {
+            subscription
+                .notified
+                .insert(signal.path.clone(), Notified::new(signal, Instant::now()));
+            subscription.initial.insert(signal.path.clone(), signal.state.sequence);
+        }
+    }
+
+    // 最小間隔が過ぎた保留中の通知を送る
+    pub fn flush_pending(&mut self) {
+        let now = Instant::now();
+        for (id, subscription) in self.subscriptions.iter_mut() {
+            let ready: Vec<String> = subscription
+                .pending
+                .keys()
+                .filter(|path| {
+                    subscription
+                        .notified
+                        .get(*path)
+                        .is_none_or(|last| subscription.filter.is_ready(last, now))
+                })
+                .cloned()
+                .collect();
+            let batch: Vec<crate::signal::Signal> = ready
+                .iter()
+                .filter_map(|path| subscription.pending.remove(path))
+                .collect();
+            subscription.send(id, &batch, now);
+        }
+    }
+}
+
+enum Notification {
+    Written(Vec<crate::signal::Signal>),
+    Replaced {
+        signals: Vec<crate::signal::Signal>,
+        removed: Vec<crate::signal::Signal>,
+    },
+    Alerts(Vec<Alert>),
+}
+
+// 変更通知を購読者に振り分ける唯一のタスクへの送信口
+// このタスクが通知を1つずつ処理し、古い状態の通知を捨てるので、
+// 同じパスへの通知は通し番号の順に購読者に届く
+#[derive(Clone)]
+pub struct NotificationDispatcher {
+    sender: tokio::sync::mpsc::UnboundedSender<Notification>,
+    vehicle_shadow: Arc<VehicleShadow>,
+}
+
+impl NotificationDispatcher {
+    // 最小間隔で保留した通知の送信も同じタスクで行う
+    pub fn spawn(
+        vehicle_shadow: Arc<VehicleShadow>,
+        subscription_manager: Arc<RwLock<SubscriptionManager>>,
+    ) -> Self {
+        let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(SUBSCRIPTION_FLUSH_INTERVAL);
+            loop {
+                tokio::select! {
+                    notification = receiver.recv() => {
+                        let mut subscription_manager = subscription_manager.write().await;
+                        match notification {
+                            Some(Notification::Written(signals)) => subscription_manager.notify(&signals),
+                            Some(Notification::Replaced { signals, removed }) => {
+                                subscription_manager.notify_replaced(&signals, &removed)
+                            }
+                            Some(Notification::Alerts(alerts)) => subscription_manager.notify_alerts(&alerts),
+                            None => break,
+                        }
+                    }
+                    _ = interval.tick() => {
+                        subscription_manager.write().await.flush_pending();
+                    }
+                }
+            }
+        });
+        Self { sender, vehicle_shadow }
+    }
+
+    /// Queues signals changed by one write, together with the derived
+    /// signals re-evaluated because of them, and then the alerts they raised
+    /// or cleared.
+    pub fn dispatch(&self, mut signals: Vec<crate::signal::Signal>) {
+        match self.vehicle_shadow.update_derived(&signals) {
+            Ok(derived) => signals.extend(derived),
+            Err(e) => error!("Failed to update derived signals: {}", e),
+        }
+        let alerts = match self.vehicle_shadow.update_alerts(&signals) {
+            Ok(alerts) => alerts,
+            Err(e) => {
+                error!("Failed to evaluate alert rules: {}", e);
+                Vec::new()
+            }
+        };
+        if !signals.is_empty() {
+            let _ = self.sender.send(Notification::Written(signals));
+        }
+        self.dispatch_alerts(alerts);
+    }
+
+    pub fn dispatch_alerts(&self, alerts: Vec<Alert>) {
+        if !alerts.is_empty() {
+            let _ = self.sender.send(Notification::Alerts(alerts));
+        }
+    }
+
+    /// Queues signals stored as is and signals removed from the tree, see
+    /// [`SubscriptionManager::notify_replaced`].
+    pub fn dispatch_replaced(&self, signals: Vec<crate::signal::Signal>, removed: Vec<crate::signal::Signal>) {
+        if !signals.is_empty() || !removed.is_empty() {
+            let _ = self.sender.send(Notification::Replaced { signals, removed });
+        }
     }
+}
+
+type ProviderSender = tokio::sync::mpsc::Sender<std::result::Result<ProviderResponse, Status>>;
+
+struct Provider {
+    paths: Vec<String>,
+    sender: ProviderSender,
+}
 
-    pub fn unsubscribe(&mut self, path: &str) {
-        self.subscriptions.remove(path);
+// プロバイダー管理用の構造体
+// プロバイダーはOpenProviderStreamの接続ごとにIDで管理し、
+// 1つのパスを提供できるのは1つのプロバイダーのみ
+// 登録時のパターンも保持し、後から追加されたシグナルは最初に登録されたパターンのプロバイダーが担当する
+#[derive(Default)]
+pub struct ProviderManager {
+    providers: HashMap<String, Provider>,
+    owners: HashMap<String, String>,
+    patterns: Vec<(String, String)>,
+}
+
+impl ProviderManager {
+    pub fn new() -> Self {
+        Self {
+            providers: HashMap::new(),
+            owners: HashMap::new(),
+            patterns: Vec::new(),
+        }
     }
 
-    pub fn notify(&self, path: &str, response: SubscribeResponse) {
-        if let Some(senders) = self.subscriptions.get(path) {
-            for sender in senders {
-                let _ = sender.try_send(response.clone());
+    // 同じストリームからの再登録はパスの追加として扱う
+    // pathsはpatternsを登録時点のシグナルに展開したもの
+    pub fn register(
+        &mut self,
+        id: &str,
+        patterns: &[String],
+        paths: &[String],
+        sender: ProviderSender,
+    ) -> Result<()> {
+        if let Some(path) = paths
+            .iter()
+            .find(|path| self.owners.get(*path).is_some_and(|owner| owner != id))
+        {
+            return Err(VehicleShadowError::InvalidInput(format!(
+                "Signal already has a provider: {}",
+                path
+            )));
+        }
+        let provider = self.providers.entry(id.to_string()).or_insert_with(|| Provider {
+            paths: Vec::new(),
+            sender,
+        });
+        for path in paths {
+            if self.owners.insert(path.clone(), id.to_string()).is_none() {
+                provider.paths.push(path.clone());
             }
         }
+        for pattern in patterns.iter().filter(|pattern| path_pattern::is_pattern(pattern)) {
+            self.patterns.push((pattern.clone(), id.to_string()));
+        }
+        Ok(())
+    }
+
+    // 登録していたパスを返す
+    pub fn unregister(&mut self, id: &str) -> Vec<String> {
+        let Some(provider) = self.providers.remove(id) else {
+            return Vec::new();
+        };
+        for path in &provider.paths {
+            self.owners.remove(path);
+        }
+        self.patterns.retain(|(_, owner)| owner != id);
+        provider.paths
+    }
+
+    fn owner(&self, path: &str) -> Option<&String> {
+        self.owners.get(path).or_else(|| {
+            self.patterns
+                .iter()
+                .find(|(pattern, _)| path_pattern::matches(pattern, path))
+                .map(|(_, id)| id)
+        })
+    }
+
+    pub fn provides(&self, id: &str, path: &str) -> bool {
+        self.owner(path).is_some_and(|owner| owner == id)
+    }
+
+    // 登録後に追加されたシグナルをパターンで担当するプロバイダーのものにする
+    // 切断時にはこのシグナルも利用不可にする
+    pub fn claim(&mut self, id: &str, path: &str) -> bool {
+        if !self.provides(id, path) {
+            return false;
+        }
+        if !self.owners.contains_key(path)
+            && let Some(provider) = self.providers.get_mut(id)
+        {
+            self.owners.insert(path.to_string(), id.to_string());
+            provider.paths.push(path.to_string());
+        }
+        true
+    }
+
+    // 目標値をパスのプロバイダーに転送する。プロバイダーがいなければOk(false)
+    // プロバイダーのキューが一杯ならresource_exhausted、切断済みならunavailable
+    pub fn route_actuation(&self, path: &str, target: &Value) -> std::result::Result<bool, Box<Status>> {
+        let Some(provider) = self.owner(path).and_then(|id| self.providers.get(id)) else {
+            return Ok(false);
+        };
+        let request = ProviderResponse {
+            message: Some(provider_response::Message::Actuation(ActuationRequest {
+                path: path.to_string(),
+                target: Some(convert_value_to_proto(target)),
+            })),
+        };
+        match provider.sender.try_send(Ok(request)) {
+            Ok(()) => Ok(true),
+            Err(tokio::sync::mpsc::error::TrySendError::Full(_)) => Err(Box::new(Status::resource_exhausted(
+                format!("Provider of {} is not keeping up with actuation requests", path),
+            ))),
+            Err(tokio::sync::mpsc::error::TrySendError::Closed(_)) => Err(Box::new(Status::unavailable(format!(
+                "Provider of {} has disconnected",
+                path
+            )))),
+        }
     }
 }
 
 // SignalServiceの実装
+#[derive(Clone)]
 pub struct SignalServiceImpl {
-    vehicle_shadow: Arc<RwLock<VehicleShadow>>,
+    vehicle_shadow: Arc<VehicleShadow>,
     subscription_manager: Arc<RwLock<SubscriptionManager>>,
+    provider_manager: Arc<RwLock<ProviderManager>>,
+    federation: Arc<Federation>,
+    dispatcher: NotificationDispatcher,
+    subscriber_queue_size: usize,
+    // 工場出荷状態に戻すときに読み直すVSS JSON (ミラーにはない)
+    vss: Option<String>,
 }
 
 impl SignalServiceImpl {
-    pub fn new(vehicle_shadow: VehicleShadow) -> Self {
+    pub fn new(
+        vehicle_shadow: VehicleShadow,
+        federation: Federation,
+        subscriber_queue_size: usize,
+        vss: Option<String>,
+    ) -> Self {
+        let vehicle_shadow = Arc::new(vehicle_shadow);
+        let subscription_manager = Arc::new(RwLock::new(SubscriptionManager::new()));
         Self {
-            vehicle_shadow: Arc::new(RwLock::new(vehicle_shadow)),
-            subscription_manager: Arc::new(RwLock::new(SubscriptionManager::new())),
+            dispatcher: NotificationDispatcher::spawn(vehicle_shadow.clone(), subscription_manager.clone()),
+            vehicle_shadow,
+            subscription_manager,
+            provider_manager: Arc::new(RwLock::new(ProviderManager::new())),
+            federation: Arc::new(federation),
+            subscriber_queue_size,
+            vss,
         }
     }
-}
 
-#[tonic::async_trait]
-impl SignalService for SignalServiceImpl {
-    async fn get(&self, request: Request<GetRequest>) -> std::result::Result<Response<GetResponse>, Status> {
-        let req = request.into_inner();
+    // 書き込みに成功したシグナルを購読者への通知に積む
+    fn write_signal(
+        &self,
+        write: impl FnOnce(&VehicleShadow) -> Result<crate::signal::Signal>,
+    ) -> Result<crate::signal::Signal> {
+        let signal = write(&self.vehicle_shadow)?;
+        self.dispatcher.dispatch(vec![signal.clone()]);
+        Ok(signal)
+    }
+
+    // 起動時と同じく、VSS JSONに記録済みのオーバーレイを重ねたシグナルを読み込む
+    fn load_signals(&self) -> Result<Vec<crate::signal::Signal>> {
+        let vss = self.vss.clone().ok_or(VehicleShadowError::ReadOnly)?;
+        let mut signals = vss_json_loader::load_vss_json(vss)?;
+        for e in self.vehicle_shadow.apply_recorded_overlays(&mut signals)? {
+            warn!("Skipping overlay that no longer applies to the VSS: {}", e);
+        }
+        Ok(signals)
+    }
+
+    // 目標値はシグナルに残るため、プロバイダーがいなければ後から接続したプロバイダーが読める
+    async fn route_actuation(&self, signal: &crate::signal::Signal) -> std::result::Result<(), Status> {
+        let Some(target) = signal.state.target.as_ref() else {
+            return Ok(());
+        };
+        let routed = self.provider_manager.read().await.route_actuation(&signal.path, target);
+        if !routed.map_err(|status| *status)? {
+            warn!("No provider to actuate {}", signal.path);
+        }
+        Ok(())
+    }
+
+    async fn get_local(&self, patterns: &[String], ids: &[u32]) -> GetResponse {
         let mut signals = Vec::new();
         let mut success = true;
         let mut error_message = String::new();
 
-        info!("Get request for paths: {:?}", req.paths);
+        let paths = match self.vehicle_shadow.resolve_paths(patterns) {
+            Ok(paths) => paths,
+            Err(e) => {
+                error!("Failed to resolve paths {:?}: {}", patterns, e);
+                return GetResponse {
+                    signals,
+                    success: false,
+                    error_message: format!("Failed to resolve paths: {}", e),
+                };
+            }
+        };
 
-        for path in req.paths {
-            match self.vehicle_shadow.read().await.get_signal(path.clone()) {
+        for path in paths {
+            match self.vehicle_shadow.get_signal(path.clone()) {
                 Ok(signal) => {
                     signals.push(convert_signal_to_proto(&signal));
                 }
@@ -311,73 +1173,115 @@ impl SignalService for SignalServiceImpl {
             }
         }
 
-        Ok(Response::new(GetResponse {
+        for id in ids {
+            match self.vehicle_shadow.path_of(*id).and_then(|path| self.vehicle_shadow.get_signal(path)) {
+                Ok(signal) => signals.push(convert_signal_to_compact_proto(&signal)),
+                Err(e) => {
+                    error!("Failed to get signal {}: {}", id, e);
+                    success = false;
+                    error_message = format!("Failed to get signal {}: {}", id, e);
+                    break;
+                }
+            }
+        }
+
+        GetResponse {
             signals,
             success,
             error_message,
-        }))
+        }
     }
 
-    async fn set(&self, request: Request<SetRequest>) -> std::result::Result<Response<SetResponse>, Status> {
-        let req = request.into_inner();
-        let mut results = Vec::new();
-        let mut success = true;
-        let mut error_message = String::new();
+    async fn set_local(&self, req: SetRequest) -> SetResponse {
+        let token = optional_token(req.token.clone());
 
+        // 目標値を含む更新はプロバイダーに転送する
+        let actuated: HashSet<String> = req
+            .signals
+            .iter()
+            .filter(|set_request| set_request.state.as_ref().is_some_and(|state| state.target.is_some()))
+            .map(|set_request| set_request.path.clone())
+            .collect();
 
-        info!("Set request for {} signals", req.signals.len());
+        // 保存されている状態にprotoのStateを適用する。同じシグナルへの同時の書き込みがあれば
+        // 新しい状態に適用し直されるので、他の書き込みを上書きで失うことはない
+        let update = |signal: &mut crate::signal::Signal, state: &Option<vehicle_shadow::State>| match state {
+            Some(proto_state) => apply_state_update(&mut signal.state, proto_state),
+            None => Ok(()),
+        };
 
-        for set_request in req.signals {
-            let result = {
-                let signal_result = self.vehicle_shadow.read().await.get_signal(set_request.path.clone());
-                
-                match signal_result {
-                    Ok(mut signal) => {
-                        // protoのStateをRustのStateに変換して設定
-                        if let Some(proto_state) = &set_request.state {
-                            apply_state_update(&mut signal.state, proto_state);
-                        }
+        // 型・範囲・許可値の検証とロックの確認はset_signal(s)で行われる
+        let written: Vec<(String, std::result::Result<crate::signal::Signal, String>)> = if req.atomic {
+            let paths: Vec<String> = req.signals.iter().map(|set_request| set_request.path.clone()).collect();
+            let result = self.vehicle_shadow.update_signals(&paths, &token, |index, signal| {
+                update(signal, &req.signals[index].state)
+            });
+            match result {
+                Ok(signals) => signals.into_iter().map(|signal| (signal.path.clone(), Ok(signal))).collect(),
+                // 失敗したパスには原因を、それ以外のパスには中断されたことを返す
+                Err(e) => {
+                    let (failed, cause) = match e {
+                        VehicleShadowError::TransactionAborted { path, cause } => (path, cause.to_string()),
+                        e => (String::new(), e.to_string()),
+                    };
+                    paths
+                        .into_iter()
+                        .map(|path| {
+                            let message = if failed.is_empty() || path == failed {
+                                cause.clone()
+                            } else {
+                                format!("Transaction aborted by {}", failed)
+                            };
+                            (path, Err(message))
+                        })
+                        .collect()
+                }
+            }
+        } else {
+            req.signals
+                .iter()
+                .map(|set_request| {
+                    let result = self.vehicle_shadow.update_signal(&set_request.path, &token, |signal| {
+                        update(signal, &set_request.state)
+                    });
+                    (set_request.path.clone(), result.map_err(|e| e.to_string()))
+                })
+                .collect()
+        };
+        // 1回のSetで変更されたシグナルは1回の通知にまとめる
+        self.dispatcher.dispatch(
+            written
+                .iter()
+                .filter_map(|(_, result)| result.as_ref().ok().cloned())
+                .collect(),
+        );
 
-                        let set_result = self.vehicle_shadow.write().await.set_signal(signal.clone(), &Some(req.token.clone()));
-                        match set_result {
-                            Ok(_) => {
-                                // 値が変更されたので、購読者に通知
-                                let response = SubscribeResponse {
-                                    signal: Some(convert_signal_to_proto(&signal)),
-                                    error_message: String::new(),
-                                };
-                                let subscription_manager = self.subscription_manager.clone();
-                                let path = set_request.path.clone();
-                                tokio::spawn(async move {
-                                    let subscription_manager = subscription_manager.read().await;
-                                    subscription_manager.notify(&path, response);
-                                });
-
-                                SetResult {
-                                    path: set_request.path,
-                                    success: true,
-                                    error_message: String::new(),
-                                }
-                            },
-                            Err(e) => {
-                                error!("Failed to set signal {}: {}", set_request.path, e);
-                                SetResult {
-                                    path: set_request.path,
-                                    success: false,
-                                    error_message: format!("Failed to set signal: {}", e),
-                                }
-                            }
-                        }
-                    }
-                    Err(e) => {
-                        error!("Signal not found: {}", set_request.path);
-                        SetResult {
-                            path: set_request.path,
-                            success: false,
-                            error_message: format!("Signal not found: {}", e),
+        let mut results = Vec::new();
+        let mut success = true;
+        let mut error_message = String::new();
+        for (set_request, (path, result)) in req.signals.iter().zip(written) {
+            let result = match result {
+                Ok(signal) => {
+                    let routed = if actuated.contains(&path) {
+                        self.route_actuation(&signal).await
+                    } else {
+                        Ok(())
+                    };
+                    match routed {
+                        Ok(()) => create_set_result(set_request, Ok(())),
+                        Err(status) => {
+                            error!("Failed to actuate {}: {}", path, status.message());
+                            create_set_result(
+                                set_request,
+                                Err(format!("Target set but not delivered: {}", status.message())),
+                            )
                         }
                     }
                 }
+                Err(e) => {
+                    error!("Failed to set signal {}: {}", path, e);
+                    create_set_result(set_request, Err(format!("Failed to set signal: {}", e)))
+                }
             };
 
             if !result.success {
@@ -388,47 +1292,372 @@ impl SignalService for SignalServiceImpl {
             results.push(result);
         }
 
-        Ok(Response::new(SetResponse {
+        SetResponse {
             results,
             success,
             error_message,
-        }))
+        }
     }
 
-    type SubscribeStream =
-        tokio_stream::wrappers::ReceiverStream<std::result::Result<SubscribeResponse, Status>>;
-
-    async fn subscribe(
+    async fn handle_provider_message(
         &self,
-        request: Request<SubscribeRequest>,
-    ) -> std::result::Result<Response<Self::SubscribeStream>, Status> {
-        let req = request.into_inner();
-        let (tx, rx) = tokio::sync::mpsc::channel(100);
-
-        info!("Subscribe request for paths: {:?}", req.paths);
+        provider_id: &str,
+        end_point: &str,
+        message: provider_request::Message,
+        tx: &ProviderSender,
+    ) {
+        let response = match message {
+            provider_request::Message::Registration(registration) => {
+                let result = self.register_provider(provider_id, end_point, &registration.paths, tx).await;
+                provider_response::Message::Registration(RegistrationResult {
+                    success: result.is_ok(),
+                    error_message: result.err().map(|e| e.to_string()).unwrap_or_default(),
+                })
+            }
+            provider_request::Message::Update(update) => {
+                let result = self.provide_value(provider_id, &update).await;
+                provider_response::Message::Update(UpdateResult {
+                    path: update.path,
+                    success: result.is_ok(),
+                    error_message: result.err().map(|e| e.to_string()).unwrap_or_default(),
+                })
+            }
+        };
+        let _ = tx
+            .send(Ok(ProviderResponse {
+                message: Some(response),
+            }))
+            .await;
+    }
 
-        // SubscriptionManagerに購読を登録
-        for path in req.paths.clone() {
-            let mut subscription_manager = self.subscription_manager.write().await;
-            let mut subscription_rx = subscription_manager.subscribe(path.clone());
-            
-            // 現在の値を取得して送信
-            if let Ok(signal) = self.vehicle_shadow.read().await.get_signal(path.clone()) {
-                let response = SubscribeResponse {
-                    signal: Some(convert_signal_to_proto(&signal)),
-                    error_message: String::new(),
-                };
-                let _ = tx.send(Ok(response)).await;
-            }
-            
-            // 購読ストリームからの通知を転送
-            let tx_clone = tx.clone();
-            tokio::spawn(async move {
-                while let Some(response) = subscription_rx.recv().await {
-                    let _ = tx_clone.send(Ok(response)).await;
+    async fn register_provider(
+        &self,
+        provider_id: &str,
+        end_point: &str,
+        paths: &[String],
+        tx: &ProviderSender,
+    ) -> Result<()> {
+        info!("Provider {} registers {:?}", end_point, paths);
+        let vehicle_shadow = &self.vehicle_shadow;
+        let patterns = paths;
+        let paths = vehicle_shadow.resolve_paths(patterns)?;
+        let mut provider_manager = self.provider_manager.write().await;
+        provider_manager.register(provider_id, patterns, &paths, tx.clone())?;
+        match vehicle_shadow.attach_provider(&paths, end_point) {
+            Ok(signals) => {
+                self.dispatcher.dispatch(signals);
+                Ok(())
+            }
+            Err(e) => {
+                // 登録をやり直せるようにこのストリームの登録を取り消す
+                let registered = provider_manager.unregister(provider_id);
+                if let Err(e) = vehicle_shadow.detach_provider(&registered) {
+                    error!("Failed to detach provider {}: {}", end_point, e);
                 }
-            });
+                Err(e)
+            }
+        }
+    }
+
+    async fn provide_value(&self, provider_id: &str, update: &ProviderUpdate) -> Result<()> {
+        if !self.provider_manager.write().await.claim(provider_id, &update.path) {
+            return Err(VehicleShadowError::InvalidInput(format!(
+                "Signal not registered by this provider: {}",
+                update.path
+            )));
         }
+        let value = match update.value.as_ref() {
+            Some(value) => convert_proto_value_to_rust(value)?,
+            None => return Err(VehicleShadowError::InvalidInput("value is missing".to_string())),
+        };
+        let source_timestamp = update.source_timestamp.as_ref().map(convert_proto_timestamp_to_rust);
+        self.write_signal(|vehicle_shadow| vehicle_shadow.provide_value(&update.path, value, source_timestamp))?;
+        Ok(())
+    }
+
+    // 切断したプロバイダーのシグナルを利用不可にする
+    async fn close_provider(&self, provider_id: &str, end_point: &str) {
+        let paths = self.provider_manager.write().await.unregister(provider_id);
+        if paths.is_empty() {
+            return;
+        }
+        info!("Provider {} disconnected, {} signals unavailable", end_point, paths.len());
+        match self.vehicle_shadow.detach_provider(&paths) {
+            Ok(signals) => self.dispatcher.dispatch(signals),
+            Err(e) => error!("Failed to detach provider {}: {}", end_point, e),
+        }
+    }
+}
+
+// 削除したシグナルはcapabilityとavailabilityをfalseにして最後に1度だけ通知する
+fn removal_notices(removed: Vec<crate::signal::Signal>) -> Vec<crate::signal::Signal> {
+    removed
+        .into_iter()
+        .map(|mut signal| {
+            signal.state.value = Value::NAN;
+            signal.state.capability = false;
+            signal.state.availability = false;
+            signal
+        })
+        .collect()
+}
+
+// IDで指定されたシグナルの結果は、パスの代わりに指定されたIDで返す
+fn create_set_result(set_request: &SetSignalRequest, result: std::result::Result<(), String>) -> SetResult {
+    let (path, id) = if set_request.id != 0 {
+        (String::new(), set_request.id)
+    } else {
+        (set_request.path.clone(), 0)
+    };
+    SetResult {
+        path,
+        id,
+        success: result.is_ok(),
+        error_message: result.err().unwrap_or_default(),
+    }
+}
+
+// 空のトークンはロックなしの書き込みとして扱う
+fn optional_token(token: String) -> Option<String> {
+    if token.is_empty() {
+        None
+    } else {
+        Some(token)
+    }
+}
+
+#[tonic::async_trait]
+impl SignalService for SignalServiceImpl {
+    async fn get(&self, request: Request<GetRequest>) -> std::result::Result<Response<GetResponse>, Status> {
+        let req = request.into_inner();
+
+        info!("Get request for paths: {:?}, ids: {:?}", req.paths, req.ids);
+
+        // 他のインスタンスが担当するパスは転送して結果をまとめる
+        // IDはインスタンスごとに振られるので、このインスタンスのシグナルだけを指す
+        let (local_paths, forwarded) = self
+            .federation
+            .routing_table()
+            .partition(&req.paths, String::as_str);
+        let mut response = self.get_local(&local_paths, &req.ids).await;
+        for (endpoint, paths) in forwarded {
+            match self.federation.get(&endpoint, paths).await {
+                Ok(forwarded) => {
+                    response.signals.extend(forwarded.signals);
+                    if !forwarded.success {
+                        response.success = false;
+                        response.error_message = forwarded.error_message;
+                    }
+                }
+                Err(e) => {
+                    error!("Failed to forward Get to {}: {}", endpoint, e);
+                    response.success = false;
+                    response.error_message = format!("Failed to forward to {}: {}", endpoint, e);
+                }
+            }
+        }
+
+        Ok(Response::new(response))
+    }
+
+    async fn set(&self, request: Request<SetRequest>) -> std::result::Result<Response<SetResponse>, Status> {
+        let mut req = request.into_inner();
+
+        info!("Set request for {} signals (atomic: {})", req.signals.len(), req.atomic);
+
+        // IDで指定されたシグナルはパスに置き換えて書き込み、結果はIDで返す。
+        // パスで指定されたシグナルのIDは使わない
+        for set_request in req.signals.iter_mut() {
+            if !set_request.path.is_empty() {
+                set_request.id = 0;
+                continue;
+            }
+            match self.vehicle_shadow.path_of(set_request.id) {
+                Ok(path) => set_request.path = path,
+                Err(e) => {
+                    return Ok(Response::new(SetResponse {
+                        results: Vec::new(),
+                        success: false,
+                        error_message: e.to_string(),
+                    }));
+                }
+            }
+        }
+
+        let (local_signals, forwarded) = self
+            .federation
+            .routing_table()
+            .partition(&req.signals, |signal| signal.path.as_str());
+
+        // トランザクションは1つのインスタンスの中でしか保証できない
+        let instances = forwarded.len() + usize::from(!local_signals.is_empty());
+        if req.atomic && instances > 1 {
+            let error_message = "Atomic set across multiple instances is not supported".to_string();
+            return Ok(Response::new(SetResponse {
+                results: req
+                    .signals
+                    .iter()
+                    .map(|set_request| create_set_result(set_request, Err(error_message.clone())))
+                    .collect(),
+                success: false,
+                error_message,
+            }));
+        }
+
+        let mut response = self
+            .set_local(SetRequest {
+                signals: local_signals,
+                token: req.token.clone(),
+                atomic: req.atomic,
+            })
+            .await;
+        for (endpoint, signals) in forwarded {
+            // IDはインスタンスごとに割り当てられるので、転送先にはパスで指定する
+            let request = SetRequest {
+                signals: signals
+                    .iter()
+                    .map(|set_request| SetSignalRequest {
+                        id: 0,
+                        ..set_request.clone()
+                    })
+                    .collect(),
+                token: req.token.clone(),
+                atomic: req.atomic,
+            };
+            match self.federation.set(&endpoint, request).await {
+                Ok(forwarded) => {
+                    // 転送先は要求の順に結果を返す
+                    response.results.extend(forwarded.results.into_iter().zip(&signals).map(|(result, set_request)| {
+                        let result = if result.success { Ok(()) } else { Err(result.error_message) };
+                        create_set_result(set_request, result)
+                    }));
+                    if !forwarded.success {
+                        response.success = false;
+                        response.error_message = forwarded.error_message;
+                    }
+                }
+                Err(e) => {
+                    error!("Failed to forward Set to {}: {}", endpoint, e);
+                    let error_message = format!("Failed to forward to {}: {}", endpoint, e);
+                    response.results.extend(
+                        signals
+                            .iter()
+                            .map(|set_request| create_set_result(set_request, Err(error_message.clone()))),
+                    );
+                    response.success = false;
+                    response.error_message = error_message;
+                }
+            }
+        }
+        Ok(Response::new(response))
+    }
+
+    type SubscribeStream =
+        tokio_stream::wrappers::ReceiverStream<std::result::Result<SubscribeResponse, Status>>;
+
+    async fn subscribe(
+        &self,
+        request: Request<SubscribeRequest>,
+    ) -> std::result::Result<Response<Self::SubscribeStream>, Status> {
+        let req = request.into_inner();
+        let (tx, rx) = tokio::sync::mpsc::channel(SUBSCRIBER_STREAM_BUFFER);
+
+        info!("Subscribe request for paths: {:?}, ids: {:?}", req.paths, req.ids);
+
+        let filter = match req.filter.as_ref().map(SubscriptionFilter::from_proto).transpose() {
+            Ok(filter) => filter.unwrap_or_default(),
+            Err(e) => return Err(Status::invalid_argument(e.to_string())),
+        };
+        let queue_size = match req.queue_size {
+            Some(0) => return Err(Status::invalid_argument("queue_size must be positive")),
+            Some(queue_size) => (queue_size as usize).min(MAX_SUBSCRIBER_QUEUE_SIZE),
+            None => self.subscriber_queue_size,
+        };
+        let queue = SubscriberQueue::new(queue_size, req.overflow_policy().into());
+
+        let (mut local_paths, forwarded) = self
+            .federation
+            .routing_table()
+            .partition(&req.paths, String::as_str);
+        for id in &req.ids {
+            match self.vehicle_shadow.path_of(*id) {
+                Ok(path) => local_paths.push(path),
+                Err(e) => return Err(Status::not_found(e.to_string())),
+            }
+        }
+        let compact = req.paths.is_empty() && !req.ids.is_empty();
+
+        // SubscriptionManagerに購読を登録し、最初のレスポンスで購読IDを返し、続けて現在の値を返す
+        // （パターンの場合は一致する全シグナル）。現在の値はキューの容量に関係なくすべて送る
+        // 登録から現在の値を読み終えるまでロックを保持し、その間の通知が現在の値より先に届かないようにする
+        // 読む前に書き込まれ、後から届いた通知はmark_notifiedにより捨てられる
+        let (subscription_id, cancelled) = {
+            let mut subscription_manager = self.subscription_manager.write().await;
+            let (subscription_id, cancelled) =
+                subscription_manager.subscribe(local_paths.clone(), filter, compact, queue.clone());
+            let mut initial = vec![SubscribeResponse {
+                signal: None,
+                signals: Vec::new(),
+                error_message: String::new(),
+                subscription_id: subscription_id.clone(),
+                dropped: 0,
+            }];
+            let current_paths = self.vehicle_shadow.resolve_paths(&local_paths).unwrap_or_default();
+            for path in current_paths {
+                if let Ok(signal) = self.vehicle_shadow.get_signal(path) {
+                    initial.push(create_subscribe_response(
+                        &subscription_id,
+                        std::slice::from_ref(&signal),
+                        compact,
+                    ));
+                    subscription_manager.mark_notified(&subscription_id, &signal);
+                }
+            }
+            queue.push_initial(initial);
+            (subscription_id, cancelled)
+        };
+
+        // 他のインスタンスが担当するパスは上流で購読して中継する
+        let mut forwarders = Vec::new();
+        for (endpoint, paths) in forwarded {
+            // フィルターは上流で評価する
+            match self.federation.subscribe(&endpoint, paths, req.filter.clone()).await {
+                Ok(stream) => forwarders.push(tokio::spawn(forward_subscription(
+                    stream,
+                    queue.clone(),
+                    subscription_id.clone(),
+                ))),
+                Err(e) => {
+                    error!("Failed to forward Subscribe to {}: {}", endpoint, e);
+                    queue.push(SubscribeResponse {
+                        signal: None,
+                        signals: Vec::new(),
+                        error_message: format!("Failed to forward to {}: {}", endpoint, e),
+                        subscription_id: subscription_id.clone(),
+                        dropped: 0,
+                    });
+                }
+            }
+        }
+
+        // ストリームを返してから送信する。以降の通知はキューを経由して配送タスクが送る
+        let delivery = tokio::spawn(queue.deliver(tx.clone()));
+
+        // クライアントがストリームを閉じたら（遅すぎて切断した場合も）購読を破棄する
+        let subscription_manager = self.subscription_manager.clone();
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = tx.closed() => {
+                    info!("Subscriber {} disconnected", subscription_id);
+                    subscription_manager.write().await.unsubscribe(&subscription_id);
+                }
+                _ = cancelled => {}
+            }
+            delivery.abort();
+            for forwarder in forwarders {
+                forwarder.abort();
+            }
+        });
 
         Ok(Response::new(tokio_stream::wrappers::ReceiverStream::new(
             rx,
@@ -440,48 +1669,714 @@ impl SignalService for SignalServiceImpl {
         request: Request<UnsubscribeRequest>,
     ) -> std::result::Result<Response<UnsubscribeResponse>, Status> {
         let req = request.into_inner();
-        let success = true;
-        let error_message = String::new();
 
-        info!("Unsubscribe request for paths: {:?}", req.paths);
+        info!("Unsubscribe request for subscription: {}", req.subscription_id);
 
-        for path in req.paths {
-            let mut subscription_manager = self.subscription_manager.write().await;
-            subscription_manager.unsubscribe(&path);
-        }
+        let removed = self
+            .subscription_manager
+            .write()
+            .await
+            .unsubscribe(&req.subscription_id);
+        let error_message = if removed {
+            String::new()
+        } else {
+            format!("Subscription not found: {}", req.subscription_id)
+        };
 
         Ok(Response::new(UnsubscribeResponse {
-            success,
+            success: removed,
             error_message,
         }))
     }
 
     async fn lock(&self, request: Request<LockRequest>) -> std::result::Result<Response<LockResponse>, Status> {
+        // holderが指定されていない場合は接続元アドレスを使う
+        let remote_addr = request.remote_addr().map(|addr| addr.to_string());
         let req = request.into_inner();
-        info!("Lock request for {:?}", req.paths.len());
-        let id = Uuid::new_v4();
-        let ret = self.vehicle_shadow.write().await.try_locks(req.paths, &id.to_string());
-        if let Ok(_) = ret {
-            Ok(Response::new(LockResponse {success: true, token: id.to_string()}))
+        let holder = if req.holder.is_empty() {
+            remote_addr.unwrap_or_default()
         } else {
-            Ok(Response::new(LockResponse {success: false, token: "".to_string()}))
+            req.holder
+        };
+        info!("Lock request for {:?} from {}", req.paths.len(), holder);
+
+        // ロックは1つのインスタンスの中でしか取得できない
+        let (local_paths, mut forwarded) = self
+            .federation
+            .routing_table()
+            .partition(&req.paths, String::as_str);
+        if !forwarded.is_empty() {
+            let endpoint = forwarded.keys().next().cloned().unwrap_or_default();
+            if !local_paths.is_empty() || forwarded.len() > 1 {
+                return Ok(Response::new(LockResponse {
+                    success: false,
+                    token: "".to_string(),
+                    expires_at_ns: 0,
+                    error_message: "Failed to lock: paths span multiple instances".to_string(),
+                    ttl_ms: 0,
+                }));
+            }
+            let request = LockRequest {
+                paths: forwarded.remove(&endpoint).unwrap_or_default(),
+                holder,
+                ttl_ms: req.ttl_ms,
+            };
+            return match self.federation.lock(&endpoint, request).await {
+                Ok(response) => Ok(Response::new(response)),
+                Err(e) => Ok(Response::new(LockResponse {
+                    success: false,
+                    token: "".to_string(),
+                    expires_at_ns: 0,
+                    error_message: format!("Failed to forward to {}: {}", endpoint, e),
+                    ttl_ms: 0,
+                })),
+            };
+        }
+
+        let id = Uuid::new_v4();
+        let ttl = req.ttl_ms.map(Duration::from_millis);
+        let ret = self.vehicle_shadow.try_locks(req.paths, &id.to_string(), &holder, ttl);
+        match ret {
+            Ok(lease) => Ok(Response::new(LockResponse {
+                success: true,
+                token: lease.token.clone(),
+                expires_at_ns: lease.expires_at_ns,
+                error_message: String::new(),
+                ttl_ms: lease.time_left(&crate::signal::Timestamp::now()).as_millis() as u64,
+            })),
+            Err(e) => Ok(Response::new(LockResponse {
+                success: false,
+                token: "".to_string(),
+                expires_at_ns: 0,
+                error_message: format!("Failed to lock: {}", e),
+                ttl_ms: 0,
+            })),
         }
     }
 
     async fn unlock(&self, request: Request<UnlockRequest>) -> std::result::Result<Response<UnlockResponse>, Status> {
         let req = request.into_inner();
-        let _ = self.vehicle_shadow.write().await.release_lock(&req.token);
-        Ok(Response::new(UnlockResponse {success: true }))
+        if let Some(endpoint) = self.federation.lock_owner(&req.token) {
+            let success = match self.federation.unlock(&endpoint, req.token).await {
+                Ok(response) => response.success,
+                Err(e) => {
+                    error!("Failed to forward Unlock to {}: {}", endpoint, e);
+                    false
+                }
+            };
+            return Ok(Response::new(UnlockResponse { success }));
+        }
+        let ret = self.vehicle_shadow.release_lock(&req.token);
+        Ok(Response::new(UnlockResponse {success: ret.is_ok() }))
+    }
+
+    async fn renew_lock(
+        &self,
+        request: Request<RenewLockRequest>,
+    ) -> std::result::Result<Response<RenewLockResponse>, Status> {
+        let req = request.into_inner();
+        if let Some(endpoint) = self.federation.lock_owner(&req.token) {
+            return match self.federation.renew_lock(&endpoint, req).await {
+                Ok(response) => Ok(Response::new(response)),
+                Err(e) => Ok(Response::new(RenewLockResponse {
+                    success: false,
+                    expires_at_ns: 0,
+                    error_message: format!("Failed to forward to {}: {}", endpoint, e),
+                    ttl_ms: 0,
+                })),
+            };
+        }
+        let ttl = req.ttl_ms.map(Duration::from_millis);
+        match self.vehicle_shadow.renew_lock(&req.token, ttl) {
+            Ok(lease) => Ok(Response::new(RenewLockResponse {
+                success: true,
+                expires_at_ns: lease.expires_at_ns,
+                error_message: String::new(),
+                ttl_ms: lease.time_left(&crate::signal::Timestamp::now()).as_millis() as u64,
+            })),
+            Err(e) => Ok(Response::new(RenewLockResponse {
+                success: false,
+                expires_at_ns: 0,
+                error_message: format!("Failed to renew lock: {}", e),
+                ttl_ms: 0,
+            })),
+        }
+    }
+
+    async fn list_locks(
+        &self,
+        _request: Request<ListLocksRequest>,
+    ) -> std::result::Result<Response<ListLocksResponse>, Status> {
+        match self.vehicle_shadow.list_locks() {
+            Ok(leases) => Ok(Response::new(ListLocksResponse {
+                locks: leases.iter().map(convert_lease_to_proto).collect(),
+                success: true,
+                error_message: String::new(),
+            })),
+            Err(e) => Ok(Response::new(ListLocksResponse {
+                locks: Vec::new(),
+                success: false,
+                error_message: format!("Failed to list locks: {}", e),
+            })),
+        }
+    }
+
+    async fn force_unlock(
+        &self,
+        request: Request<ForceUnlockRequest>,
+    ) -> std::result::Result<Response<ForceUnlockResponse>, Status> {
+        let req = request.into_inner();
+        warn!("ForceUnlock request for {:?}", req.paths);
+        match self.vehicle_shadow.force_unlock(&req.paths) {
+            Ok(leases) => Ok(Response::new(ForceUnlockResponse {
+                released: leases.iter().map(convert_lease_to_proto).collect(),
+                success: true,
+                error_message: String::new(),
+            })),
+            Err(e) => Ok(Response::new(ForceUnlockResponse {
+                released: Vec::new(),
+                success: false,
+                error_message: format!("Failed to force unlock: {}", e),
+            })),
+        }
+    }
+
+    async fn apply_overlay(
+        &self,
+        request: Request<ApplyOverlayRequest>,
+    ) -> std::result::Result<Response<ApplyOverlayResponse>, Status> {
+        let req = request.into_inner();
+        warn!("ApplyOverlay request ({} bytes)", req.overlay_json.len());
+
+        let changes = match self.vehicle_shadow.apply_overlay(&req.overlay_json) {
+            Ok(changes) => changes,
+            Err(e) => {
+                error!("Failed to apply overlay: {}", e);
+                return Ok(Response::new(ApplyOverlayResponse {
+                    success: false,
+                    error_message: format!("Failed to apply overlay: {}", e),
+                    ..Default::default()
+                }));
+            }
+        };
+        let paths = |signals: &[crate::signal::Signal]| -> Vec<String> {
+            signals.iter().map(|signal| signal.path.clone()).collect()
+        };
+        let response = ApplyOverlayResponse {
+            success: true,
+            error_message: String::new(),
+            added: paths(&changes.added),
+            updated: paths(&changes.updated),
+            removed: paths(&changes.removed),
+        };
+        info!(
+            "Applied overlay: {} added, {} updated, {} removed",
+            response.added.len(),
+            response.updated.len(),
+            response.removed.len()
+        );
+
+        // 設定だけが変わったシグナルは採番が進まないため、古い通知として捨てられないようにする
+        self.dispatcher
+            .dispatch_replaced(changes.added.into_iter().chain(changes.updated).collect(), changes.removed);
+        // 追加・変更された派生シグナルを現在の入力で計算し直す
+        match self.vehicle_shadow.refresh_derived() {
+            Ok(signals) => self.dispatcher.dispatch(signals),
+            Err(e) => error!("Failed to refresh derived signals: {}", e),
+        }
+        Ok(Response::new(response))
+    }
+
+    async fn reset_signals(
+        &self,
+        request: Request<ResetSignalsRequest>,
+    ) -> std::result::Result<Response<ResetSignalsResponse>, Status> {
+        let req = request.into_inner();
+        info!("ResetSignals request for {:?}", req.paths);
+
+        match self.vehicle_shadow.reset_signals(&req.paths, &optional_token(req.token)) {
+            Ok(signals) => {
+                let paths = signals.iter().map(|signal| signal.path.clone()).collect();
+                self.dispatcher.dispatch(signals);
+                Ok(Response::new(ResetSignalsResponse {
+                    paths,
+                    success: true,
+                    error_message: String::new(),
+                }))
+            }
+            Err(e) => {
+                error!("Failed to reset signals: {}", e);
+                Ok(Response::new(ResetSignalsResponse {
+                    paths: Vec::new(),
+                    success: false,
+                    error_message: format!("Failed to reset signals: {}", e),
+                }))
+            }
+        }
+    }
+
+    async fn factory_reset(
+        &self,
+        _request: Request<FactoryResetRequest>,
+    ) -> std::result::Result<Response<FactoryResetResponse>, Status> {
+        warn!("FactoryReset request");
+
+        let result = self.load_signals().and_then(|signals| self.vehicle_shadow.factory_reset(signals));
+        let changes = match result {
+            Ok(changes) => changes,
+            Err(e) => {
+                error!("Failed to reset to factory state: {}", e);
+                return Ok(Response::new(FactoryResetResponse {
+                    success: false,
+                    error_message: format!("Failed to reset to factory state: {}", e),
+                    reset: 0,
+                }));
+            }
+        };
+        let reset = (changes.added.len() + changes.updated.len()) as u32;
+        info!("Reset {} signals to factory state", reset);
+
+        // 戻した状態は採番を進めて書き込んだので、派生シグナルとアラートも合わせて評価し直される
+        self.dispatcher.dispatch_replaced(Vec::new(), changes.removed);
+        self.dispatcher.dispatch(changes.added.into_iter().chain(changes.updated).collect());
+        match self.vehicle_shadow.refresh_derived() {
+            Ok(signals) => self.dispatcher.dispatch(signals),
+            Err(e) => error!("Failed to refresh derived signals: {}", e),
+        }
+        Ok(Response::new(FactoryResetResponse {
+            success: true,
+            error_message: String::new(),
+            reset,
+        }))
+    }
+
+    async fn export(&self, request: Request<ExportRequest>) -> std::result::Result<Response<ExportResponse>, Status> {
+        let req = request.into_inner();
+        let encoding = match vehicle_shadow::SnapshotEncoding::try_from(req.encoding) {
+            Ok(vehicle_shadow::SnapshotEncoding::SnapshotJson) => Encoding::Json,
+            Ok(vehicle_shadow::SnapshotEncoding::SnapshotBinary) => Encoding::Binary,
+            Err(_) => {
+                return Ok(Response::new(ExportResponse {
+                    snapshot: Vec::new(),
+                    success: false,
+                    error_message: format!("Unknown snapshot encoding: {}", req.encoding),
+                }));
+            }
+        };
+        info!("Export request ({:?})", encoding);
+
+        match self.vehicle_shadow.export_snapshot().and_then(|snapshot| snapshot.encode(encoding)) {
+            Ok(snapshot) => Ok(Response::new(ExportResponse {
+                snapshot,
+                success: true,
+                error_message: String::new(),
+            })),
+            Err(e) => {
+                error!("Failed to export snapshot: {}", e);
+                Ok(Response::new(ExportResponse {
+                    snapshot: Vec::new(),
+                    success: false,
+                    error_message: format!("Failed to export snapshot: {}", e),
+                }))
+            }
+        }
+    }
+
+    async fn import(&self, request: Request<ImportRequest>) -> std::result::Result<Response<ImportResponse>, Status> {
+        let req = request.into_inner();
+        warn!("Import request ({} bytes)", req.snapshot.len());
+
+        let result = Snapshot::decode(&req.snapshot).and_then(|snapshot| self.vehicle_shadow.import_snapshot(snapshot));
+        let changes = match result {
+            Ok(changes) => changes,
+            Err(e) => {
+                error!("Failed to import snapshot: {}", e);
+                return Ok(Response::new(ImportResponse {
+                    success: false,
+                    error_message: format!("Failed to import snapshot: {}", e),
+                    ..Default::default()
+                }));
+            }
+        };
+        let paths = |signals: &[crate::signal::Signal]| -> Vec<String> {
+            signals.iter().map(|signal| signal.path.clone()).collect()
+        };
+        let response = ImportResponse {
+            success: true,
+            error_message: String::new(),
+            added: paths(&changes.added),
+            updated: paths(&changes.updated),
+            removed: paths(&changes.removed),
+        };
+        info!(
+            "Imported snapshot: {} added, {} updated, {} removed",
+            response.added.len(),
+            response.updated.len(),
+            response.removed.len()
+        );
+
+        // 取り込んだ状態は新しい採番で書き込まれているので、派生シグナルとアラートも評価し直される
+        self.dispatcher.dispatch_replaced(Vec::new(), changes.removed);
+        self.dispatcher.dispatch(changes.added.into_iter().chain(changes.updated).collect());
+        match self.vehicle_shadow.refresh_derived() {
+            Ok(signals) => self.dispatcher.dispatch(signals),
+            Err(e) => error!("Failed to refresh derived signals: {}", e),
+        }
+        Ok(Response::new(response))
+    }
+
+    async fn put_alert_rule(
+        &self,
+        request: Request<PutAlertRuleRequest>,
+    ) -> std::result::Result<Response<PutAlertRuleResponse>, Status> {
+        let req = request.into_inner();
+        let result = req
+            .rule
+            .as_ref()
+            .ok_or_else(|| VehicleShadowError::InvalidInput("rule is missing".to_string()))
+            .and_then(convert_proto_alert_rule_to_rust)
+            .and_then(|rule| {
+                info!("PutAlertRule request for {}", rule.id);
+                self.vehicle_shadow.put_alert_rule(rule)
+            });
+        match result {
+            Ok(alert) => {
+                self.dispatcher.dispatch_alerts(alert.into_iter().collect());
+                Ok(Response::new(PutAlertRuleResponse {
+                    success: true,
+                    error_message: String::new(),
+                }))
+            }
+            Err(e) => {
+                error!("Failed to put alert rule: {}", e);
+                Ok(Response::new(PutAlertRuleResponse {
+                    success: false,
+                    error_message: format!("Failed to put alert rule: {}", e),
+                }))
+            }
+        }
+    }
+
+    async fn remove_alert_rule(
+        &self,
+        request: Request<RemoveAlertRuleRequest>,
+    ) -> std::result::Result<Response<RemoveAlertRuleResponse>, Status> {
+        let req = request.into_inner();
+        info!("RemoveAlertRule request for {}", req.id);
+        match self.vehicle_shadow.remove_alert_rule(&req.id) {
+            Ok(alert) => {
+                self.dispatcher.dispatch_alerts(alert.into_iter().collect());
+                Ok(Response::new(RemoveAlertRuleResponse {
+                    success: true,
+                    error_message: String::new(),
+                }))
+            }
+            Err(e) => Ok(Response::new(RemoveAlertRuleResponse {
+                success: false,
+                error_message: format!("Failed to remove alert rule: {}", e),
+            })),
+        }
+    }
+
+    async fn list_alert_rules(
+        &self,
+        _request: Request<ListAlertRulesRequest>,
+    ) -> std::result::Result<Response<ListAlertRulesResponse>, Status> {
+        match self.vehicle_shadow.alert_rules() {
+            Ok(rules) => Ok(Response::new(ListAlertRulesResponse {
+                rules: rules.iter().map(convert_alert_rule_to_proto).collect(),
+                success: true,
+                error_message: String::new(),
+            })),
+            Err(e) => Ok(Response::new(ListAlertRulesResponse {
+                rules: Vec::new(),
+                success: false,
+                error_message: format!("Failed to list alert rules: {}", e),
+            })),
+        }
+    }
+
+    async fn list_alerts(
+        &self,
+        request: Request<ListAlertsRequest>,
+    ) -> std::result::Result<Response<ListAlertsResponse>, Status> {
+        let limit = match request.into_inner().limit as usize {
+            0 => DEFAULT_ALERT_LOG_LIMIT,
+            limit => limit,
+        };
+        let alerts = self
+            .vehicle_shadow
+            .active_alerts()
+            .and_then(|active| Ok((active, self.vehicle_shadow.alert_log(limit)?)));
+        match alerts {
+            Ok((active, history)) => Ok(Response::new(ListAlertsResponse {
+                active: active.iter().map(convert_alert_to_proto).collect(),
+                history: history.iter().map(convert_alert_to_proto).collect(),
+                success: true,
+                error_message: String::new(),
+            })),
+            Err(e) => Ok(Response::new(ListAlertsResponse {
+                active: Vec::new(),
+                history: Vec::new(),
+                success: false,
+                error_message: format!("Failed to list alerts: {}", e),
+            })),
+        }
+    }
+
+    type SubscribeAlertsStream =
+        tokio_stream::wrappers::ReceiverStream<std::result::Result<vehicle_shadow::Alert, Status>>;
+
+    async fn subscribe_alerts(
+        &self,
+        _request: Request<SubscribeAlertsRequest>,
+    ) -> std::result::Result<Response<Self::SubscribeAlertsStream>, Status> {
+        let (tx, rx) = tokio::sync::mpsc::channel(ALERT_STREAM_BUFFER);
+        info!("SubscribeAlerts request");
+
+        // 通知を止めた状態で有効なアラートを読み、その後の変化だけがストリームに続くようにする
+        let mut subscription_manager = self.subscription_manager.write().await;
+        let active = self
+            .vehicle_shadow
+            .active_alerts()
+            .map_err(|e| Status::internal(format!("Failed to read active alerts: {}", e)))?;
+        subscription_manager.subscribe_alerts(tx, &active);
+
+        Ok(Response::new(tokio_stream::wrappers::ReceiverStream::new(rx)))
+    }
+
+    async fn set_target(
+        &self,
+        request: Request<SetTargetRequest>,
+    ) -> std::result::Result<Response<SetTargetResponse>, Status> {
+        let req = request.into_inner();
+        info!("SetTarget request for {}", req.path);
+
+        let result = match req.target.as_ref().map(convert_proto_value_to_rust) {
+            Some(Ok(target)) => self.write_signal(|vehicle_shadow| {
+                vehicle_shadow.set_target(&req.path, target, &optional_token(req.token))
+            }),
+            Some(Err(e)) => Err(e),
+            None => Err(VehicleShadowError::InvalidInput("target is missing".to_string())),
+        };
+        match result {
+            Ok(signal) => {
+                self.route_actuation(&signal).await?;
+                Ok(Response::new(SetTargetResponse {
+                    success: true,
+                    error_message: String::new(),
+                }))
+            }
+            Err(e) => {
+                error!("Failed to set target of {}: {}", req.path, e);
+                Ok(Response::new(SetTargetResponse {
+                    success: false,
+                    error_message: format!("Failed to set target: {}", e),
+                }))
+            }
+        }
+    }
+
+    async fn set_current(
+        &self,
+        request: Request<SetCurrentRequest>,
+    ) -> std::result::Result<Response<SetCurrentResponse>, Status> {
+        let req = request.into_inner();
+        info!("SetCurrent request for {}", req.path);
+
+        let source_timestamp = req.source_timestamp.as_ref().map(convert_proto_timestamp_to_rust);
+        let result = match req.value.as_ref().map(convert_proto_value_to_rust) {
+            Some(Ok(value)) => self.write_signal(|vehicle_shadow| {
+                vehicle_shadow.set_current(&req.path, value, source_timestamp, &optional_token(req.token))
+            }),
+            Some(Err(e)) => Err(e),
+            None => Err(VehicleShadowError::InvalidInput("value is missing".to_string())),
+        };
+        match result {
+            Ok(_) => Ok(Response::new(SetCurrentResponse {
+                success: true,
+                error_message: String::new(),
+            })),
+            Err(e) => {
+                error!("Failed to set current value of {}: {}", req.path, e);
+                Ok(Response::new(SetCurrentResponse {
+                    success: false,
+                    error_message: format!("Failed to set current value: {}", e),
+                }))
+            }
+        }
+    }
+
+    type OpenProviderStreamStream =
+        tokio_stream::wrappers::ReceiverStream<std::result::Result<ProviderResponse, Status>>;
+
+    async fn open_provider_stream(
+        &self,
+        request: Request<tonic::Streaming<ProviderRequest>>,
+    ) -> std::result::Result<Response<Self::OpenProviderStreamStream>, Status> {
+        let remote_addr = request.remote_addr().map(|addr| addr.to_string());
+        let mut inbound = request.into_inner();
+        let (tx, rx) = tokio::sync::mpsc::channel(100);
+        let provider_id = Uuid::new_v4().to_string();
+
+        info!("Provider stream {} opened from {:?}", provider_id, remote_addr);
+
+        let service = self.clone();
+        tokio::spawn(async move {
+            // end_pointは登録時に名前が指定されなければ接続元アドレスを使う
+            let mut end_point = remote_addr.unwrap_or_default();
+            loop {
+                match inbound.message().await {
+                    Ok(Some(request)) => {
+                        let Some(message) = request.message else {
+                            continue;
+                        };
+                        if let provider_request::Message::Registration(ref registration) = message
+                            && !registration.name.is_empty()
+                        {
+                            end_point = registration.name.clone();
+                        }
+                        service.handle_provider_message(&provider_id, &end_point, message, &tx).await;
+                    }
+                    Ok(None) => break,
+                    Err(e) => {
+                        warn!("Provider stream {} failed: {}", provider_id, e);
+                        break;
+                    }
+                }
+            }
+            service.close_provider(&provider_id, &end_point).await;
+        });
+
+        Ok(Response::new(tokio_stream::wrappers::ReceiverStream::new(rx)))
+    }
+
+    async fn get_history(
+        &self,
+        request: Request<GetHistoryRequest>,
+    ) -> std::result::Result<Response<GetHistoryResponse>, Status> {
+        let req = request.into_inner();
+
+        info!(
+            "GetHistory request for {} (from: {:?}, to: {:?}, limit: {:?})",
+            req.path, req.from, req.to, req.limit
+        );
+
+        let history = self.vehicle_shadow.get_history(
+            &req.path,
+            req.from,
+            req.to,
+            req.limit.map(|limit| limit as usize),
+        );
+        match history {
+            Ok(states) => Ok(Response::new(GetHistoryResponse {
+                states: states.iter().map(convert_state_to_proto).collect(),
+                success: true,
+                error_message: String::new(),
+            })),
+            Err(e) => {
+                error!("Failed to get history of {}: {}", req.path, e);
+                Ok(Response::new(GetHistoryResponse {
+                    states: Vec::new(),
+                    success: false,
+                    error_message: format!("Failed to get history of {}: {}", req.path, e),
+                }))
+            }
+        }
+    }
+
+    async fn get_metadata(
+        &self,
+        request: Request<GetMetadataRequest>,
+    ) -> std::result::Result<Response<GetMetadataResponse>, Status> {
+        let req = request.into_inner();
+
+        info!("GetMetadata request for paths: {:?}", req.paths);
+
+        // パスを省略した場合は全シグナル。IDはこのインスタンスのシグナルにだけ振られている
+        let patterns = if req.paths.is_empty() {
+            vec!["**".to_string()]
+        } else {
+            req.paths
+        };
+        let metadata = self.vehicle_shadow.resolve_paths(&patterns).and_then(|paths| {
+            paths
+                .into_iter()
+                .map(|path| Ok(convert_signal_to_metadata(&self.vehicle_shadow.get_signal(path)?)))
+                .collect::<Result<Vec<_>>>()
+        });
+        match metadata {
+            Ok(signals) => Ok(Response::new(GetMetadataResponse {
+                signals,
+                success: true,
+                error_message: String::new(),
+            })),
+            Err(e) => {
+                error!("Failed to get metadata of {:?}: {}", patterns, e);
+                Ok(Response::new(GetMetadataResponse {
+                    signals: Vec::new(),
+                    success: false,
+                    error_message: format!("Failed to get metadata: {}", e),
+                }))
+            }
+        }
+    }
+
+    async fn list_signals(
+        &self,
+        request: Request<ListSignalsRequest>,
+    ) -> std::result::Result<Response<ListSignalsResponse>, Status> {
+        let req = request.into_inner();
+
+        info!("ListSignals request for prefix: {:?} (recursive: {})", req.prefix, req.recursive);
+
+        let page_size = match req.page_size as usize {
+            0 => DEFAULT_LIST_PAGE_SIZE,
+            size => size.min(MAX_LIST_PAGE_SIZE),
+        };
+        match self.vehicle_shadow.list_tree(&req.prefix, req.recursive) {
+            Ok(nodes) => {
+                let (page, next_page_token) = page_nodes(nodes, &req.page_token, page_size);
+                Ok(Response::new(ListSignalsResponse {
+                    nodes: page.iter().map(convert_node_to_proto).collect(),
+                    next_page_token,
+                    success: true,
+                    error_message: String::new(),
+                }))
+            }
+            Err(e) => {
+                error!("Failed to list signals under {:?}: {}", req.prefix, e);
+                Ok(Response::new(ListSignalsResponse {
+                    nodes: Vec::new(),
+                    next_page_token: String::new(),
+                    success: false,
+                    error_message: format!("Failed to list signals: {}", e),
+                }))
+            }
+        }
     }
 }
 
 // サーバーを起動する関数
 pub async fn run_server(
     vehicle_shadow: VehicleShadow,
-    addr: &str,
+    config: &Config,
 ) -> std::result::Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let addr = addr.parse()?;
-    let service = SignalServiceImpl::new(vehicle_shadow);
+    let addr = config.server_addr.parse()?;
+    let routes = config.routes()?;
+    for route in &routes {
+        info!("Forwarding {} to {}", route.prefix, route.endpoint);
+    }
+    let federation = Federation::new(routes)?;
+    let service = SignalServiceImpl::new(vehicle_shadow, federation, config.subscriber_queue_size, config.vss.clone());
+    spawn_lock_reaper(service.vehicle_shadow.clone());
+    // ミラーのavailabilityは上流の判定をそのまま複製する
+    if !config.is_mirror() {
+        spawn_staleness_monitor(service.vehicle_shadow.clone(), service.dispatcher.clone(), config.stale_periods);
+    }
+    for endpoint in config.mirrors.clone() {
+        tokio::spawn(Mirror::new(endpoint).run(service.vehicle_shadow.clone(), service.dispatcher.clone()));
+    }
 
     info!("Starting gRPC server on {}", addr);
 
@@ -493,10 +2388,78 @@ pub async fn run_server(
     Ok(())
 }
 
+// 上流インスタンスの購読を自分の購読IDで中継する
+async fn forward_subscription(
+    mut stream: tonic::Streaming<SubscribeResponse>,
+    queue: SubscriberQueue,
+    subscription_id: String,
+) {
+    loop {
+        match stream.message().await {
+            Ok(Some(mut response)) => {
+                // 上流の購読IDを知らせるだけの応答は中継しない
+                if response.signal.is_none() && response.signals.is_empty() && response.error_message.is_empty() {
+                    continue;
+                }
+                response.subscription_id = subscription_id.clone();
+                queue.push(response);
+            }
+            Ok(None) => break,
+            Err(e) => {
+                queue.push(SubscribeResponse {
+                    signal: None,
+                    signals: Vec::new(),
+                    error_message: format!("Upstream subscription ended: {}", e),
+                    subscription_id: subscription_id.clone(),
+                    dropped: 0,
+                });
+                break;
+            }
+        }
+    }
+}
+
+// 期限切れのロックを定期的に解放するタスク
+fn spawn_lock_reaper(vehicle_shadow: Arc<VehicleShadow>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(LOCK_REAPER_INTERVAL);
+        loop {
+            interval.tick().await;
+            match vehicle_shadow.release_expired_locks() {
+                Ok(leases) => {
+                    for lease in leases {
+                        warn!("Lock {} held by {} expired: {:?}", lease.token, lease.holder, lease.paths);
+                    }
+                }
+                Err(e) => error!("Failed to release expired locks: {}", e),
+            }
+        }
+    });
+}
+
+// 更新周期を過ぎても書き込まれないシグナルを利用不可にして購読者へ通知するタスク
+fn spawn_staleness_monitor(vehicle_shadow: Arc<VehicleShadow>, dispatcher: NotificationDispatcher, periods: u32) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(STALENESS_CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+            match vehicle_shadow.mark_stale(periods) {
+                Ok(signals) => {
+                    for signal in &signals {
+                        warn!("Signal not updated within {} periods: {}", periods, signal.path);
+                    }
+                    dispatcher.dispatch(signals);
+                }
+                Err(e) => error!("Failed to check signal staleness: {}", e),
+            }
+        }
+    });
+}
+
 // 部分的な更新を適用する関数
-fn apply_state_update(current_state: &mut crate::signal::State, proto_state: &vehicle_shadow::State) {
+fn apply_state_update(current_state: &mut crate::signal::State, proto_state: &vehicle_shadow::State) -> Result<()> {
     if let Some(ref proto_value) = proto_state.value {
-        current_state.value = convert_proto_value_to_rust(proto_value);
+        current_state.value = convert_proto_value_to_rust(proto_value)?;
     }
     if let Some(capability) = proto_state.capability {
         current_state.capability = capability;
@@ -507,4 +2470,352 @@ fn apply_state_update(current_state: &mut crate::signal::State, proto_state: &ve
     if let Some(ref reserved) = proto_state.reserved {
         current_state.reserved = reserved.clone();
     }
+    // sequenceとsink_timestampはシャドウ側で採番するためクライアントの値は無視する。
+    // 値だけを書き換えた場合、前の値の計測時刻を新しい値に引き継がない
+    if let Some(ref source_timestamp) = proto_state.source_timestamp {
+        current_state.source_timestamp = Some(convert_proto_timestamp_to_rust(source_timestamp));
+    } else if proto_state.value.is_some() {
+        current_state.source_timestamp = None;
+    }
+    if let Some(ref target) = proto_state.target {
+        current_state.target = Some(convert_proto_value_to_rust(target)?);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rpc::subscriber_queue::OverflowPolicy;
+
+    fn create_test_signal(path: &str, value: Value) -> crate::signal::Signal {
+        crate::signal::Signal {
+            path: path.to_string(),
+            id: 0,
+            state: crate::signal::State {
+                value,
+                capability: true,
+                availability: true,
+                ..Default::default()
+            },
+            config: crate::signal::Config {
+                leaf_type: LeafType::Sensor,
+                data_type: ValueType::TypeFloat,
+                ..Default::default()
+            },
+        }
+    }
+
+    #[test]
+    fn test_unsubscribe_keeps_other_subscribers() {
+        let mut subscription_manager = SubscriptionManager::new();
+        let queue1 = SubscriberQueue::new(10, OverflowPolicy::Coalesce);
+        let queue2 = SubscriberQueue::new(10, OverflowPolicy::Coalesce);
+        let (id1, _cancelled1) = subscription_manager.subscribe(
+            vec!["Vehicle.Speed".to_string()],
+            SubscriptionFilter::default(),
+            false,
+            queue1.clone(),
+        );
+        let (id2, _cancelled2) = subscription_manager.subscribe(
+            vec!["Vehicle.**".to_string()],
+            SubscriptionFilter::default(),
+            false,
+            queue2.clone(),
+        );
+
+        assert!(subscription_manager.unsubscribe(&id1));
+        assert!(!subscription_manager.unsubscribe(&id1));
+        subscription_manager.notify(&[create_test_signal("Vehicle.Speed", Value::Float(0.0))]);
+
+        assert!(queue1.pop().is_none());
+        let response = queue2.pop().unwrap().unwrap();
+        assert_eq!(response.subscription_id, id2);
+
+        // 誰も購読していないパスの通し番号は残さない
+        assert!(subscription_manager.unsubscribe(&id2));
+        assert!(subscription_manager.sequences.is_empty());
+    }
+
+    #[test]
+    fn test_new_value_does_not_keep_old_source_timestamp() {
+        let mut state = crate::signal::State {
+            value: Value::Float(1.0),
+            capability: true,
+            availability: true,
+            ..Default::default()
+        };
+        let stamped = vehicle_shadow::State {
+            value: Some(convert_value_to_proto(&Value::Float(2.0))),
+            source_timestamp: Some(vehicle_shadow::Timestamp {
+                wall_clock_ns: 42,
+                monotonic_ns: 0,
+            }),
+            ..Default::default()
+        };
+        apply_state_update(&mut state, &stamped).unwrap();
+        assert_eq!(state.source_timestamp.as_ref().map(|t| t.wall_clock_ns), Some(42));
+
+        // an update without a value keeps the source time of the current value
+        let availability_only = vehicle_shadow::State {
+            availability: Some(false),
+            ..Default::default()
+        };
+        apply_state_update(&mut state, &availability_only).unwrap();
+        assert!(state.source_timestamp.is_some());
+
+        let unstamped = vehicle_shadow::State {
+            value: Some(convert_value_to_proto(&Value::Float(3.0))),
+            ..Default::default()
+        };
+        apply_state_update(&mut state, &unstamped).unwrap();
+        assert_eq!(state.source_timestamp, None);
+    }
+
+    #[test]
+    fn test_throttled_subscription_sends_latest_value() {
+        let mut subscription_manager = SubscriptionManager::new();
+        let queue = SubscriberQueue::new(10, OverflowPolicy::Coalesce);
+        let filter = SubscriptionFilter {
+            min_interval: Some(Duration::from_millis(20)),
+            ..Default::default()
+        };
+        subscription_manager.subscribe(vec!["Vehicle.Speed".to_string()], filter, false, queue.clone());
+
+        for (sequence, speed) in [(1, 1.0), (2, 2.0), (3, 3.0)] {
+            let mut signal = create_test_signal("Vehicle.Speed", Value::Float(speed));
+            signal.state.sequence = sequence;
+            subscription_manager.notify(&[signal]);
+        }
+        assert!(queue.pop().is_some());
+        assert!(queue.pop().is_none());
+
+        std::thread::sleep(Duration::from_millis(20));
+        subscription_manager.flush_pending();
+        let response = queue.pop().unwrap().unwrap();
+        let value = response.signal.unwrap().state.unwrap().value.unwrap();
+        assert_eq!(convert_proto_value_to_rust(&value).unwrap(), Value::Float(3.0));
+        assert!(queue.pop().is_none());
+    }
+
+    #[test]
+    fn test_stale_notification_is_dropped() {
+        let mut subscription_manager = SubscriptionManager::new();
+        let queue = SubscriberQueue::new(10, OverflowPolicy::DropOldest);
+        subscription_manager.subscribe(vec!["Vehicle.Speed".to_string()], SubscriptionFilter::default(), false, queue.clone());
+
+        let mut newer = create_test_signal("Vehicle.Speed", Value::Float(2.0));
+        newer.state.sequence = 2;
+        let mut older = create_test_signal("Vehicle.Speed", Value::Float(1.0));
+        older.state.sequence = 1;
+        subscription_manager.notify(std::slice::from_ref(&newer));
+        subscription_manager.notify(std::slice::from_ref(&older));
+        // 捨てた古い状態は取りこぼしとして数える
+        let response = queue.pop().unwrap().unwrap();
+        assert_eq!(response.dropped, 1);
+        assert!(queue.pop().is_none());
+
+        // 番号が振り直された状態は捨てない
+        subscription_manager.notify_replaced(std::slice::from_ref(&older), &[]);
+        let response = queue.pop().unwrap().unwrap();
+        assert_eq!(response.signal.unwrap().state.unwrap().sequence, Some(1));
+
+        // 削除したシグナルは1度だけ通知して忘れる
+        subscription_manager.notify_replaced(&[], &[older]);
+        let response = queue.pop().unwrap().unwrap();
+        assert!(!response.signal.unwrap().state.unwrap().capability.unwrap());
+        assert!(subscription_manager.sequences.is_empty());
+    }
+
+    #[test]
+    fn test_write_before_initial_value_is_not_sent_again() {
+        let mut subscription_manager = SubscriptionManager::new();
+        let queue = SubscriberQueue::new(10, OverflowPolicy::DropOldest);
+        let (id, _cancelled) =
+            subscription_manager.subscribe(vec!["Vehicle.Speed".to_string()], SubscriptionFilter::default(), false, queue.clone());
+
+        // 登録後、現在の値を読む前に書き込まれた状態は、現在の値として送られる
+        let mut written = create_test_signal("Vehicle.Speed", Value::Float(1.0));
+        written.state.sequence = 1;
+        queue.push_initial(vec![create_subscribe_response(&id, std::slice::from_ref(&written), false)]);
+        subscription_manager.mark_notified(&id, &written);
+
+        // その書き込みの通知が後から届いても送らず、より新しい状態だけを送る
+        let mut newer = create_test_signal("Vehicle.Speed", Value::Float(2.0));
+        newer.state.sequence = 2;
+        subscription_manager.notify(&[written]);
+        subscription_manager.notify(&[newer]);
+
+        let sequences: Vec<Option<u64>> = std::iter::from_fn(|| queue.pop())
+            .map(|response| response.unwrap().signal.unwrap().state.unwrap().sequence)
+            .collect();
+        assert_eq!(sequences, vec![Some(1), Some(2)]);
+    }
+
+    #[test]
+    fn test_batch_is_sent_as_one_response() {
+        let mut subscription_manager = SubscriptionManager::new();
+        let all = SubscriberQueue::new(10, OverflowPolicy::Coalesce);
+        let speed_only = SubscriberQueue::new(10, OverflowPolicy::Coalesce);
+        subscription_manager.subscribe(vec!["Vehicle.**".to_string()], SubscriptionFilter::default(), false, all.clone());
+        subscription_manager.subscribe(
+            vec!["Vehicle.Speed".to_string()],
+            SubscriptionFilter::default(),
+            false,
+            speed_only.clone(),
+        );
+
+        subscription_manager.notify(&[
+            create_test_signal("Vehicle.Speed", Value::Float(10.0)),
+            create_test_signal("Vehicle.Rpm", Value::Float(1000.0)),
+        ]);
+
+        let response = all.pop().unwrap().unwrap();
+        assert!(response.signal.is_none());
+        let paths: Vec<String> = response.signals.into_iter().map(|signal| signal.path).collect();
+        assert_eq!(paths, vec!["Vehicle.Speed".to_string(), "Vehicle.Rpm".to_string()]);
+        assert!(all.pop().is_none());
+
+        // 1つだけのシグナルはsignalで送る
+        let response = speed_only.pop().unwrap().unwrap();
+        assert_eq!(response.signal.unwrap().path, "Vehicle.Speed");
+        assert!(response.signals.is_empty());
+    }
+
+    #[test]
+    fn test_provider_owns_registered_paths() {
+        let mut provider_manager = ProviderManager::new();
+        let (tx1, mut rx1) = tokio::sync::mpsc::channel(10);
+        let (tx2, _rx2) = tokio::sync::mpsc::channel(10);
+        let paths = vec!["Vehicle.Body.Hood.Position".to_string()];
+        provider_manager.register("first", &paths, &paths, tx1).unwrap();
+
+        assert!(provider_manager.register("second", &paths, &paths, tx2).is_err());
+        assert!(provider_manager.provides("first", &paths[0]));
+        assert!(!provider_manager.provides("second", &paths[0]));
+
+        assert!(provider_manager.route_actuation(&paths[0], &Value::Uint8(80)).unwrap());
+        let request = rx1.try_recv().unwrap().unwrap();
+        let Some(provider_response::Message::Actuation(actuation)) = request.message else {
+            panic!("expected an actuation request");
+        };
+        assert_eq!(actuation.path, paths[0]);
+
+        assert_eq!(provider_manager.unregister("first"), paths);
+        assert!(!provider_manager.route_actuation(&paths[0], &Value::Uint8(80)).unwrap());
+    }
+
+    #[test]
+    fn test_provider_pattern_covers_later_signals() {
+        let mut provider_manager = ProviderManager::new();
+        let (tx, _rx) = tokio::sync::mpsc::channel(1);
+        let patterns = vec!["Vehicle.Body.Lights.*".to_string()];
+        let paths = vec!["Vehicle.Body.Lights.Beam".to_string()];
+        provider_manager.register("lights", &patterns, &paths, tx).unwrap();
+
+        // 登録後に追加されたシグナルもパターンで転送し、更新時に担当に加える
+        let added = "Vehicle.Body.Lights.Fog";
+        assert!(provider_manager.route_actuation(added, &Value::Bool(true)).unwrap());
+        assert!(provider_manager.claim("lights", added));
+        assert!(!provider_manager.claim("other", "Vehicle.Body.Hood.Position"));
+
+        // キューが一杯なら要求を黙って捨てずにエラーを返す
+        let status = provider_manager.route_actuation(added, &Value::Bool(false)).unwrap_err();
+        assert_eq!(status.code(), tonic::Code::ResourceExhausted);
+
+        assert_eq!(provider_manager.unregister("lights"), vec![paths[0].clone(), added.to_string()]);
+        assert!(!provider_manager.provides("lights", added));
+    }
+
+    #[test]
+    fn test_page_nodes() {
+        let nodes = || {
+            ["Vehicle.Cabin", "Vehicle.Cabin.Door", "Vehicle.Speed"]
+                .iter()
+                .map(|path| SignalNode::Branch(path.to_string()))
+                .collect::<Vec<_>>()
+        };
+
+        let (page, next_page_token) = page_nodes(nodes(), "", 2);
+        assert_eq!(page.len(), 2);
+        assert_eq!(next_page_token, "Vehicle.Cabin.Door");
+
+        let (page, next_page_token) = page_nodes(nodes(), &next_page_token, 2);
+        assert_eq!(page.iter().map(SignalNode::path).collect::<Vec<_>>(), vec!["Vehicle.Speed"]);
+        assert!(next_page_token.is_empty());
+
+        // 前のページの最後のノードが消えていても続きから返す
+        let (page, _) = page_nodes(nodes(), "Vehicle.Cabin.Door.Row1", 2);
+        assert_eq!(page.iter().map(SignalNode::path).collect::<Vec<_>>(), vec!["Vehicle.Speed"]);
+    }
+
+    #[tokio::test]
+    async fn test_set_without_token_is_rejected_while_locked() {
+        let vehicle_shadow = VehicleShadow::create().unwrap();
+        vehicle_shadow
+            .set_signal(create_test_signal("Vehicle.Speed", Value::Float(0.0)), &None)
+            .unwrap();
+        let service = SignalServiceImpl::new(vehicle_shadow, Federation::default(), 10, None);
+
+        let lock = service
+            .lock(Request::new(LockRequest {
+                paths: vec!["Vehicle.Speed".to_string()],
+                holder: "client-a".to_string(),
+                ttl_ms: None,
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(lock.success);
+
+        // 空のトークンはロックなしの書き込みとして扱われ、ロック中のシグナルには書き込めない
+        let set = |token: String| SetRequest {
+            signals: vec![SetSignalRequest {
+                path: "Vehicle.Speed".to_string(),
+                state: Some(vehicle_shadow::State {
+                    value: Some(convert_value_to_proto(&Value::Float(50.0))),
+                    ..Default::default()
+                }),
+                id: 0,
+            }],
+            token,
+            atomic: false,
+        };
+        let response = service.set(Request::new(set(String::new()))).await.unwrap().into_inner();
+        assert!(!response.success);
+        assert!(response.results[0].error_message.contains("Signal locked"));
+        let response = service.set(Request::new(set(lock.token))).await.unwrap().into_inner();
+        assert!(response.success);
+    }
+
+    #[tokio::test]
+    async fn test_set_results_keep_requested_ids() {
+        let vehicle_shadow = VehicleShadow::create().unwrap();
+        vehicle_shadow
+            .set_signal(create_test_signal("Vehicle.Speed", Value::Float(0.0)), &None)
+            .unwrap();
+        vehicle_shadow.assign_ids(&["Vehicle.Speed".to_string()]).unwrap();
+        let id = vehicle_shadow.get_signal("Vehicle.Speed".to_string()).unwrap().id;
+        let service = SignalServiceImpl::new(vehicle_shadow, Federation::default(), 10, None);
+
+        // 失敗した結果も、IDで指定したシグナルはIDで返す
+        for atomic in [false, true] {
+            let request = SetRequest {
+                signals: vec![SetSignalRequest {
+                    path: String::new(),
+                    state: Some(vehicle_shadow::State {
+                        value: Some(convert_value_to_proto(&Value::Bool(true))),
+                        ..Default::default()
+                    }),
+                    id,
+                }],
+                token: String::new(),
+                atomic,
+            };
+            let response = service.set(Request::new(request)).await.unwrap().into_inner();
+            assert!(!response.results[0].success);
+            assert_eq!(response.results[0].id, id);
+            assert!(response.results[0].path.is_empty());
+        }
+    }
 }
@@ -0,0 +1,297 @@
+use crate::error::{Result, VehicleShadowError};
+use crate::path_pattern;
+use crate::rpc::databroker_server::vehicle_shadow::signal_service_client::SignalServiceClient;
+use crate::rpc::databroker_server::vehicle_shadow::{
+    GetRequest, GetResponse, LockRequest, LockResponse, RenewLockRequest, RenewLockResponse,
+    SetRequest, SetResponse, SubscribeRequest, SubscribeResponse, SubscriptionFilter, UnlockRequest,
+    UnlockResponse,
+};
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tonic::transport::{Channel, Endpoint};
+use tonic::Streaming;
+
+const SEPARATOR: char = '.';
+// 期限後も転送先を覚えておく時間。期限を過ぎてから届いたUnlockも持ち主に転送する
+const LOCK_OWNER_GRACE: Duration = Duration::from_secs(60);
+
+// パスのプレフィックスと、そのシグナルを持つシャドウインスタンスの対応
+// 例: "Vehicle.Cabin=http://[::1]:50052"
+#[derive(Debug, Clone, PartialEq)]
+pub struct Route {
+    pub prefix: String,
+    pub endpoint: String,
+}
+
+impl Route {
+    pub fn parse(route: &str) -> Result<Route> {
+        match route.split_once('=') {
+            Some((prefix, endpoint)) if !prefix.is_empty() && !endpoint.is_empty() => Ok(Route {
+                prefix: prefix.trim_end_matches(SEPARATOR).to_string(),
+                endpoint: endpoint.to_string(),
+            }),
+            _ => Err(VehicleShadowError::Configuration(format!(
+                "Invalid route (expected PREFIX=ENDPOINT): {}",
+                route
+            ))),
+        }
+    }
+}
+
+// ルーティングテーブル
+// どのルートにも一致しないパスはこのインスタンス自身が担当する
+#[derive(Debug, Default)]
+pub struct RoutingTable {
+    routes: Vec<Route>,
+}
+
+impl RoutingTable {
+    pub fn new(mut routes: Vec<Route>) -> Self {
+        // 最長一致にするため長いプレフィックスから並べる
+        routes.sort_by_key(|route| std::cmp::Reverse(route.prefix.len()));
+        Self { routes }
+    }
+
+    /// Returns the endpoint owning `path`, or None if it is served locally.
+    pub fn route(&self, path: &str) -> Option<&str> {
+        self.routes
+            .iter()
+            .find(|route| is_within(path, &route.prefix))
+            .map(|route| route.endpoint.as_str())
+    }
+
+    /// Returns every owner that may hold signals matching `path`. A plain
+    /// path has exactly one owner; a pattern goes to the owner of its literal
+    /// prefix and to every more specific route it may reach into.
+    pub fn targets(&self, path: &str) -> Vec<Option<&str>> {
+        if !path_pattern::is_pattern(path) {
+            return vec![self.route(path)];
+        }
+        let literal_prefix = path_pattern::literal_prefix(path);
+        let literal_prefix = literal_prefix.trim_end_matches(SEPARATOR);
+        let mut targets = vec![self.route(literal_prefix)];
+        for route in &self.routes {
+            let target = Some(route.endpoint.as_str());
+            if route.prefix != literal_prefix
+                && is_within(&route.prefix, literal_prefix)
+                && path_pattern::may_match_within(path, &route.prefix)
+                && !targets.contains(&target)
+            {
+                targets.push(target);
+            }
+        }
+        targets
+    }
+
+    /// Splits `items` into those served locally and those to forward, grouped
+    /// by endpoint. An item whose path is a pattern may land in several groups.
+    pub fn partition<T: Clone>(
+        &self,
+        items: &[T],
+        path_of: impl Fn(&T) -> &str,
+    ) -> (Vec<T>, HashMap<String, Vec<T>>) {
+        let mut local = Vec::new();
+        let mut forwarded: HashMap<String, Vec<T>> = HashMap::new();
+        for item in items {
+            for target in self.targets(path_of(item)) {
+                match target {
+                    Some(endpoint) => forwarded.entry(endpoint.to_string()).or_default().push(item.clone()),
+                    None => local.push(item.clone()),
+                }
+            }
+        }
+        (local, forwarded)
+    }
+}
+
+fn is_within(path: &str, prefix: &str) -> bool {
+    prefix.is_empty()
+        || path == prefix
+        || (path.starts_with(prefix) && path[prefix.len()..].starts_with(SEPARATOR))
+}
+
+// 転送したロックを持つインスタンスと、その期限 (自インスタンスの単調時計)
+struct LockOwner {
+    endpoint: String,
+    expires_at: Instant,
+}
+
+// 他のインスタンスへの転送に使うクライアントと、転送したロックの記録
+#[derive(Default)]
+pub struct Federation {
+    routing_table: RoutingTable,
+    clients: HashMap<String, SignalServiceClient<Channel>>,
+    // ロックのトークン -> ロックを持つインスタンス
+    lock_owners: Mutex<HashMap<String, LockOwner>>,
+}
+
+impl Federation {
+    // 接続は最初の転送時に確立される
+    pub fn new(routes: Vec<Route>) -> Result<Self> {
+        let mut clients = HashMap::new();
+        for route in &routes {
+            if !clients.contains_key(&route.endpoint) {
+                let channel = Endpoint::from_shared(route.endpoint.clone())?.connect_lazy();
+                clients.insert(route.endpoint.clone(), SignalServiceClient::new(channel));
+            }
+        }
+        Ok(Self {
+            routing_table: RoutingTable::new(routes),
+            clients,
+            lock_owners: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn routing_table(&self) -> &RoutingTable {
+        &self.routing_table
+    }
+
+    pub async fn get(&self, endpoint: &str, paths: Vec<String>) -> Result<GetResponse> {
+        Ok(self.client(endpoint)?.get(GetRequest { paths, ..Default::default() }).await?.into_inner())
+    }
+
+    pub async fn set(&self, endpoint: &str, request: SetRequest) -> Result<SetResponse> {
+        Ok(self.client(endpoint)?.set(request).await?.into_inner())
+    }
+
+    pub async fn subscribe(
+        &self,
+        endpoint: &str,
+        paths: Vec<String>,
+        filter: Option<SubscriptionFilter>,
+    ) -> Result<Streaming<SubscribeResponse>> {
+        Ok(self
+            .client(endpoint)?
+            .subscribe(SubscribeRequest {
+                paths,
+                filter,
+                ..Default::default()
+            })
+            .await?
+            .into_inner())
+    }
+
+    pub async fn lock(&self, endpoint: &str, request: LockRequest) -> Result<LockResponse> {
+        let response = self.client(endpoint)?.lock(request).await?.into_inner();
+        if response.success {
+            self.remember_lock(&response.token, endpoint, Duration::from_millis(response.ttl_ms));
+        }
+        Ok(response)
+    }
+
+    pub async fn unlock(&self, endpoint: &str, token: String) -> Result<UnlockResponse> {
+        let response = self
+            .client(endpoint)?
+            .unlock(UnlockRequest { token: token.clone() })
+            .await?
+            .into_inner();
+        // 失敗は転送先がトークンを知らないことを表すので、どちらの場合も記録を消す
+        self.forget_lock(&token);
+        Ok(response)
+    }
+
+    pub async fn renew_lock(&self, endpoint: &str, request: RenewLockRequest) -> Result<RenewLockResponse> {
+        let token = request.token.clone();
+        let response = self.client(endpoint)?.renew_lock(request).await?.into_inner();
+        if response.success {
+            self.remember_lock(&token, endpoint, Duration::from_millis(response.ttl_ms));
+        } else {
+            // 期限切れなどで失効したロック
+            self.forget_lock(&token);
+        }
+        Ok(response)
+    }
+
+    fn client(&self, endpoint: &str) -> Result<SignalServiceClient<Channel>> {
+        self.clients
+            .get(endpoint)
+            .cloned()
+            .ok_or_else(|| VehicleShadowError::Configuration(format!("Unknown endpoint: {}", endpoint)))
+    }
+
+    // 転送先が返した残り時間を自インスタンスの単調時計の期限にして記録する
+    // 時計の異なるインスタンスのUNIX時刻は比べない
+    // 期限から猶予を過ぎた記録はここで捨てるので、解除されずに失効したロックも溜まらない
+    fn remember_lock(&self, token: &str, endpoint: &str, ttl: Duration) {
+        let now = Instant::now();
+        let mut lock_owners = self.lock_owners.lock().unwrap();
+        lock_owners.retain(|_, owner| owner.expires_at + LOCK_OWNER_GRACE > now);
+        lock_owners.insert(
+            token.to_string(),
+            LockOwner {
+                endpoint: endpoint.to_string(),
+                expires_at: now + ttl,
+            },
+        );
+    }
+
+    /// Returns the endpoint holding the lock `token`, or None for local locks.
+    /// A lock that expired here is still routed to its endpoint, which
+    /// decides whether it is still held.
+    pub fn lock_owner(&self, token: &str) -> Option<String> {
+        self.lock_owners.lock().unwrap().get(token).map(|owner| owner.endpoint.clone())
+    }
+
+    fn forget_lock(&self, token: &str) {
+        self.lock_owners.lock().unwrap().remove(token);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_test_table() -> RoutingTable {
+        RoutingTable::new(vec![
+            Route::parse("Vehicle.Cabin=http://[::1]:50052").unwrap(),
+            Route::parse("Vehicle.Cabin.Seat=http://[::1]:50053").unwrap(),
+        ])
+    }
+
+    #[test]
+    fn test_route_by_longest_prefix() {
+        let routing_table = create_test_table();
+        assert_eq!(routing_table.route("Vehicle.Cabin.Door.Row1.IsOpen"), Some("http://[::1]:50052"));
+        assert_eq!(routing_table.route("Vehicle.Cabin.Seat.Row1.Pos"), Some("http://[::1]:50053"));
+        assert_eq!(routing_table.route("Vehicle.CabinTemperature"), None);
+        assert_eq!(routing_table.route("Vehicle.Body.Hood.IsOpen"), None);
+    }
+
+    #[test]
+    fn test_pattern_targets() {
+        let routing_table = create_test_table();
+        assert_eq!(
+            routing_table.targets("Vehicle.**"),
+            vec![None, Some("http://[::1]:50053"), Some("http://[::1]:50052")]
+        );
+        assert_eq!(routing_table.targets("Vehicle.Cabin.Door.*.IsOpen"), vec![Some("http://[::1]:50052")]);
+        assert_eq!(routing_table.targets("Vehicle.Body.**"), vec![None]);
+    }
+
+    #[test]
+    fn test_expired_lock_owner_is_pruned() {
+        let federation = Federation::default();
+        // 期限切れでもUnlockは持ち主に転送する
+        federation.remember_lock("expired", "http://[::1]:50052", Duration::ZERO);
+        assert_eq!(federation.lock_owner("expired").as_deref(), Some("http://[::1]:50052"));
+
+        federation.remember_lock("held", "http://[::1]:50052", Duration::from_secs(60));
+        assert_eq!(federation.lock_owner("held").as_deref(), Some("http://[::1]:50052"));
+        assert_eq!(federation.lock_owners.lock().unwrap().len(), 2);
+
+        // 猶予も過ぎた記録は次の記録時に捨てる
+        federation.lock_owners.lock().unwrap().get_mut("expired").unwrap().expires_at -= LOCK_OWNER_GRACE;
+        federation.remember_lock("another", "http://[::1]:50053", Duration::from_secs(60));
+        assert!(!federation.lock_owners.lock().unwrap().contains_key("expired"));
+        assert!(federation.lock_owners.lock().unwrap().contains_key("held"));
+    }
+
+    #[test]
+    fn test_parse_route() {
+        assert!(Route::parse("Vehicle.Cabin").is_err());
+        assert!(Route::parse("=http://[::1]:50052").is_err());
+        assert_eq!(Route::parse("Vehicle.Cabin.=http://x").unwrap().prefix, "Vehicle.Cabin");
+    }
+}
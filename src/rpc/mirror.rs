@@ -0,0 +1,95 @@
+use crate::error::{Result, VehicleShadowError};
+use crate::rpc::databroker_server::{convert_proto_signal_to_rust, NotificationDispatcher};
+use crate::rpc::databroker_server::vehicle_shadow::signal_service_client::SignalServiceClient;
+use crate::rpc::databroker_server::vehicle_shadow::{GetRequest, SubscribeRequest};
+use crate::vehicle_shadow::VehicleShadow;
+
+use log::{info, warn};
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Duration;
+
+const ALL_SIGNALS: &str = "**";
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+
+// 上流のシャドウの全シグナルを読み取り専用のシャドウに複製する
+// 接続するたびにスナップショットで同期し直し、その後は購読で差分を反映する
+pub struct Mirror {
+    endpoint: String,
+    // この上流から複製したパス。再同期時に上流から消えたシグナルを削除するために使う
+    paths: HashSet<String>,
+}
+
+impl Mirror {
+    pub fn new(endpoint: String) -> Self {
+        Self {
+            endpoint,
+            paths: HashSet::new(),
+        }
+    }
+
+    pub async fn run(mut self, vehicle_shadow: Arc<VehicleShadow>, dispatcher: NotificationDispatcher) {
+        loop {
+            match self.sync(&vehicle_shadow, &dispatcher).await {
+                Ok(()) => warn!("Upstream {} closed the subscription", self.endpoint),
+                Err(e) => warn!("Mirroring {} failed: {}", self.endpoint, e),
+            }
+            tokio::time::sleep(RECONNECT_INTERVAL).await;
+        }
+    }
+
+    async fn sync(&mut self, vehicle_shadow: &VehicleShadow, dispatcher: &NotificationDispatcher) -> Result<()> {
+        let mut client = SignalServiceClient::connect(self.endpoint.clone()).await?;
+
+        // スナップショットの取得中の更新を取りこぼさないよう、先に購読する
+        let mut updates = client
+            .subscribe(SubscribeRequest {
+                paths: vec![ALL_SIGNALS.to_string()],
+                ..Default::default()
+            })
+            .await?
+            .into_inner();
+        let snapshot = client
+            .get(GetRequest {
+                paths: vec![ALL_SIGNALS.to_string()],
+                ..Default::default()
+            })
+            .await?
+            .into_inner();
+        if !snapshot.success {
+            return Err(VehicleShadowError::Rpc(snapshot.error_message));
+        }
+
+        let signals = snapshot
+            .signals
+            .iter()
+            .map(convert_proto_signal_to_rust)
+            .collect::<Result<Vec<_>>>()?;
+        let paths: HashSet<String> = signals.iter().map(|signal| signal.path.clone()).collect();
+        // 上流が再起動していれば通し番号が振り直されている
+        for signal in &signals {
+            vehicle_shadow.store_replica(signal.clone())?;
+        }
+        let mut removed = Vec::new();
+        for path in self.paths.difference(&paths) {
+            removed.extend(vehicle_shadow.delete_signal(path)?);
+        }
+        dispatcher.dispatch_replaced(signals, removed);
+        info!("Mirrored {} signals from {}", paths.len(), self.endpoint);
+        self.paths = paths;
+
+        // スナップショットより古い更新はapply_replicaで読み捨てられる
+        while let Some(response) = updates.message().await? {
+            let mut applied = Vec::new();
+            for signal in response.signal.iter().chain(&response.signals) {
+                let signal = convert_proto_signal_to_rust(signal)?;
+                if vehicle_shadow.apply_replica(signal.clone())? {
+                    self.paths.insert(signal.path.clone());
+                    applied.push(signal);
+                }
+            }
+            dispatcher.dispatch(applied);
+        }
+        Ok(())
+    }
+}
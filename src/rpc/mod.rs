@@ -1 +1,5 @@
 pub mod databroker_server;
+pub mod federation;
+pub mod mirror;
+pub mod subscription_filter;
+pub mod subscriber_queue;
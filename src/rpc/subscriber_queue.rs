@@ -0,0 +1,247 @@
+use crate::rpc::databroker_server::vehicle_shadow;
+use crate::rpc::databroker_server::vehicle_shadow::SubscribeResponse;
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use tokio::sync::{mpsc, Notify};
+use tonic::Status;
+
+// 通知を送る側がgRPCストリームの送信を待たないよう、購読者ごとのキューに積み、
+// 購読者ごとの配送タスクが読み出して送信する。キューが溢れたときの動作は購読者が選ぶ
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OverflowPolicy {
+    // 同じパスの古い更新を最新の更新で置き換える。異なるパスで溢れたときは最も古い更新を捨てる
+    Coalesce,
+    DropOldest,
+    // 購読を終了させる
+    Disconnect,
+}
+
+impl From<vehicle_shadow::OverflowPolicy> for OverflowPolicy {
+    fn from(policy: vehicle_shadow::OverflowPolicy) -> Self {
+        match policy {
+            vehicle_shadow::OverflowPolicy::Coalesce => OverflowPolicy::Coalesce,
+            vehicle_shadow::OverflowPolicy::DropOldest => OverflowPolicy::DropOldest,
+            vehicle_shadow::OverflowPolicy::Disconnect => OverflowPolicy::Disconnect,
+        }
+    }
+}
+
+struct Queue {
+    // 購読IDと現在の値。容量に関係なくすべて、どの更新よりも先に送る
+    initial: VecDeque<SubscribeResponse>,
+    entries: VecDeque<SubscribeResponse>,
+    capacity: usize,
+    policy: OverflowPolicy,
+    // 捨てた更新の累計。送信する応答に付けて購読者に知らせる
+    dropped: u64,
+    overflowed: bool,
+}
+
+#[derive(Clone)]
+pub struct SubscriberQueue {
+    queue: Arc<Mutex<Queue>>,
+    wakeup: Arc<Notify>,
+}
+
+impl SubscriberQueue {
+    pub fn new(capacity: usize, policy: OverflowPolicy) -> Self {
+        Self {
+            queue: Arc::new(Mutex::new(Queue {
+                initial: VecDeque::new(),
+                entries: VecDeque::new(),
+                capacity,
+                policy,
+                dropped: 0,
+                overflowed: false,
+            })),
+            wakeup: Arc::new(Notify::new()),
+        }
+    }
+
+    /// Queues `response` without waiting, applying the overflow policy when
+    /// the queue is full.
+    pub fn push(&self, response: SubscribeResponse) {
+        let mut queue = self.queue.lock().unwrap();
+        if queue.overflowed {
+            return;
+        }
+
+        // まとめて送る応答は置き換えない。順序を保つため、置き換えるのは
+        // そのパスを含む最後の応答が同じパスだけの応答である場合に限る
+        if queue.policy == OverflowPolicy::Coalesce
+            && let Some(path) = response.signal.as_ref().map(|signal| signal.path.clone())
+            && let Some(queued) = queue.entries.iter_mut().rev().find(|queued| contains(queued, &path))
+            && queued.signals.is_empty()
+        {
+            *queued = response;
+            queue.dropped += 1;
+            return;
+        }
+
+        if queue.entries.len() >= queue.capacity {
+            match queue.policy {
+                OverflowPolicy::Coalesce | OverflowPolicy::DropOldest => {
+                    queue.entries.pop_front();
+                    queue.dropped += 1;
+                }
+                OverflowPolicy::Disconnect => {
+                    queue.overflowed = true;
+                    queue.entries.clear();
+                    drop(queue);
+                    self.wakeup.notify_one();
+                    return;
+                }
+            }
+        }
+        queue.entries.push_back(response);
+        drop(queue);
+        self.wakeup.notify_one();
+    }
+
+    /// Counts an update that was not queued at all, so the subscriber still
+    /// learns that it missed one.
+    pub fn count_dropped(&self) {
+        self.queue.lock().unwrap().dropped += 1;
+    }
+
+    /// Queues the first responses of a subscription ahead of all updates.
+    /// They are neither counted against the capacity nor replaced.
+    pub fn push_initial(&self, responses: Vec<SubscribeResponse>) {
+        self.queue.lock().unwrap().initial.extend(responses);
+        self.wakeup.notify_one();
+    }
+
+    /// Takes the next response to deliver, stamped with the number of
+    /// updates dropped so far, or the error ending an overflowed subscription.
+    pub fn pop(&self) -> Option<Result<SubscribeResponse, Status>> {
+        let mut queue = self.queue.lock().unwrap();
+        if queue.overflowed {
+            return Some(Err(Status::resource_exhausted(format!(
+                "Subscriber too slow: more than {} updates queued",
+                queue.capacity
+            ))));
+        }
+        let mut response = match queue.initial.pop_front() {
+            Some(response) => response,
+            None => queue.entries.pop_front()?,
+        };
+        response.dropped = queue.dropped;
+        Some(Ok(response))
+    }
+
+    // 配送タスク: ストリームの送信を待つのはこのタスクだけ
+    pub async fn deliver(self, tx: mpsc::Sender<Result<SubscribeResponse, Status>>) {
+        loop {
+            match self.pop() {
+                Some(Ok(response)) => {
+                    if tx.send(Ok(response)).await.is_err() {
+                        break;
+                    }
+                }
+                Some(Err(status)) => {
+                    let _ = tx.send(Err(status)).await;
+                    break;
+                }
+                None => {
+                    tokio::select! {
+                        _ = self.wakeup.notified() => {}
+                        _ = tx.closed() => break,
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn contains(response: &SubscribeResponse, path: &str) -> bool {
+    response
+        .signal
+        .iter()
+        .chain(&response.signals)
+        .any(|signal| signal.path == path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_test_response(path: &str, sequence: u64) -> SubscribeResponse {
+        SubscribeResponse {
+            signal: Some(vehicle_shadow::Signal {
+                path: path.to_string(),
+                state: Some(vehicle_shadow::State {
+                    sequence: Some(sequence),
+                    ..Default::default()
+                }),
+                config: None,
+                id: 0,
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn popped_sequence(queue: &SubscriberQueue) -> Option<(String, u64, u64)> {
+        let response = queue.pop()?.unwrap();
+        let signal = response.signal.unwrap();
+        Some((signal.path, signal.state.unwrap().sequence.unwrap(), response.dropped))
+    }
+
+    #[test]
+    fn test_drop_oldest() {
+        let queue = SubscriberQueue::new(2, OverflowPolicy::DropOldest);
+        for sequence in 1..=3 {
+            queue.push(create_test_response("Vehicle.Speed", sequence));
+        }
+        assert_eq!(popped_sequence(&queue), Some(("Vehicle.Speed".to_string(), 2, 1)));
+        assert_eq!(popped_sequence(&queue), Some(("Vehicle.Speed".to_string(), 3, 1)));
+        assert!(queue.pop().is_none());
+    }
+
+    #[test]
+    fn test_coalesce_keeps_latest_per_path() {
+        let queue = SubscriberQueue::new(2, OverflowPolicy::Coalesce);
+        queue.push(create_test_response("Vehicle.Speed", 1));
+        queue.push(create_test_response("Vehicle.Rpm", 1));
+        queue.push(create_test_response("Vehicle.Speed", 2));
+
+        assert_eq!(popped_sequence(&queue), Some(("Vehicle.Speed".to_string(), 2, 1)));
+        assert_eq!(popped_sequence(&queue), Some(("Vehicle.Rpm".to_string(), 1, 1)));
+    }
+
+    #[test]
+    fn test_coalesce_keeps_order_with_batches() {
+        let queue = SubscriberQueue::new(10, OverflowPolicy::Coalesce);
+        queue.push(create_test_response("Vehicle.Speed", 1));
+        let mut batch = create_test_response("Vehicle.Speed", 2);
+        batch.signals = vec![batch.signal.take().unwrap(), create_test_response("Vehicle.Rpm", 2).signal.unwrap()];
+        queue.push(batch);
+        queue.push(create_test_response("Vehicle.Speed", 3));
+
+        assert_eq!(popped_sequence(&queue), Some(("Vehicle.Speed".to_string(), 1, 0)));
+        assert_eq!(queue.pop().unwrap().unwrap().signals.len(), 2);
+        assert_eq!(popped_sequence(&queue), Some(("Vehicle.Speed".to_string(), 3, 0)));
+    }
+
+    #[test]
+    fn test_initial_responses_come_first() {
+        let queue = SubscriberQueue::new(1, OverflowPolicy::DropOldest);
+        queue.push(create_test_response("Vehicle.Speed", 3));
+        queue.push_initial(vec![create_test_response("Vehicle.Speed", 1), create_test_response("Vehicle.Rpm", 2)]);
+
+        assert_eq!(popped_sequence(&queue), Some(("Vehicle.Speed".to_string(), 1, 0)));
+        assert_eq!(popped_sequence(&queue), Some(("Vehicle.Rpm".to_string(), 2, 0)));
+        assert_eq!(popped_sequence(&queue), Some(("Vehicle.Speed".to_string(), 3, 0)));
+        assert!(queue.pop().is_none());
+    }
+
+    #[test]
+    fn test_disconnect_on_overflow() {
+        let queue = SubscriberQueue::new(1, OverflowPolicy::Disconnect);
+        queue.push(create_test_response("Vehicle.Speed", 1));
+        queue.push(create_test_response("Vehicle.Speed", 2));
+
+        let status = queue.pop().unwrap().unwrap_err();
+        assert_eq!(status.code(), tonic::Code::ResourceExhausted);
+    }
+}
@@ -0,0 +1,237 @@
+use crate::error::{Result, VehicleShadowError};
+use crate::rpc::databroker_server::vehicle_shadow;
+use crate::signal::{Signal, Value};
+
+use std::time::{Duration, Instant};
+
+// 購読ごとの通知条件
+// 値の変化が小さい通知（デッドバンド・変化なし）は捨て、
+// 間隔が短すぎる通知は保留して間隔が空いた時点で最新の値だけを送る
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SubscriptionFilter {
+    pub min_interval: Option<Duration>,
+    pub deadband: Option<Deadband>,
+    pub on_change_only: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Deadband {
+    Absolute(f64),
+    // 設定のminからmaxまでの範囲に対する割合。範囲がないシグナルでは前回通知した値に対する割合
+    Percent(f64),
+}
+
+// 購読者に最後に通知した状態
+#[derive(Debug, Clone)]
+pub struct Notified {
+    value: Value,
+    availability: bool,
+    at: Instant,
+}
+
+impl Notified {
+    pub fn new(signal: &Signal, at: Instant) -> Self {
+        Self {
+            value: signal.state.value.clone(),
+            availability: signal.state.availability,
+            at,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Decision {
+    Send,
+    // 間隔を空けてから送る
+    Defer,
+    Drop,
+}
+
+impl SubscriptionFilter {
+    pub fn from_proto(filter: &vehicle_shadow::SubscriptionFilter) -> Result<Self> {
+        let invalid = |name: &str, value: f64| {
+            VehicleShadowError::InvalidInput(format!("{} must be a positive number: {}", name, value))
+        };
+
+        // 最大レートは最小間隔に換算し、両方指定された場合は長い方を使う
+        let mut min_interval = filter.min_interval_ms.map(Duration::from_millis);
+        if let Some(max_rate_hz) = filter.max_rate_hz {
+            if !(max_rate_hz > 0.0 && max_rate_hz.is_finite()) {
+                return Err(invalid("max_rate_hz", max_rate_hz));
+            }
+            let interval = Duration::from_secs_f64(1.0 / max_rate_hz);
+            min_interval = Some(min_interval.map_or(interval, |min_interval| min_interval.max(interval)));
+        }
+
+        let deadband = match (filter.deadband, filter.deadband_percent) {
+            (Some(_), Some(_)) => {
+                return Err(VehicleShadowError::InvalidInput(
+                    "deadband and deadband_percent are mutually exclusive".to_string(),
+                ));
+            }
+            (Some(deadband), None) if deadband >= 0.0 && deadband.is_finite() => Some(Deadband::Absolute(deadband)),
+            (None, Some(percent)) if percent >= 0.0 && percent.is_finite() => Some(Deadband::Percent(percent)),
+            (Some(deadband), None) => return Err(invalid("deadband", deadband)),
+            (None, Some(percent)) => return Err(invalid("deadband_percent", percent)),
+            (None, None) => None,
+        };
+
+        Ok(Self {
+            min_interval,
+            deadband,
+            on_change_only: filter.on_change_only,
+        })
+    }
+
+    /// Decides what to do with a new state of a signal, given what was last
+    /// sent to the subscriber for it.
+    pub fn decide(&self, last: Option<&Notified>, signal: &Signal, now: Instant) -> Decision {
+        let Some(last) = last else {
+            return Decision::Send;
+        };
+        if !self.is_significant(last, signal) {
+            return Decision::Drop;
+        }
+        if self.is_ready(last, now) {
+            Decision::Send
+        } else {
+            Decision::Defer
+        }
+    }
+
+    /// Whether the minimum interval since the last notification has passed.
+    pub fn is_ready(&self, last: &Notified, now: Instant) -> bool {
+        self.min_interval
+            .is_none_or(|min_interval| now.duration_since(last.at) >= min_interval)
+    }
+
+    // 利用可否の変化は常に通知する
+    fn is_significant(&self, last: &Notified, signal: &Signal) -> bool {
+        let value = &signal.state.value;
+        if last.availability != signal.state.availability {
+            return true;
+        }
+        if let Some(deadband) = &self.deadband
+            && let (Some(last_value), Some(new_value)) = (last.value.as_f64(), value.as_f64())
+        {
+            let threshold = match deadband {
+                Deadband::Absolute(threshold) => *threshold,
+                Deadband::Percent(percent) => {
+                    let range = match (&signal.config.min, &signal.config.max) {
+                        (Some(min), Some(max)) => max.as_f64().zip(min.as_f64()).map(|(max, min)| max - min),
+                        _ => None,
+                    };
+                    range.unwrap_or(last_value.abs()) * percent / 100.0
+                }
+            };
+            return (new_value - last_value).abs() > threshold;
+        }
+        // 数値でない値のデッドバンドは変化の有無で判定する
+        if self.on_change_only || self.deadband.is_some() {
+            return last.value != *value;
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::signal::{Config, LeafType, State, ValueType};
+
+    fn create_test_signal(value: Value) -> Signal {
+        Signal {
+            path: "Vehicle.Speed".to_string(),
+            id: 0,
+            state: State {
+                value,
+                capability: true,
+                availability: true,
+                ..Default::default()
+            },
+            config: Config {
+                leaf_type: LeafType::Sensor,
+                data_type: ValueType::TypeFloat,
+                min: Some(Value::Float(0.0)),
+                max: Some(Value::Float(200.0)),
+                ..Default::default()
+            },
+        }
+    }
+
+    #[test]
+    fn test_on_change_only() {
+        let filter = SubscriptionFilter {
+            on_change_only: true,
+            ..Default::default()
+        };
+        let now = Instant::now();
+        let last = Notified::new(&create_test_signal(Value::Float(10.0)), now);
+
+        assert_eq!(filter.decide(Some(&last), &create_test_signal(Value::Float(10.0)), now), Decision::Drop);
+        assert_eq!(filter.decide(Some(&last), &create_test_signal(Value::Float(11.0)), now), Decision::Send);
+
+        let mut unavailable = create_test_signal(Value::Float(10.0));
+        unavailable.state.availability = false;
+        assert_eq!(filter.decide(Some(&last), &unavailable, now), Decision::Send);
+    }
+
+    #[test]
+    fn test_deadband() {
+        let now = Instant::now();
+        let last = Notified::new(&create_test_signal(Value::Float(100.0)), now);
+
+        let absolute = SubscriptionFilter {
+            deadband: Some(Deadband::Absolute(5.0)),
+            ..Default::default()
+        };
+        assert_eq!(absolute.decide(Some(&last), &create_test_signal(Value::Float(104.0)), now), Decision::Drop);
+        assert_eq!(absolute.decide(Some(&last), &create_test_signal(Value::Float(94.0)), now), Decision::Send);
+
+        // 範囲0..200の5%は10
+        let percent = SubscriptionFilter {
+            deadband: Some(Deadband::Percent(5.0)),
+            ..Default::default()
+        };
+        assert_eq!(percent.decide(Some(&last), &create_test_signal(Value::Float(109.0)), now), Decision::Drop);
+        assert_eq!(percent.decide(Some(&last), &create_test_signal(Value::Float(111.0)), now), Decision::Send);
+    }
+
+    #[test]
+    fn test_min_interval() {
+        let filter = SubscriptionFilter {
+            min_interval: Some(Duration::from_millis(100)),
+            ..Default::default()
+        };
+        let now = Instant::now();
+        let last = Notified::new(&create_test_signal(Value::Float(1.0)), now);
+        let signal = create_test_signal(Value::Float(2.0));
+
+        assert_eq!(filter.decide(None, &signal, now), Decision::Send);
+        assert_eq!(filter.decide(Some(&last), &signal, now + Duration::from_millis(50)), Decision::Defer);
+        assert_eq!(filter.decide(Some(&last), &signal, now + Duration::from_millis(100)), Decision::Send);
+    }
+
+    #[test]
+    fn test_from_proto() {
+        let filter = SubscriptionFilter::from_proto(&vehicle_shadow::SubscriptionFilter {
+            min_interval_ms: Some(50),
+            max_rate_hz: Some(10.0),
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(filter.min_interval, Some(Duration::from_millis(100)));
+
+        assert!(SubscriptionFilter::from_proto(&vehicle_shadow::SubscriptionFilter {
+            deadband: Some(1.0),
+            deadband_percent: Some(1.0),
+            ..Default::default()
+        })
+        .is_err());
+        assert!(SubscriptionFilter::from_proto(&vehicle_shadow::SubscriptionFilter {
+            max_rate_hz: Some(0.0),
+            ..Default::default()
+        })
+        .is_err());
+    }
+}
@@ -5,24 +5,58 @@ use serde::{Deserialize, Serialize};
 use std::fmt::Display;
 use std::io;
 use std::str::FromStr;
+use std::sync::OnceLock;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 
 #[derive(Encode, Decode, serde::Serialize, Deserialize, Debug, Clone)]
 pub struct Signal {
     pub path: String,
+    // Compact identifier assigned by the shadow the first time it stores the
+    // signal, stable across restarts. 0 means not assigned yet.
+    pub id: u32,
     pub state: State,
     pub config: Config,
 }
 
-#[derive(Encode, Decode, Serialize, Deserialize, Debug, Clone)]
+#[derive(Encode, Decode, Serialize, Deserialize, Debug, Clone, Default)]
 pub struct State {
     pub value: Value,
     pub capability: bool,
     pub availability: bool,
     pub lock_uuid: Option<String>,
     pub reserved: String,
+    pub sequence: u64,
+    pub source_timestamp: Option<Timestamp>,
+    pub sink_timestamp: Option<Timestamp>,
+    // Requested value of an actuator. `value` holds the current value
+    // reported by the provider.
+    pub target: Option<Value>,
 }
 
-#[derive(Encode, Decode, Serialize, Deserialize, Debug, Clone)]
+// wall_clock_ns is nanoseconds since the UNIX epoch. monotonic_ns is nanoseconds
+// since the shadow process started and is only comparable within one run.
+#[derive(Encode, Decode, Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Timestamp {
+    pub wall_clock_ns: u64,
+    pub monotonic_ns: u64,
+}
+
+impl Timestamp {
+    pub fn now() -> Self {
+        static MONOTONIC_ORIGIN: OnceLock<Instant> = OnceLock::new();
+        let origin = MONOTONIC_ORIGIN.get_or_init(Instant::now);
+        let wall_clock_ns = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0);
+        Timestamp {
+            wall_clock_ns,
+            monotonic_ns: origin.elapsed().as_nanos() as u64,
+        }
+    }
+}
+
+#[derive(Encode, Decode, Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
 pub struct Config {
     pub leaf_type: LeafType,
     pub data_type: ValueType,
@@ -35,10 +69,17 @@ pub struct Config {
     pub allowd: Option<Vec<Value>>,
     pub default: Option<Value>,
     pub end_point: String,
+    /// Makes the signal a read-only derived signal computed by the shadow,
+    /// see [`crate::expression`].
+    pub expression: Option<String>,
+    /// Expected interval between writes in milliseconds. A signal not
+    /// written for several periods is marked unavailable by the shadow.
+    pub update_period: Option<u64>,
 }
 
-#[derive(Encode, Decode, Serialize, Deserialize, Clone, Debug)]
+#[derive(Encode, Decode, Serialize, Deserialize, Clone, Debug, PartialEq, Default)]
 pub enum Value {
+    #[default]
     NAN,
     Bool(bool),
     String(String),
@@ -66,8 +107,77 @@ pub enum Value {
     DoubleArray(Vec<f64>),
 }
 
-#[derive(Encode, Decode, Serialize, Deserialize, Clone, Debug)]
+impl Value {
+    pub fn value_type(&self) -> ValueType {
+        match self {
+            Value::NAN => ValueType::TypeNAN,
+            Value::Bool(_) => ValueType::TypeBool,
+            Value::String(_) => ValueType::TypeString,
+            Value::Int8(_) => ValueType::TypeInt8,
+            Value::Int16(_) => ValueType::TypeInt16,
+            Value::Int32(_) => ValueType::TypeInt32,
+            Value::Int64(_) => ValueType::TypeInt64,
+            Value::Uint8(_) => ValueType::TypeUint8,
+            Value::Uint16(_) => ValueType::TypeUint16,
+            Value::Uint32(_) => ValueType::TypeUint32,
+            Value::Uint64(_) => ValueType::TypeUint64,
+            Value::Float(_) => ValueType::TypeFloat,
+            Value::Double(_) => ValueType::TypeDouble,
+            Value::BoolArray(_) => ValueType::TypeBoolArray,
+            Value::StringArray(_) => ValueType::TypeStringArray,
+            Value::Int8Array(_) => ValueType::TypeInt8Array,
+            Value::Int16Array(_) => ValueType::TypeInt16Array,
+            Value::Int32Array(_) => ValueType::TypeInt32Array,
+            Value::Int64Array(_) => ValueType::TypeInt64Array,
+            Value::Uint8Array(_) => ValueType::TypeUint8Array,
+            Value::Uint16Array(_) => ValueType::TypeUint16Array,
+            Value::Uint32Array(_) => ValueType::TypeUint32Array,
+            Value::Uint64Array(_) => ValueType::TypeUint64Array,
+            Value::FloatArray(_) => ValueType::TypeFloatArray,
+            Value::DoubleArray(_) => ValueType::TypeDoubleArray,
+        }
+    }
+
+    /// Splits an array value into scalar values. A scalar yields itself.
+    pub fn elements(&self) -> Vec<Value> {
+        match self {
+            Value::NAN => Vec::new(),
+            Value::BoolArray(v) => v.iter().map(|x| Value::Bool(*x)).collect(),
+            Value::StringArray(v) => v.iter().map(|x| Value::String(x.clone())).collect(),
+            Value::Int8Array(v) => v.iter().map(|x| Value::Int8(*x)).collect(),
+            Value::Int16Array(v) => v.iter().map(|x| Value::Int16(*x)).collect(),
+            Value::Int32Array(v) => v.iter().map(|x| Value::Int32(*x)).collect(),
+            Value::Int64Array(v) => v.iter().map(|x| Value::Int64(*x)).collect(),
+            Value::Uint8Array(v) => v.iter().map(|x| Value::Uint8(*x)).collect(),
+            Value::Uint16Array(v) => v.iter().map(|x| Value::Uint16(*x)).collect(),
+            Value::Uint32Array(v) => v.iter().map(|x| Value::Uint32(*x)).collect(),
+            Value::Uint64Array(v) => v.iter().map(|x| Value::Uint64(*x)).collect(),
+            Value::FloatArray(v) => v.iter().map(|x| Value::Float(*x)).collect(),
+            Value::DoubleArray(v) => v.iter().map(|x| Value::Double(*x)).collect(),
+            scalar => vec![scalar.clone()],
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Value::Int8(v) => Some(*v as f64),
+            Value::Int16(v) => Some(*v as f64),
+            Value::Int32(v) => Some(*v as f64),
+            Value::Int64(v) => Some(*v as f64),
+            Value::Uint8(v) => Some(*v as f64),
+            Value::Uint16(v) => Some(*v as f64),
+            Value::Uint32(v) => Some(*v as f64),
+            Value::Uint64(v) => Some(*v as f64),
+            Value::Float(v) => Some(*v as f64),
+            Value::Double(v) => Some(*v),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Encode, Decode, Serialize, Deserialize, Clone, Debug, PartialEq, Default)]
 pub enum ValueType {
+    #[default]
     TypeNAN,
     TypeBool,
     TypeString,
@@ -97,7 +207,7 @@ pub enum ValueType {
 
 impl ValueType {
     pub fn build_value(&self, value: &serde_json::Value) -> Value {
-        return match self {
+        match self {
             ValueType::TypeNAN => Value::NAN,
             ValueType::TypeBool => {
                 if let Ok(value) = serde_json::from_value::<bool>(value.clone()) {
@@ -357,7 +467,27 @@ impl ValueType {
                     Value::NAN
                 }
             }
-        };
+        }
+    }
+}
+
+impl ValueType {
+    pub fn element_type(&self) -> ValueType {
+        match self {
+            ValueType::TypeBoolArray => ValueType::TypeBool,
+            ValueType::TypeStringArray => ValueType::TypeString,
+            ValueType::TypeInt8Array => ValueType::TypeInt8,
+            ValueType::TypeInt16Array => ValueType::TypeInt16,
+            ValueType::TypeInt32Array => ValueType::TypeInt32,
+            ValueType::TypeInt64Array => ValueType::TypeInt64,
+            ValueType::TypeUint8Array => ValueType::TypeUint8,
+            ValueType::TypeUint16Array => ValueType::TypeUint16,
+            ValueType::TypeUint32Array => ValueType::TypeUint32,
+            ValueType::TypeUint64Array => ValueType::TypeUint64,
+            ValueType::TypeFloatArray => ValueType::TypeFloat,
+            ValueType::TypeDoubleArray => ValueType::TypeDouble,
+            scalar => scalar.clone(),
+        }
     }
 }
 
@@ -394,8 +524,64 @@ impl FromStr for ValueType {
     }
 }
 
-#[derive(Encode, Decode, Serialize, Deserialize, Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConstraintViolation {
+    TypeMismatch { expected: ValueType, actual: ValueType },
+    BelowMin { value: Value, min: Value },
+    AboveMax { value: Value, max: Value },
+    NotAllowed { value: Value, allowed: Vec<Value> },
+}
+
+impl Config {
+    /// Checks `value` against the data type, range and allowed list of this
+    /// signal. `Value::NAN` means "no value" and is always accepted.
+    pub fn validate(&self, value: &Value) -> Result<(), ConstraintViolation> {
+        if *value == Value::NAN {
+            return Ok(());
+        }
+
+        if value.value_type() != self.data_type {
+            return Err(ConstraintViolation::TypeMismatch {
+                expected: self.data_type.clone(),
+                actual: value.value_type(),
+            });
+        }
+
+        for element in value.elements() {
+            let number = element.as_f64();
+            if let (Some(min), Some(number)) = (&self.min, number)
+                && min.as_f64().is_some_and(|min| number < min)
+            {
+                return Err(ConstraintViolation::BelowMin {
+                    value: element,
+                    min: min.clone(),
+                });
+            }
+            if let (Some(max), Some(number)) = (&self.max, number)
+                && max.as_f64().is_some_and(|max| number > max)
+            {
+                return Err(ConstraintViolation::AboveMax {
+                    value: element,
+                    max: max.clone(),
+                });
+            }
+            if let Some(allowed) = &self.allowd
+                && !allowed.contains(&element)
+            {
+                return Err(ConstraintViolation::NotAllowed {
+                    value: element,
+                    allowed: allowed.clone(),
+                });
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Encode, Decode, Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
 pub enum LeafType {
+    #[default]
     Branch,
     Sensor,
     Attribute,
@@ -431,8 +617,8 @@ impl Display for State {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
-            "{{ \"value\": \"{}\", \"capability\": {}, \"availability\": {}, \"reserved\": \"{}\" }}",
-            self.value, self.capability, self.availability, self.reserved
+            "{{ \"value\": \"{}\", \"capability\": {}, \"availability\": {}, \"reserved\": \"{}\", \"sequence\": {}, \"target\": \"{:?}\" }}",
+            self.value, self.capability, self.availability, self.reserved, self.sequence, self.target
         )
     }
 }
@@ -498,6 +684,26 @@ impl Display for Config {
     }
 }
 
+impl Display for ConstraintViolation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConstraintViolation::TypeMismatch { expected, actual } => {
+                write!(f, "type mismatch: expected {:?}, got {:?}", expected, actual)
+            }
+            ConstraintViolation::BelowMin { value, min } => {
+                write!(f, "{} is below min {}", value, min)
+            }
+            ConstraintViolation::AboveMax { value, max } => {
+                write!(f, "{} is above max {}", value, max)
+            }
+            ConstraintViolation::NotAllowed { value, allowed } => {
+                let allowed: Vec<String> = allowed.iter().map(|v| v.to_string()).collect();
+                write!(f, "{} is not one of [{}]", value, allowed.join(", "))
+            }
+        }
+    }
+}
+
 impl Display for LeafType {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let name = match self {
@@ -533,6 +739,10 @@ mod tests {
             availability: false,
             lock_uuid: None,
             reserved: "test".to_string(),
+            sequence: 0,
+            source_timestamp: None,
+            sink_timestamp: None,
+            target: None,
         };
         
         assert!(matches!(state.value, Value::Int32(100)));
@@ -545,12 +755,17 @@ mod tests {
     fn test_signal_creation() {
         let signal = Signal {
             path: "Vehicle.Speed".to_string(),
+            id: 0,
             state: State {
                 value: Value::Float(60.5),
                 capability: true,
                 availability: true,
                 lock_uuid: None,
                 reserved: "".to_string(),
+                sequence: 0,
+                source_timestamp: None,
+                sink_timestamp: None,
+                target: None,
             },
             config: Config {
                 leaf_type: LeafType::Sensor,
@@ -564,6 +779,8 @@ mod tests {
                 allowd: None,
                 default: None,
                 end_point: "".to_string(),
+                expression: None,
+                update_period: None,
             },
         };
         
@@ -572,6 +789,77 @@ mod tests {
         assert_eq!(signal.config.unit, Some("km/h".to_string()));
     }
 
+    fn create_test_config(data_type: ValueType) -> Config {
+        Config {
+            leaf_type: LeafType::Sensor,
+            data_type,
+            deprecation: None,
+            unit: None,
+            min: None,
+            max: None,
+            description: None,
+            comment: None,
+            allowd: None,
+            default: None,
+            end_point: "".to_string(),
+            expression: None,
+            update_period: None,
+        }
+    }
+
+    #[test]
+    fn test_validate_type_mismatch() {
+        let config = create_test_config(ValueType::TypeFloat);
+
+        assert!(config.validate(&Value::Float(1.0)).is_ok());
+        assert!(config.validate(&Value::NAN).is_ok());
+        assert_eq!(
+            config.validate(&Value::String("fast".to_string())),
+            Err(ConstraintViolation::TypeMismatch {
+                expected: ValueType::TypeFloat,
+                actual: ValueType::TypeString,
+            })
+        );
+    }
+
+    #[test]
+    fn test_validate_range() {
+        let mut config = create_test_config(ValueType::TypeUint8Array);
+        config.min = Some(Value::Uint8(10));
+        config.max = Some(Value::Uint8(100));
+
+        assert!(config.validate(&Value::Uint8Array(vec![10, 50, 100])).is_ok());
+        assert_eq!(
+            config.validate(&Value::Uint8Array(vec![50, 5])),
+            Err(ConstraintViolation::BelowMin {
+                value: Value::Uint8(5),
+                min: Value::Uint8(10),
+            })
+        );
+        assert_eq!(
+            config.validate(&Value::Uint8Array(vec![101])),
+            Err(ConstraintViolation::AboveMax {
+                value: Value::Uint8(101),
+                max: Value::Uint8(100),
+            })
+        );
+    }
+
+    #[test]
+    fn test_validate_allowed() {
+        let mut config = create_test_config(ValueType::TypeString);
+        config.allowd = Some(vec![
+            Value::String("OPEN".to_string()),
+            Value::String("CLOSE".to_string()),
+        ]);
+
+        assert!(config.validate(&Value::String("OPEN".to_string())).is_ok());
+        assert!(matches!(
+            config.validate(&Value::String("AJAR".to_string())),
+            Err(ConstraintViolation::NotAllowed { .. })
+        ));
+    }
+
     #[test]
     fn test_value_type_from_str() {
         assert!(matches!(ValueType::from_str("boolean").unwrap(), ValueType::TypeBool));
@@ -0,0 +1,130 @@
+// シャドウ全体（全シグナルの設定と状態）のスナップショット。JSONは人とテスト用、バイナリ(bincode)はサイズ用
+// どちらも形式名とバージョンを持ち、新しいシャドウが書いたものは読み込まない
+
+use bincode::config::standard;
+use bincode::{decode_from_slice, encode_to_vec, Decode, Encode};
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Result, VehicleShadowError};
+use crate::signal;
+
+pub const SNAPSHOT_FORMAT: &str = "vehicle-signal-shadow-snapshot";
+pub const SNAPSHOT_VERSION: u32 = 1;
+// バイナリの先頭。続けてバージョンをビッグエンディアンのu32で置く
+const BINARY_MAGIC: &[u8; 4] = b"VSSS";
+
+#[derive(Encode, Decode, Serialize, Deserialize, Debug, Clone)]
+pub struct Snapshot {
+    pub format: String,
+    pub version: u32,
+    pub created_at: signal::Timestamp,
+    pub signals: Vec<signal::Signal>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encoding {
+    Json,
+    Binary,
+}
+
+impl Snapshot {
+    pub fn new(signals: Vec<signal::Signal>) -> Snapshot {
+        Snapshot {
+            format: SNAPSHOT_FORMAT.to_string(),
+            version: SNAPSHOT_VERSION,
+            created_at: signal::Timestamp::now(),
+            signals,
+        }
+    }
+
+    pub fn encode(&self, encoding: Encoding) -> Result<Vec<u8>> {
+        match encoding {
+            Encoding::Json => Ok(serde_json::to_vec_pretty(self)?),
+            Encoding::Binary => {
+                let mut bytes = BINARY_MAGIC.to_vec();
+                bytes.extend_from_slice(&self.version.to_be_bytes());
+                bytes.extend(encode_to_vec(self, standard())?);
+                Ok(bytes)
+            }
+        }
+    }
+
+    /// Reads a snapshot in either encoding, told apart by the binary header.
+    pub fn decode(bytes: &[u8]) -> Result<Snapshot> {
+        let snapshot: Snapshot = match bytes.strip_prefix(BINARY_MAGIC) {
+            Some(rest) => {
+                let (version, body) = rest.split_at_checked(4).ok_or_else(|| invalid("truncated header"))?;
+                check_version(u32::from_be_bytes(version.try_into().unwrap()))?;
+                decode_from_slice(body, standard()).map_err(|e| invalid(&e.to_string()))?.0
+            }
+            None => serde_json::from_slice(bytes).map_err(|e| invalid(&e.to_string()))?,
+        };
+        if snapshot.format != SNAPSHOT_FORMAT {
+            return Err(invalid(&format!("unknown format {:?}", snapshot.format)));
+        }
+        check_version(snapshot.version)?;
+        Ok(snapshot)
+    }
+}
+
+fn check_version(version: u32) -> Result<()> {
+    if version > SNAPSHOT_VERSION {
+        return Err(invalid(&format!(
+            "version {} is newer than the supported version {}",
+            version, SNAPSHOT_VERSION
+        )));
+    }
+    Ok(())
+}
+
+fn invalid(message: &str) -> VehicleShadowError {
+    VehicleShadowError::InvalidInput(format!("Invalid snapshot: {}", message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::signal::{Config, LeafType, Signal, State, Value, ValueType};
+
+    fn create_test_snapshot() -> Snapshot {
+        Snapshot::new(vec![Signal {
+            path: "Vehicle.Speed".to_string(),
+            id: 1,
+            state: State {
+                value: Value::Float(12.5),
+                capability: true,
+                availability: true,
+                sequence: 7,
+                sink_timestamp: Some(signal::Timestamp::now()),
+                ..Default::default()
+            },
+            config: Config {
+                leaf_type: LeafType::Sensor,
+                data_type: ValueType::TypeFloat,
+                unit: Some("km/h".to_string()),
+                ..Default::default()
+            },
+        }])
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let snapshot = create_test_snapshot();
+        for encoding in [Encoding::Json, Encoding::Binary] {
+            let decoded = Snapshot::decode(&snapshot.encode(encoding).unwrap()).unwrap();
+            assert_eq!(decoded.created_at, snapshot.created_at);
+            assert_eq!(decoded.signals[0].state.value, Value::Float(12.5));
+            assert_eq!(decoded.signals[0].config, snapshot.signals[0].config);
+        }
+    }
+
+    #[test]
+    fn test_rejects_unknown_snapshots() {
+        let mut newer = create_test_snapshot();
+        newer.version = SNAPSHOT_VERSION + 1;
+        assert!(Snapshot::decode(&newer.encode(Encoding::Json).unwrap()).is_err());
+        assert!(Snapshot::decode(&newer.encode(Encoding::Binary).unwrap()).is_err());
+        assert!(Snapshot::decode(b"{\"format\": \"other\"}").is_err());
+        assert!(Snapshot::decode(b"VSSS\0").is_err());
+    }
+}
@@ -1,37 +1,473 @@
+use crate::alert::{Alert, AlertRule, CompiledRule};
+use crate::path_pattern;
 use crate::signal;
 use crate::error::{Result, VehicleShadowError};
+use crate::expression::Expression;
+use crate::snapshot::Snapshot;
+use crate::vss_json_loader;
 use bincode::config::standard;
-use bincode::{decode_from_slice, encode_to_vec};
+use bincode::{decode_from_slice, encode_to_vec, Decode, Encode};
+use log::warn;
 use sled;
+use sled::Transactional;
+use sled::transaction::{ConflictableTransactionError, ConflictableTransactionResult, TransactionalTree};
+use std::collections::HashSet;
+use std::fmt;
+use std::sync::{Mutex, RwLock};
+use std::time::Duration;
+
+const HISTORY_TREE: &str = "history";
+const LOCKS_TREE: &str = "locks";
+// id (big endian) -> path
+const SIGNAL_IDS_TREE: &str = "signal_ids";
+const META_TREE: &str = "meta";
+const NEXT_ID_KEY: &str = "next_id";
+const SCHEMA_VERSION_KEY: &str = "schema_version";
+// Version of everything stored with bincode. Bump it whenever a stored
+// struct (Signal, State, Config, Lease, AlertRule, Alert) changes: a database
+// written with another version is dropped on open and reloaded from the VSS.
+const SCHEMA_VERSION: u32 = 10;
+// applied VSS overlays in order, re-applied on top of the VSS file at startup
+const OVERLAYS_TREE: &str = "overlays";
+const ALERT_RULES_TREE: &str = "alert_rules";
+// rule id -> last alert raised or cleared by the rule
+const ALERT_STATE_TREE: &str = "alert_state";
+const ALERT_LOG_TREE: &str = "alert_log";
+const MAX_ALERT_LOG_ENTRIES: usize = 10_000;
+// paths the shadow marked unavailable for not being written in time
+const STALE_TREE: &str = "stale";
+const DEFAULT_LOCK_TTL: Duration = Duration::from_secs(30);
 
 pub struct VehicleShadow {
     database: sled::Db,
+    history: sled::Tree,
+    history_config: HistoryConfig,
+    locks: sled::Tree,
+    signal_ids: sled::Tree,
+    meta: sled::Tree,
+    overlays: sled::Tree,
+    overlay_lock: Mutex<()>,
+    derived: RwLock<Vec<Derived>>,
+    alert_rules: sled::Tree,
+    alert_state: sled::Tree,
+    alert_log: sled::Tree,
+    compiled_rules: RwLock<Vec<CompiledRule>>,
+    stale: sled::Tree,
+    lock_ttl: Duration,
+    read_only: bool,
     config: bincode::config::Configuration,
 }
 
+/// Retention of past states per signal. History is recorded only when at
+/// least one bound is set.
+#[derive(Debug, Clone, Default)]
+pub struct HistoryConfig {
+    pub max_entries: Option<u64>,
+    pub max_age: Option<Duration>,
+}
+
+impl HistoryConfig {
+    pub fn is_enabled(&self) -> bool {
+        self.max_entries.is_some() || self.max_age.is_some()
+    }
+}
+
 impl VehicleShadow {
     pub fn create() -> Result<VehicleShadow> {
-        Ok(VehicleShadow {
-            database: sled::Config::new().temporary(true).open()?,
-            config: standard(),
-        })
+        Self::open(sled::Config::new().temporary(true).open()?)
     }
 
     pub fn create_with_path<P: AsRef<std::path::Path>>(path: P) -> Result<VehicleShadow> {
-        Ok(VehicleShadow {
-            database: sled::Config::new().path(path).open()?,
+        Self::open(sled::Config::new().path(path).open()?)
+    }
+
+    fn open(database: sled::Db) -> Result<VehicleShadow> {
+        let vehicle_shadow = VehicleShadow {
+            history: database.open_tree(HISTORY_TREE)?,
+            history_config: HistoryConfig::default(),
+            locks: database.open_tree(LOCKS_TREE)?,
+            signal_ids: database.open_tree(SIGNAL_IDS_TREE)?,
+            meta: database.open_tree(META_TREE)?,
+            overlays: database.open_tree(OVERLAYS_TREE)?,
+            overlay_lock: Mutex::new(()),
+            derived: RwLock::new(Vec::new()),
+            alert_rules: database.open_tree(ALERT_RULES_TREE)?,
+            alert_state: database.open_tree(ALERT_STATE_TREE)?,
+            alert_log: database.open_tree(ALERT_LOG_TREE)?,
+            compiled_rules: RwLock::new(Vec::new()),
+            stale: database.open_tree(STALE_TREE)?,
+            lock_ttl: DEFAULT_LOCK_TTL,
+            read_only: false,
+            database,
             config: standard(),
+        };
+        vehicle_shadow.check_schema()?;
+        vehicle_shadow.load_alert_rules()?;
+        Ok(vehicle_shadow)
+    }
+
+    // Stored data of another schema version cannot be decoded, so it is
+    // dropped and the caller reloads the signals from the VSS file. Overlays
+    // are kept: they are stored as their JSON source.
+    fn check_schema(&self) -> Result<()> {
+        let stored = match self.meta.get(SCHEMA_VERSION_KEY)? {
+            Some(value) => Some(self.decode::<u32>(&value)?),
+            None => None,
+        };
+        if stored == Some(SCHEMA_VERSION) {
+            return Ok(());
+        }
+        // The other trees are cleared even if no signal is stored, since they
+        // may still hold entries of the old layout.
+        if stored.is_some() || !self.database.is_empty() {
+            warn!(
+                "Dropping stored state written with schema version {:?} (current: {})",
+                stored, SCHEMA_VERSION
+            );
+        }
+        for tree in [
+            &*self.database,
+            &self.history,
+            &self.locks,
+            &self.signal_ids,
+            &self.meta,
+            &self.alert_rules,
+            &self.alert_state,
+            &self.alert_log,
+            &self.stale,
+        ] {
+            tree.clear()?;
+        }
+        self.meta.insert(SCHEMA_VERSION_KEY, encode_to_vec(SCHEMA_VERSION, self.config)?)?;
+        self.database.flush()?;
+        Ok(())
+    }
+
+    pub fn with_history(mut self, history_config: HistoryConfig) -> Self {
+        self.history_config = history_config;
+        self
+    }
+
+    pub fn with_lock_ttl(mut self, lock_ttl: Duration) -> Self {
+        self.lock_ttl = lock_ttl;
+        self
+    }
+
+    /// A read-only shadow rejects writes and locks; its signals only change
+    /// through [`VehicleShadow::store_replica`] and
+    /// [`VehicleShadow::apply_replica`].
+    pub fn with_read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Writes a new state for `signal.path`, stamping it with the next
+    /// sequence number and the time the shadow received it. Returns the
+    /// signal as stored. Without a token the write is refused while the
+    /// signal is locked; the lock is checked against the state being replaced.
+    pub fn set_signal(&self, signal: signal::Signal, token: &Option<String>) -> Result<signal::Signal> {
+        self.write_signal(&signal.path, token, true, |_| Ok(signal.clone()))
+    }
+
+    /// Applies `update` to the stored state of `path` and writes the result
+    /// like [`VehicleShadow::set_signal`]. A concurrent write to the same
+    /// signal makes `update` run again on the newer state, so no write is lost.
+    pub fn update_signal(
+        &self,
+        path: &str,
+        token: &Option<String>,
+        update: impl Fn(&mut signal::Signal) -> Result<()>,
+    ) -> Result<signal::Signal> {
+        self.modify_stored_signal(path, token, true, update)
+    }
+
+    fn modify_stored_signal(
+        &self,
+        path: &str,
+        token: &Option<String>,
+        respect_lock: bool,
+        update: impl Fn(&mut signal::Signal) -> Result<()>,
+    ) -> Result<signal::Signal> {
+        self.write_signal(path, token, respect_lock, |stored| {
+            let mut signal = stored.ok_or_else(|| not_found(path))?;
+            update(&mut signal)?;
+            Ok(signal)
         })
     }
 
-    pub fn set_signal(&self, signal: signal::Signal, token: &Option<String>) -> Result<()> {
-        let encoded = encode_to_vec(&signal, self.config)?;
-        if let Some(_) = token {
-            if self.get_signal(signal.path.clone())?.state.lock_uuid != token.clone() {
-                return Err(VehicleShadowError::Database(format!("Authentication failed.")))
+    /// Writes all `signals` in one transaction: either every signal is
+    /// stored or none is, and the error names the signal that failed.
+    pub fn set_signals(&self, signals: Vec<signal::Signal>, token: &Option<String>) -> Result<Vec<signal::Signal>> {
+        let paths: Vec<String> = signals.iter().map(|signal| signal.path.clone()).collect();
+        self.write_signals(&paths, token, true, |index, _| Ok(signals[index].clone()))
+    }
+
+    /// Like [`VehicleShadow::set_signals`], but applies `update` to the stored
+    /// state of each of `paths`. `update` gets the index of the path.
+    pub fn update_signals(
+        &self,
+        paths: &[String],
+        token: &Option<String>,
+        update: impl Fn(usize, &mut signal::Signal) -> Result<()>,
+    ) -> Result<Vec<signal::Signal>> {
+        self.modify_stored_signals(paths, token, true, update)
+    }
+
+    fn modify_stored_signals(
+        &self,
+        paths: &[String],
+        token: &Option<String>,
+        respect_lock: bool,
+        update: impl Fn(usize, &mut signal::Signal) -> Result<()>,
+    ) -> Result<Vec<signal::Signal>> {
+        self.write_signals(paths, token, respect_lock, |index, stored| {
+            let mut signal = stored.ok_or_else(|| not_found(&paths[index]))?;
+            update(index, &mut signal)?;
+            Ok(signal)
+        })
+    }
+
+    // A single signal is written with compare-and-swap rather than a
+    // transaction, because sled runs transactions one at a time. Locking or
+    // unlocking changes the stored signal, so the swap also fails when the
+    // lock changed after it was checked. `respect_lock` refuses writes
+    // without a token to a locked signal.
+    fn write_signal(
+        &self,
+        path: &str,
+        token: &Option<String>,
+        respect_lock: bool,
+        prepare: impl Fn(Option<signal::Signal>) -> Result<signal::Signal>,
+    ) -> Result<signal::Signal> {
+        if self.read_only {
+            return Err(VehicleShadowError::ReadOnly);
+        }
+        let now = signal::Timestamp::now();
+        let stored = self.modify_signal(path, |stored| {
+            let lease = match token {
+                Some(token) => self.get_lease(token)?,
+                None => None,
+            };
+            let stored_lease = match stored.as_ref().and_then(|s| s.state.lock_uuid.as_ref()) {
+                Some(lock_uuid) if respect_lock => self.get_lease(lock_uuid)?,
+                _ => None,
+            };
+            let stored = self.restore_availability(stored)?;
+            let signal = prepare(stored.clone())?;
+            prepare_write(signal, stored, lease, stored_lease, token, &now).map(Some)
+        })?;
+        let stored = stored.expect("write_signal always writes");
+        if self.stale.contains_key(path)? {
+            self.stale.remove(path)?;
+        }
+
+        if self.history_config.is_enabled() {
+            self.record_history(&stored)?;
+        }
+        Ok(stored)
+    }
+
+    fn write_signals(
+        &self,
+        paths: &[String],
+        token: &Option<String>,
+        respect_lock: bool,
+        prepare: impl Fn(usize, Option<signal::Signal>) -> Result<signal::Signal>,
+    ) -> Result<Vec<signal::Signal>> {
+        if self.read_only {
+            return Err(VehicleShadowError::ReadOnly);
+        }
+        let now = signal::Timestamp::now();
+        let stored_signals = (&*self.database, &self.locks, &self.stale).transaction(|(database, locks, stale)| {
+            let mut stored_signals = Vec::new();
+            for (index, path) in paths.iter().enumerate() {
+                let abort = |cause| {
+                    ConflictableTransactionError::Abort(VehicleShadowError::TransactionAborted {
+                        path: path.clone(),
+                        cause: Box::new(cause),
+                    })
+                };
+                let mut stored = match database.get(path)? {
+                    Some(value) => Some(self.decode::<signal::Signal>(&value).map_err(abort)?),
+                    None => None,
+                };
+                if stale.remove(path.as_bytes())?.is_some()
+                    && let Some(stored) = &mut stored
+                {
+                    stored.state.availability = true;
+                }
+                let lease = match token {
+                    Some(token) => match locks.get(token)? {
+                        Some(value) => Some(self.decode::<Lease>(&value).map_err(abort)?),
+                        None => None,
+                    },
+                    None => None,
+                };
+                let stored_lease = match stored.as_ref().and_then(|s| s.state.lock_uuid.as_ref()) {
+                    Some(lock_uuid) if respect_lock => match locks.get(lock_uuid)? {
+                        Some(value) => Some(self.decode::<Lease>(&value).map_err(abort)?),
+                        None => None,
+                    },
+                    _ => None,
+                };
+                let signal = prepare(index, stored.clone()).map_err(abort)?;
+                let signal = prepare_write(signal, stored, lease, stored_lease, token, &now).map_err(abort)?;
+                let encoded = encode_to_vec(&signal, self.config).map_err(|e| abort(e.into()))?;
+                database.insert(signal.path.as_bytes(), encoded)?;
+                stored_signals.push(signal);
+            }
+            Ok(stored_signals)
+        })?;
+
+        if self.history_config.is_enabled() {
+            for signal in &stored_signals {
+                self.record_history(signal)?;
+            }
+        }
+        Ok(stored_signals)
+    }
+
+    // A write to a signal marked stale makes it available again. This is done
+    // to the stored state, so the write itself can still set availability.
+    fn restore_availability(&self, mut stored: Option<signal::Signal>) -> Result<Option<signal::Signal>> {
+        if let Some(signal) = &mut stored
+            && self.stale.contains_key(&signal.path)?
+        {
+            signal.state.availability = true;
+        }
+        Ok(stored)
+    }
+
+    // Read-modify-write of one stored signal with compare-and-swap, retried
+    // whenever another writer changed the signal in between. `modify` gets
+    // the stored signal and returns None to leave it as is.
+    fn modify_signal(
+        &self,
+        path: &str,
+        modify: impl Fn(Option<signal::Signal>) -> Result<Option<signal::Signal>>,
+    ) -> Result<Option<signal::Signal>> {
+        loop {
+            let current = self.database.get(path)?;
+            let stored = match &current {
+                Some(value) => Some(self.decode::<signal::Signal>(value)?),
+                None => None,
             };
+            let Some(signal) = modify(stored)? else {
+                return Ok(None);
+            };
+            let encoded = encode_to_vec(&signal, self.config)?;
+            if self.database.compare_and_swap(path, current, Some(encoded))?.is_ok() {
+                return Ok(Some(signal));
+            }
         }
+    }
+
+    /// Requests a new value for an actuator. The current value is left to the
+    /// provider, which reports it through [`VehicleShadow::set_current`].
+    pub fn set_target(&self, path: &str, target: signal::Value, token: &Option<String>) -> Result<signal::Signal> {
+        self.update_signal(path, token, |signal| {
+            signal.state.target = Some(target.clone());
+            Ok(())
+        })
+    }
 
+    /// Stores the value an actuator (or sensor) provider actually observed.
+    pub fn set_current(
+        &self,
+        path: &str,
+        value: signal::Value,
+        source_timestamp: Option<signal::Timestamp>,
+        token: &Option<String>,
+    ) -> Result<signal::Signal> {
+        self.update_signal(path, token, |signal| {
+            signal.state.value = value.clone();
+            signal.state.source_timestamp = source_timestamp.clone();
+            Ok(())
+        })
+    }
+
+    /// Records `end_point` as the provider serving `paths` and marks them
+    /// available. All paths are attached or none is.
+    pub fn attach_provider(&self, paths: &[String], end_point: &str) -> Result<Vec<signal::Signal>> {
+        self.update_provider(paths, end_point, true)
+    }
+
+    /// Forgets the provider of `paths` and marks them unavailable until a
+    /// provider attaches again.
+    pub fn detach_provider(&self, paths: &[String]) -> Result<Vec<signal::Signal>> {
+        self.update_provider(paths, "", false)
+    }
+
+    // The provider owns its signals, so client locks do not apply.
+    fn update_provider(&self, paths: &[String], end_point: &str, availability: bool) -> Result<Vec<signal::Signal>> {
+        self.modify_stored_signals(paths, &None, false, |_, signal| {
+            signal.config.end_point = end_point.to_string();
+            signal.state.availability = availability;
+            Ok(())
+        })
+    }
+
+    /// Stores a value pushed by the provider of `path`. The provider owns the
+    /// signal, so client locks do not apply.
+    pub fn provide_value(
+        &self,
+        path: &str,
+        value: signal::Value,
+        source_timestamp: Option<signal::Timestamp>,
+    ) -> Result<signal::Signal> {
+        self.modify_stored_signal(path, &None, false, |signal| {
+            signal.state.value = value.clone();
+            signal.state.source_timestamp = source_timestamp.clone();
+            signal.state.availability = true;
+            Ok(())
+        })
+    }
+
+    /// Stores a signal copied from another shadow as is, keeping its sequence
+    /// number and timestamps.
+    /// The id is not copied: the signal keeps the id this shadow assigned.
+    pub fn store_replica(&self, signal: signal::Signal) -> Result<()> {
+        let stored = self.modify_signal(&signal.path, |stored| {
+            let id = stored.map_or(0, |stored| stored.id);
+            Ok(Some(signal::Signal { id, ..signal.clone() }))
+        })?;
+        let stored = stored.expect("store_replica always writes");
+        if self.history_config.is_enabled() {
+            self.record_history(&stored)?;
+        }
+        if stored.id == 0 {
+            self.assign_ids(&[stored.path])?;
+        }
+        Ok(())
+    }
+
+    /// Like [`VehicleShadow::store_replica`], but skips the signal unless it
+    /// is newer than the stored copy. Returns whether it was stored.
+    pub fn apply_replica(&self, signal: signal::Signal) -> Result<bool> {
+        let stored = self.modify_signal(&signal.path, |stored| {
+            let id = match stored {
+                Some(stored) if stored.state.sequence >= signal.state.sequence => return Ok(None),
+                Some(stored) => stored.id,
+                None => 0,
+            };
+            Ok(Some(signal::Signal { id, ..signal.clone() }))
+        })?;
+        let Some(stored) = stored else {
+            return Ok(false);
+        };
+        if self.history_config.is_enabled() {
+            self.record_history(&stored)?;
+        }
+        if stored.id == 0 {
+            self.assign_ids(&[stored.path])?;
+        }
+        Ok(true)
+    }
+
+    // Stores the signal as is. Used for bookkeeping writes such as locks that
+    // must not count as a new state.
+    fn store_signal(&self, signal: signal::Signal) -> Result<()> {
+        let encoded = encode_to_vec(&signal, self.config)?;
         self.database.insert(signal.path, encoded)?;
         Ok(())
     }
@@ -45,71 +481,299 @@ impl VehicleShadow {
             return Ok(signal);
         }
 
-        Err(VehicleShadowError::NotFound(format!("Signal not found: {}", path)))
+        Err(not_found(&path))
     }
 
-    pub fn try_locks(&self, paths: Vec<String>, lock_uuid: &String) -> Result<()>{
-        let is_locked = paths.iter().fold(false, |ret, path|{
-            if true == ret {
-                return true;
+    /// Expands wildcard patterns into the stored leaf paths they match.
+    /// Plain paths are passed through unchanged, whether or not they exist.
+    pub fn resolve_paths(&self, patterns: &[String]) -> Result<Vec<String>> {
+        let mut paths = Vec::new();
+        let mut seen = HashSet::new();
+        for pattern in patterns {
+            if !path_pattern::is_pattern(pattern) {
+                seen.insert(pattern.clone());
+                paths.push(pattern.clone());
+                continue;
+            }
+
+            for item in self.database.scan_prefix(path_pattern::literal_prefix(pattern)) {
+                let (key, _) = item?;
+                if let Ok(path) = String::from_utf8(key.to_vec())
+                    && path_pattern::matches(pattern, &path)
+                    && seen.insert(path.clone())
+                {
+                    paths.push(path);
+                }
             }
-            return self.is_locked(path.clone()).unwrap_or(true);
-        });
-        if true == is_locked {
-            return Err(VehicleShadowError::NotFound(format!("Some of signals are already locked: {:?}", paths)));
         }
+        Ok(paths)
+    }
+
+    /// Locks all `paths` under one lease identified by `lock_uuid`, or none of
+    /// them if any is already locked. The lease expires after `ttl` (or the
+    /// default lock TTL) unless renewed.
+    pub fn try_locks(
+        &self,
+        paths: Vec<String>,
+        lock_uuid: &str,
+        holder: &str,
+        ttl: Option<Duration>,
+    ) -> Result<Lease> {
+        if self.read_only {
+            return Err(VehicleShadowError::ReadOnly);
+        }
+        let paths = self.resolve_paths(&paths)?;
+        let mut lease = Lease {
+            token: lock_uuid.to_string(),
+            holder: holder.to_string(),
+            paths,
+            expires_at_ns: 0,
+            expires_at_monotonic_ns: 0,
+        };
+        lease.extend(ttl.unwrap_or(self.lock_ttl));
+        let now = signal::Timestamp::now();
 
+        (&*self.database, &self.locks).transaction(|(database, locks)| {
+            for path in &lease.paths {
+                let abort = ConflictableTransactionError::Abort;
+                let Some(value) = database.get(path)? else {
+                    return Err(abort(VehicleShadowError::NotFound(format!("Signal not found: {}", path))));
+                };
+                let mut signal = self.decode::<signal::Signal>(&value).map_err(abort)?;
+                if let Some(holder) = &signal.state.lock_uuid
+                    && let Some(held) = locks.get(holder.as_bytes())?
+                    && !self.decode::<Lease>(&held).map_err(abort)?.is_expired(&now)
+                {
+                    return Err(abort(VehicleShadowError::Database(format!("Signal already locked: {}", path))));
+                }
+                signal.state.lock_uuid = Some(lock_uuid.to_string());
+                let encoded = encode_to_vec(&signal, self.config).map_err(|e| abort(e.into()))?;
+                database.insert(path.as_bytes(), encoded)?;
+            }
+            let encoded = encode_to_vec(&lease, self.config).map_err(|e| ConflictableTransactionError::Abort(e.into()))?;
+            locks.insert(lease.token.as_bytes(), encoded)?;
+            Ok(())
+        })?;
+        Ok(lease)
+    }
+
+    pub fn renew_lock(&self, lock_uuid: &str, ttl: Option<Duration>) -> Result<Lease> {
+        let expired = || VehicleShadowError::NotFound(format!("Lock not found or expired: {}", lock_uuid));
+        loop {
+            // Compare-and-swap so that a lease released meanwhile is not revived
+            let current = self.locks.get(lock_uuid)?.ok_or_else(expired)?;
+            let mut lease: Lease = self.decode(&current)?;
+            if lease.is_expired(&signal::Timestamp::now()) {
+                return Err(expired());
+            }
+            lease.extend(ttl.unwrap_or(self.lock_ttl));
+            let encoded = encode_to_vec(&lease, self.config)?;
+            if self.locks.compare_and_swap(lock_uuid, Some(current), Some(encoded))?.is_ok() {
+                return Ok(lease);
+            }
+        }
+    }
+
+    /// Releases the lease `lock_uuid`, touching only the paths it holds.
+    pub fn release_lock(&self, lock_uuid: &str) -> Result<Lease> {
+        let lease = self
+            .locks
+            .remove(lock_uuid)?
+            .ok_or_else(|| VehicleShadowError::NotFound(format!("Lock not found: {}", lock_uuid)))?;
+        let lease: Lease = self.decode(&lease)?;
+        self.clear_lock(&lease.paths, lock_uuid)?;
+        Ok(lease)
+    }
+
+    // Clears the lock from `paths` that still hold `lock_uuid`, without
+    // counting as a new state.
+    fn clear_lock(&self, paths: &[String], lock_uuid: &str) -> Result<()> {
         for path in paths {
-            self.try_lock(path, lock_uuid)?;
+            self.modify_signal(path, |stored| {
+                Ok(stored
+                    .filter(|signal| signal.state.lock_uuid.as_deref() == Some(lock_uuid))
+                    .map(|mut signal| {
+                        signal.state.lock_uuid = None;
+                        signal
+                    }))
+            })?;
         }
         Ok(())
     }
 
-    pub fn try_lock(&self, path: String, lock_uuid: &String) -> Result<()>{
-        let is_locked= self.is_locked(path.clone())?;
-        if true == is_locked{
-            return Err(VehicleShadowError::Database(format!("Signal already locked: {}", path)))
+    /// Releases every lease holding one of `paths`, regardless of holder.
+    pub fn force_unlock(&self, paths: &[String]) -> Result<Vec<Lease>> {
+        let mut released = Vec::new();
+        for path in self.resolve_paths(paths)? {
+            let signal = self.get_signal(path.clone())?;
+            let Some(lock_uuid) = signal.state.lock_uuid else {
+                continue;
+            };
+            match self.release_lock(&lock_uuid) {
+                Ok(lease) => released.push(lease),
+                // lock left behind without a lease, e.g. by an older version
+                Err(VehicleShadowError::NotFound(_)) => self.clear_lock(&[path], &lock_uuid)?,
+                Err(e) => return Err(e),
+            }
         }
+        Ok(released)
+    }
 
-        let query_result = self.database.get(&path)?;
-        if let None = query_result {
-            return Err(VehicleShadowError::NotFound(format!("Signal not found: {}", path)));
+    pub fn release_expired_locks(&self) -> Result<Vec<Lease>> {
+        let now = signal::Timestamp::now();
+        let mut released = Vec::new();
+        for item in self.locks.iter() {
+            let (token, value) = item?;
+            let lease: Lease = self.decode(&value)?;
+            // A lease renewed after it was read is left alone
+            if lease.is_expired(&now)
+                && self.locks.compare_and_swap(token, Some(value), None::<Vec<u8>>)?.is_ok()
+            {
+                self.clear_lock(&lease.paths, &lease.token)?;
+                released.push(lease);
+            }
         }
-
-        let encoded_signal = query_result.unwrap();
-        let (mut signal, _len): (signal::Signal, usize) =
-            decode_from_slice(&encoded_signal, self.config)?;
-        assert_eq!(encoded_signal.len(), _len);
-        signal.state.lock_uuid = Some(lock_uuid.clone());
-        self.set_signal(signal, &None)
+        Ok(released)
     }
 
-    pub fn release_lock(&self, lock_uuid: &String) -> Result<()>{
+    /// Marks signals with an update period unavailable once they have not
+    /// been written for `periods` periods. The next write makes them
+    /// available again. Returns the signals marked. Like lease expiry, the
+    /// periods are measured on the monotonic clock, so a wall clock step
+    /// neither marks signals nor keeps them available.
+    pub fn mark_stale(&self, periods: u32) -> Result<Vec<signal::Signal>> {
+        if self.read_only {
+            return Ok(Vec::new());
+        }
+        let now = signal::Timestamp::now().monotonic_ns;
+        let is_stale = |signal: &signal::Signal| {
+            if signal.state.availability
+                && signal.config.expression.is_none()
+                && let Some(period) = signal.config.update_period
+                && let Some(written) = &signal.state.sink_timestamp
+            {
+                let timeout = period.saturating_mul(periods as u64).saturating_mul(1_000_000);
+                now.saturating_sub(written.monotonic_ns) > timeout
+            } else {
+                false
+            }
+        };
+
+        let mut marked = Vec::new();
         for item in self.database.iter() {
             let (_, value) = item?;
-            let (mut signal, _len): (signal::Signal, usize) = decode_from_slice(&value, self.config)?;
-            if Some(lock_uuid.clone()) == signal.state.lock_uuid {
-                signal.state.lock_uuid = None;
+            let signal: signal::Signal = self.decode(&value)?;
+            if !is_stale(&signal) {
+                continue;
+            }
+            // Marked before the signal changes, so a write racing with this
+            // one always finds the mark and restores availability.
+            self.stale.insert(signal.path.as_bytes(), &[])?;
+            let stored = self.modify_signal(&signal.path, |stored| {
+                let Some(mut signal) = stored.filter(|stored| is_stale(stored)) else {
+                    return Ok(None);
+                };
+                signal.state.availability = false;
+                signal.state.sequence += 1;
+                Ok(Some(signal))
+            })?;
+            match stored {
+                Some(signal) => {
+                    if self.history_config.is_enabled() {
+                        self.record_history(&signal)?;
+                    }
+                    marked.push(signal);
+                }
+                None => {
+                    self.stale.remove(signal.path.as_bytes())?;
+                }
             }
-            self.set_signal(signal, &None)?;
         }
-        Ok(())
+        Ok(marked)
+    }
+
+    pub fn list_locks(&self) -> Result<Vec<Lease>> {
+        let mut leases = Vec::new();
+        for item in self.locks.iter() {
+            let (_, value) = item?;
+            let (lease, _len): (Lease, usize) = decode_from_slice(&value, self.config)?;
+            leases.push(lease);
+        }
+        Ok(leases)
     }
 
+    /// A signal counts as locked only while its lease is alive, so a crashed
+    /// holder cannot keep it locked past the lease expiry.
     pub fn is_locked(&self, path: String) -> Result<bool>{
-        let query_result = self.database.get(&path)?;
-        if let None = query_result {
-            return Err(VehicleShadowError::NotFound(format!("Signal not found: {}", path)));
+        let signal = self.get_signal(path)?;
+        self.is_lease_alive(&signal.state.lock_uuid)
+    }
+
+    fn is_lease_alive(&self, lock_uuid: &Option<String>) -> Result<bool> {
+        let Some(lock_uuid) = lock_uuid else {
+            return Ok(false);
+        };
+        let now = signal::Timestamp::now();
+        Ok(self.get_lease(lock_uuid)?.is_some_and(|lease| !lease.is_expired(&now)))
+    }
+
+    fn get_lease(&self, lock_uuid: &str) -> Result<Option<Lease>> {
+        match self.locks.get(lock_uuid)? {
+            Some(value) => Ok(Some(self.decode(&value)?)),
+            None => Ok(None),
         }
+    }
 
-        let encoded_signal = query_result .unwrap();
-        let (signal, _len): (signal::Signal, usize) =
-            decode_from_slice(&encoded_signal, self.config)?;
-        assert_eq!(encoded_signal.len(), _len);
-        return if let Some(_) = signal.state.lock_uuid {
-            Ok(true)
-        } else {
-            Ok(false)
+    fn decode<T: Decode<()>>(&self, bytes: &[u8]) -> Result<T> {
+        let (decoded, _len): (T, usize) = decode_from_slice(bytes, self.config)?;
+        Ok(decoded)
+    }
+
+    /// Gives each of `paths` that has none yet the next unused id. Ids of
+    /// removed signals are not reused.
+    pub fn assign_ids(&self, paths: &[String]) -> Result<()> {
+        (&*self.database, &self.signal_ids, &self.meta).transaction(|(database, signal_ids, meta)| {
+            let abort = ConflictableTransactionError::Abort;
+            for path in paths {
+                let Some(value) = database.get(path)? else {
+                    return Err(abort(not_found(path)));
+                };
+                let mut signal = self.decode::<signal::Signal>(&value).map_err(abort)?;
+                if signal.id != 0 {
+                    continue;
+                }
+                signal.id = self.take_id(signal_ids, meta, path)?;
+                let encoded = encode_to_vec(&signal, self.config).map_err(|e| abort(e.into()))?;
+                database.insert(path.as_bytes(), encoded)?;
+            }
+            Ok(())
+        })?;
+        Ok(())
+    }
+
+    // Gives `path` the next unused id inside a transaction on the signal_ids
+    // and meta trees. The caller stores the signal with the id.
+    fn take_id(
+        &self,
+        signal_ids: &TransactionalTree,
+        meta: &TransactionalTree,
+        path: &str,
+    ) -> ConflictableTransactionResult<u32, VehicleShadowError> {
+        let abort = ConflictableTransactionError::Abort;
+        let id = match meta.get(NEXT_ID_KEY)? {
+            Some(value) => self.decode::<u32>(&value).map_err(abort)?,
+            None => 1,
+        };
+        let encoded = encode_to_vec(id + 1, self.config).map_err(|e| abort(e.into()))?;
+        meta.insert(NEXT_ID_KEY, encoded)?;
+        signal_ids.insert(&id.to_be_bytes(), path.as_bytes())?;
+        Ok(id)
+    }
+
+    pub fn path_of(&self, id: u32) -> Result<String> {
+        match self.signal_ids.get(id.to_be_bytes())? {
+            Some(path) => Ok(String::from_utf8_lossy(&path).into_owned()),
+            None => Err(VehicleShadowError::NotFound(format!("Signal id not found: {}", id))),
         }
     }
 
@@ -124,8 +788,144 @@ impl VehicleShadow {
         Ok(paths)
     }
 
-    pub fn delete_signal(&self, path: &str) -> Result<()> {
-        self.database.remove(path)?;
+    /// Returns the branches and leaves below `prefix` in depth-first order.
+    /// Only the direct children are listed unless `recursive` is set. An
+    /// empty prefix lists from the root and a leaf path lists just that leaf.
+    pub fn list_tree(&self, prefix: &str, recursive: bool) -> Result<Vec<SignalNode>> {
+        if !prefix.is_empty()
+            && let Some(value) = self.database.get(prefix)?
+        {
+            return Ok(vec![SignalNode::Leaf(Box::new(self.decode(&value)?))]);
+        }
+
+        let scan = if prefix.is_empty() {
+            String::new()
+        } else {
+            format!("{}.", prefix)
+        };
+        let mut nodes = Vec::new();
+        let mut branches = HashSet::new();
+        for item in self.database.scan_prefix(&scan) {
+            let (key, value) = item?;
+            let Ok(path) = String::from_utf8(key.to_vec()) else {
+                continue;
+            };
+            let segments: Vec<&str> = path[scan.len()..].split('.').collect();
+            let depth = if recursive { segments.len() - 1 } else { 1 };
+            for end in 1..=depth.min(segments.len() - 1) {
+                let branch = format!("{}{}", scan, segments[..end].join("."));
+                if branches.insert(branch.clone()) {
+                    nodes.push(SignalNode::Branch(branch));
+                }
+            }
+            if recursive || segments.len() == 1 {
+                nodes.push(SignalNode::Leaf(Box::new(self.decode(&value)?)));
+            }
+        }
+        if nodes.is_empty() && !prefix.is_empty() {
+            return Err(not_found(prefix));
+        }
+
+        nodes.sort_by(|a, b| SignalNode::compare_paths(a.path(), b.path()));
+        Ok(nodes)
+    }
+
+    pub fn all_signals(&self) -> Result<Vec<signal::Signal>> {
+        let mut signals = Vec::new();
+        for item in self.database.iter() {
+            let (_, value) = item?;
+            signals.push(self.decode(&value)?);
+        }
+        Ok(signals)
+    }
+
+    /// Removes `path` with its id and history. Returns the removed signal.
+    pub fn delete_signal(&self, path: &str) -> Result<Option<signal::Signal>> {
+        let mut removed = None;
+        if let Some(value) = self.database.remove(path)? {
+            let signal: signal::Signal = self.decode(&value)?;
+            self.signal_ids.remove(signal.id.to_be_bytes())?;
+            removed = Some(signal);
+        }
+        self.clear_history(path)?;
+        Ok(removed)
+    }
+
+    fn clear_history(&self, path: &str) -> Result<()> {
+        for item in self.history.scan_prefix(history_prefix(path)) {
+            let (key, _) = item?;
+            self.history.remove(key)?;
+        }
+        Ok(())
+    }
+
+    /// Returns past states of `path` in chronological order. `from` and `to`
+    /// bound the sink wall clock time in nanoseconds (inclusive), and `limit`
+    /// keeps only the most recent entries. Entries older than the maximum
+    /// age are left out, even if no later write has trimmed them yet.
+    pub fn get_history(
+        &self,
+        path: &str,
+        from: Option<u64>,
+        to: Option<u64>,
+        limit: Option<usize>,
+    ) -> Result<Vec<signal::State>> {
+        if !self.database.contains_key(path)? {
+            return Err(VehicleShadowError::NotFound(format!("Signal not found: {}", path)));
+        }
+        let cutoff = self.history_config.max_age.map(|max_age| {
+            signal::Timestamp::now()
+                .wall_clock_ns
+                .saturating_sub(max_age.as_nanos() as u64)
+        });
+        let from = from.into_iter().chain(cutoff).max();
+
+        let mut states = Vec::new();
+        for item in self.history.scan_prefix(history_prefix(path)).rev() {
+            if limit.is_some_and(|limit| states.len() >= limit) {
+                break;
+            }
+            let (_, value) = item?;
+            let (state, _len): (signal::State, usize) = decode_from_slice(&value, self.config)?;
+            let time = state.sink_timestamp.as_ref().map_or(0, |t| t.wall_clock_ns);
+            if to.is_some_and(|to| time > to) {
+                continue;
+            }
+            if from.is_some_and(|from| time < from) {
+                break;
+            }
+            states.push(state);
+        }
+        states.reverse();
+        Ok(states)
+    }
+
+    fn record_history(&self, signal: &signal::Signal) -> Result<()> {
+        let prefix = history_prefix(&signal.path);
+        let encoded = encode_to_vec(&signal.state, self.config)?;
+        self.history
+            .insert(history_key(&signal.path, signal.state.sequence), encoded)?;
+
+        if let Some(max_entries) = self.history_config.max_entries {
+            let oldest_kept = signal.state.sequence.saturating_sub(max_entries) + 1;
+            for item in self.history.range(prefix.clone()..history_key(&signal.path, oldest_kept)) {
+                let (key, _) = item?;
+                self.history.remove(key)?;
+            }
+        }
+
+        if let Some(max_age) = self.history_config.max_age {
+            let now = signal.state.sink_timestamp.as_ref().map_or(0, |t| t.wall_clock_ns);
+            let cutoff = now.saturating_sub(max_age.as_nanos() as u64);
+            for item in self.history.scan_prefix(&prefix) {
+                let (key, value) = item?;
+                let (state, _len): (signal::State, usize) = decode_from_slice(&value, self.config)?;
+                if state.sink_timestamp.is_some_and(|t| t.wall_clock_ns >= cutoff) {
+                    break;
+                }
+                self.history.remove(key)?;
+            }
+        }
         Ok(())
     }
 
@@ -139,8 +939,1604 @@ impl VehicleShadow {
         Ok(())
     }
 
+    pub fn export_snapshot(&self) -> Result<Snapshot> {
+        Ok(Snapshot::new(self.all_signals()?))
+    }
+
+    /// Replaces the whole tree with the signals of `snapshot` in one
+    /// transaction: either the snapshot is loaded completely or nothing
+    /// changes. Locks and staleness marks are dropped in the same
+    /// transaction, the history of removed signals right after it. Stored
+    /// signals keep their ids and their sequence numbers never go back, so
+    /// subscribers see the imported states as new ones.
+    pub fn import_snapshot(&self, snapshot: Snapshot) -> Result<TreeChanges> {
+        if self.read_only {
+            return Err(VehicleShadowError::ReadOnly);
+        }
+        for signal in &snapshot.signals {
+            if let Err(violation) = signal.config.validate(&signal.state.value) {
+                return Err(VehicleShadowError::ConstraintViolation {
+                    path: signal.path.clone(),
+                    violation,
+                });
+            }
+            if let Some(expression) = &signal.config.expression {
+                Expression::parse(expression)?;
+            }
+        }
+        let _guard = self.overlay_lock.lock().unwrap_or_else(|e| e.into_inner());
+
+        let now = signal::Timestamp::now();
+        let changes = self.reset_tree(&snapshot.signals, |signal, stored| {
+            let mut imported = signal.clone();
+            rebase_monotonic(&mut imported.state, &now);
+            if let Some(stored) = stored {
+                imported.state.sequence = imported.state.sequence.max(stored.state.sequence + 1);
+            }
+            imported
+        })?;
+        for signal in &changes.removed {
+            self.clear_history(&signal.path)?;
+        }
+        self.database.flush()?;
+        Ok(changes)
+    }
+
     pub fn clear(&self) -> Result<()> {
         self.database.clear()?;
+        self.history.clear()?;
+        self.locks.clear()?;
+        self.signal_ids.clear()?;
+        self.meta.clear()?;
+        self.overlays.clear()?;
+        self.alert_rules.clear()?;
+        self.alert_state.clear()?;
+        self.alert_log.clear()?;
+        self.stale.clear()?;
+        self.meta.insert(SCHEMA_VERSION_KEY, encode_to_vec(SCHEMA_VERSION, self.config)?)?;
+        self.load_alert_rules()
+    }
+
+    /// Applies a VSS overlay (see [`vss_json_loader::read_overlay`]) to the
+    /// running tree and records it, so it is applied again after a restart.
+    /// An overlay that does not fit the tree is rejected as a whole.
+    pub fn apply_overlay(&self, overlay_json: &str) -> Result<TreeChanges> {
+        if self.read_only {
+            return Err(VehicleShadowError::ReadOnly);
+        }
+        let _guard = self.overlay_lock.lock().unwrap_or_else(|e| e.into_inner());
+
+        let mut signals = self.all_signals()?;
+        vss_json_loader::read_overlay(overlay_json)
+            .and_then(|entries| vss_json_loader::apply_overlay(&mut signals, &entries))
+            .map_err(|e| VehicleShadowError::InvalidInput(e.to_string()))?;
+        let changes = self.update_tree(signals)?;
+
+        let key = self.database.generate_id()?.to_be_bytes();
+        self.overlays.insert(key, overlay_json.as_bytes())?;
+        self.database.flush()?;
+        Ok(changes)
+    }
+
+    /// Returns the recorded overlays in the order they were applied.
+    pub fn overlays(&self) -> Result<Vec<String>> {
+        let mut overlays = Vec::new();
+        for item in self.overlays.iter() {
+            let (_, value) = item?;
+            overlays.push(String::from_utf8_lossy(&value).into_owned());
+        }
+        Ok(overlays)
+    }
+
+    /// Applies the recorded overlays to `signals` freshly loaded from the VSS
+    /// file. Overlays that no longer apply are skipped; their errors are
+    /// returned.
+    pub fn apply_recorded_overlays(&self, signals: &mut Vec<signal::Signal>) -> Result<Vec<String>> {
+        let mut skipped = Vec::new();
+        for overlay in self.overlays()? {
+            let applied = vss_json_loader::read_overlay(&overlay)
+                .and_then(|entries| vss_json_loader::apply_overlay(signals, &entries));
+            if let Err(e) = applied {
+                skipped.push(e.to_string());
+            }
+        }
+        Ok(skipped)
+    }
+
+    /// Puts the values of the signals matching `patterns` back to their VSS
+    /// default (NAN without one) and clears their targets. All signals are
+    /// reset or none is; locked signals need the lock's `token`. Derived
+    /// signals are skipped, they follow their inputs.
+    pub fn reset_signals(&self, patterns: &[String], token: &Option<String>) -> Result<Vec<signal::Signal>> {
+        let mut paths = Vec::new();
+        for path in self.resolve_paths(patterns)? {
+            if self.get_signal(path.clone())?.config.expression.is_none() {
+                paths.push(path);
+            }
+        }
+        self.update_signals(&paths, token, |_, signal| {
+            signal.state.value = signal.config.default.clone().unwrap_or(signal::Value::NAN);
+            signal.state.target = None;
+            signal.state.source_timestamp = None;
+            Ok(())
+        })
+    }
+
+    /// Replaces the whole tree with `signals` as loaded at startup (the VSS
+    /// file with the recorded overlays applied), state included. Locks and
+    /// staleness marks are dropped together with the tree. History is
+    /// cleared once the tree is replaced, since sled cannot clear a tree
+    /// inside a transaction: states written in between are dropped from the
+    /// history as well. Sequence numbers keep counting and providers stay
+    /// attached. Returns the signals reset as `updated`.
+    pub fn factory_reset(&self, signals: Vec<signal::Signal>) -> Result<TreeChanges> {
+        if self.read_only {
+            return Err(VehicleShadowError::ReadOnly);
+        }
+        let _guard = self.overlay_lock.lock().unwrap_or_else(|e| e.into_inner());
+
+        let now = signal::Timestamp::now();
+        let changes = self.reset_tree(&signals, |signal, stored| {
+            let mut reset = signal.clone();
+            if let Some(stored) = stored {
+                reset.config.end_point = stored.config.end_point;
+                reset.state.sequence = stored.state.sequence + 1;
+                reset.state.sink_timestamp = Some(now.clone());
+            }
+            reset
+        })?;
+        self.history.clear()?;
+        self.database.flush()?;
+        Ok(changes)
+    }
+
+    // Replaces the whole tree with `signals` in one transaction and drops all
+    // locks and staleness marks with it. `merge` makes the signal to store
+    // from the new and the stored one; stored signals keep their id and new
+    // ones get the next unused id. History is left to the caller.
+    fn reset_tree(
+        &self,
+        signals: &[signal::Signal],
+        merge: impl Fn(&signal::Signal, Option<signal::Signal>) -> signal::Signal,
+    ) -> Result<TreeChanges> {
+        let paths: HashSet<&str> = signals.iter().map(|signal| signal.path.as_str()).collect();
+        let mut missing = self.list_signals()?;
+        missing.retain(|path| !paths.contains(path.as_str()));
+        // leases taken later hold a signal of the tree, they are found there
+        let mut tokens = Vec::new();
+        for item in self.locks.iter() {
+            let (token, _) = item?;
+            tokens.push(token);
+        }
+
+        let trees = (&*self.database, &self.signal_ids, &self.meta, &self.locks, &self.stale);
+        let changes = trees.transaction(|(database, signal_ids, meta, locks, stale)| {
+            let abort = ConflictableTransactionError::Abort;
+            let mut changes = TreeChanges::default();
+            for token in &tokens {
+                locks.remove(token)?;
+            }
+            for path in &missing {
+                if let Some(value) = database.remove(path.as_bytes())? {
+                    let signal = self.decode::<signal::Signal>(&value).map_err(abort)?;
+                    signal_ids.remove(&signal.id.to_be_bytes())?;
+                    if let Some(lock_uuid) = &signal.state.lock_uuid {
+                        locks.remove(lock_uuid.as_bytes())?;
+                    }
+                    stale.remove(path.as_bytes())?;
+                    changes.removed.push(signal);
+                }
+            }
+            for signal in signals {
+                let stored = match database.get(&signal.path)? {
+                    Some(value) => Some(self.decode::<signal::Signal>(&value).map_err(abort)?),
+                    None => None,
+                };
+                if let Some(lock_uuid) = stored.as_ref().and_then(|s| s.state.lock_uuid.as_ref()) {
+                    locks.remove(lock_uuid.as_bytes())?;
+                }
+                stale.remove(signal.path.as_bytes())?;
+                let id = stored.as_ref().map(|stored| stored.id);
+                let mut stored_signal = merge(signal, stored);
+                stored_signal.state.lock_uuid = None;
+                stored_signal.id = match id {
+                    Some(id) => id,
+                    None => self.take_id(signal_ids, meta, &signal.path)?,
+                };
+                let encoded = encode_to_vec(&stored_signal, self.config).map_err(|e| abort(e.into()))?;
+                database.insert(stored_signal.path.as_bytes(), encoded)?;
+                if id.is_some() {
+                    changes.updated.push(stored_signal);
+                } else {
+                    changes.added.push(stored_signal);
+                }
+            }
+            Ok(changes)
+        })?;
+        Ok(changes)
+    }
+
+    // Like reconcile, but safe while the shadow is serving: leaves whose
+    // config is unchanged are not written, so concurrent writes survive, and
+    // changed leaves keep their sequence number and lock.
+    fn update_tree(&self, signals: Vec<signal::Signal>) -> Result<TreeChanges> {
+        self.replace_tree(signals, |signal, stored| {
+            if stored.config == signal.config {
+                return None;
+            }
+            let mut updated = signal.clone();
+            updated.id = stored.id;
+            if updated.config.validate(&stored.state.value).is_ok() {
+                updated.state = stored.state;
+            } else {
+                updated.state.sequence = stored.state.sequence;
+                updated.state.lock_uuid = stored.state.lock_uuid;
+            }
+            Some(updated)
+        })
+    }
+
+    // Stores `signals` as the whole tree: new leaves are added as is, stored
+    // ones are replaced by what `merge` makes of the new and the stored
+    // signal (None leaves them), and the rest is removed.
+    fn replace_tree(
+        &self,
+        signals: Vec<signal::Signal>,
+        merge: impl Fn(&signal::Signal, signal::Signal) -> Option<signal::Signal>,
+    ) -> Result<TreeChanges> {
+        let mut changes = TreeChanges::default();
+        let mut paths = HashSet::new();
+
+        for signal in signals {
+            paths.insert(signal.path.clone());
+            let existed = self.database.contains_key(&signal.path)?;
+            let stored = self.modify_signal(&signal.path, |stored| {
+                Ok(match stored {
+                    Some(stored) => merge(&signal, stored),
+                    None => Some(signal::Signal { id: 0, ..signal.clone() }),
+                })
+            })?;
+            match stored {
+                Some(stored) if existed => changes.updated.push(stored),
+                Some(stored) => changes.added.push(stored),
+                None => {}
+            }
+        }
+
+        for signal in self.all_signals()? {
+            if !paths.contains(&signal.path) {
+                self.delete_signal(&signal.path)?;
+                changes.removed.push(signal);
+            }
+        }
+
+        let added: Vec<String> = changes.added.iter().map(|signal| signal.path.clone()).collect();
+        self.assign_ids(&added)?;
+        for signal in &mut changes.added {
+            signal.id = self.get_signal(signal.path.clone())?.id;
+        }
+        Ok(changes)
+    }
+
+    /// Re-reads the expressions of derived signals and evaluates all of them.
+    /// Call after the tree has been loaded or changed. Returns the derived
+    /// signals whose state changed.
+    pub fn refresh_derived(&self) -> Result<Vec<signal::Signal>> {
+        let mut derived = Vec::new();
+        for signal in self.all_signals()? {
+            if let Some(expression) = &signal.config.expression {
+                derived.push(Derived {
+                    path: signal.path.clone(),
+                    expression: Expression::parse(expression)?,
+                });
+            }
+        }
+        *self.derived.write().unwrap_or_else(|e| e.into_inner()) = derived;
+
+        // mirrors replicate derived signals as computed upstream
+        if self.read_only {
+            return Ok(Vec::new());
+        }
+        let paths = self.list_signals()?;
+        self.evaluate_derived(&paths)
+    }
+
+    /// Evaluates the derived signals that read one of `changed`, and the
+    /// ones reading those in turn. Returns the derived signals whose state
+    /// changed, each once with its last state.
+    pub fn update_derived(&self, changed: &[signal::Signal]) -> Result<Vec<signal::Signal>> {
+        if self.read_only {
+            return Ok(Vec::new());
+        }
+        let paths: Vec<String> = changed.iter().map(|signal| signal.path.clone()).collect();
+        self.evaluate_derived(&paths)
+    }
+
+    fn evaluate_derived(&self, changed: &[String]) -> Result<Vec<signal::Signal>> {
+        let derived = self.derived.read().unwrap_or_else(|e| e.into_inner());
+        let mut written: Vec<signal::Signal> = Vec::new();
+        let mut changed = changed.to_vec();
+        // a derived signal may read other derived signals; a cycle stops
+        // after every signal had its turn
+        for _ in 0..=derived.len() {
+            let mut next = Vec::new();
+            for derived in derived.iter() {
+                if !changed.iter().any(|path| derived.expression.depends_on(path)) {
+                    continue;
+                }
+                if let Some(signal) = self.evaluate(derived)? {
+                    written.retain(|written| written.path != signal.path);
+                    next.push(signal.path.clone());
+                    written.push(signal);
+                }
+            }
+            if next.is_empty() {
+                break;
+            }
+            changed = next;
+        }
+        Ok(written)
+    }
+
+    // Inputs are read inside the compare-and-swap loop, so of two concurrent
+    // evaluations the one that stores last has seen the latest inputs.
+    fn evaluate(&self, derived: &Derived) -> Result<Option<signal::Signal>> {
+        let now = signal::Timestamp::now();
+        let lookup = |pattern: &str| self.input_values(pattern);
+        let stored = self.modify_signal(&derived.path, |stored| {
+            let Some(mut signal) = stored else {
+                return Ok(None);
+            };
+            let value = derived
+                .expression
+                .evaluate(&lookup)?
+                .and_then(|result| result.to_value(&signal.config.data_type))
+                .filter(|value| signal.config.validate(value).is_ok());
+            let availability = value.is_some();
+            let value = value.unwrap_or_else(|| signal.state.value.clone());
+            if signal.state.value == value && signal.state.availability == availability {
+                return Ok(None);
+            }
+            signal.state.value = value;
+            signal.state.availability = availability;
+            signal.state.sequence += 1;
+            signal.state.sink_timestamp = Some(now.clone());
+            Ok(Some(signal))
+        })?;
+
+        if let Some(signal) = &stored
+            && self.history_config.is_enabled()
+        {
+            self.record_history(signal)?;
+        }
+        Ok(stored)
+    }
+
+    fn input_values(&self, pattern: &str) -> Result<Vec<signal::Value>> {
+        let mut values = Vec::new();
+        for path in self.resolve_paths(&[pattern.to_string()])? {
+            match self.get_signal(path) {
+                Ok(signal) => values.push(signal.state.value),
+                Err(VehicleShadowError::NotFound(_)) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(values)
+    }
+
+    /// Stores `rule`, replacing the rule with the same id, and evaluates it
+    /// right away. Returns the alert it raised or cleared, if any.
+    pub fn put_alert_rule(&self, rule: AlertRule) -> Result<Option<Alert>> {
+        if self.read_only {
+            return Err(VehicleShadowError::ReadOnly);
+        }
+        let compiled = CompiledRule::compile(rule)?;
+        let encoded = encode_to_vec(&compiled.rule, self.config)?;
+        self.alert_rules.insert(compiled.rule.id.as_bytes(), encoded)?;
+        self.load_alert_rules()?;
+        self.evaluate_rule(&compiled)
+    }
+
+    /// Removes the rule `id`. Returns the alert cleared by removing it, if
+    /// it was active.
+    pub fn remove_alert_rule(&self, id: &str) -> Result<Option<Alert>> {
+        if self.read_only {
+            return Err(VehicleShadowError::ReadOnly);
+        }
+        let Some(value) = self.alert_rules.remove(id)? else {
+            return Err(VehicleShadowError::NotFound(format!("Alert rule not found: {}", id)));
+        };
+        let rule: AlertRule = self.decode(&value)?;
+        self.load_alert_rules()?;
+        // the state is kept so the sequence goes on if the rule comes back
+        self.change_alert(&rule, |active| Ok(active.then_some(false)))
+    }
+
+    pub fn alert_rules(&self) -> Result<Vec<AlertRule>> {
+        let mut rules = Vec::new();
+        for item in self.alert_rules.iter() {
+            let (_, value) = item?;
+            rules.push(self.decode(&value)?);
+        }
+        Ok(rules)
+    }
+
+    pub fn active_alerts(&self) -> Result<Vec<Alert>> {
+        let mut alerts = Vec::new();
+        for item in self.alert_state.iter() {
+            let (_, value) = item?;
+            let alert: Alert = self.decode(&value)?;
+            if alert.active {
+                alerts.push(alert);
+            }
+        }
+        Ok(alerts)
+    }
+
+    /// Returns up to `limit` of the most recent alerts raised or cleared, in
+    /// chronological order.
+    pub fn alert_log(&self, limit: usize) -> Result<Vec<Alert>> {
+        let mut alerts = Vec::new();
+        for item in self.alert_log.iter().rev().take(limit) {
+            let (_, value) = item?;
+            alerts.push(self.decode(&value)?);
+        }
+        alerts.reverse();
+        Ok(alerts)
+    }
+
+    /// Evaluates the rules that read one of `changed`. Returns the alerts
+    /// raised or cleared.
+    pub fn update_alerts(&self, changed: &[signal::Signal]) -> Result<Vec<Alert>> {
+        let compiled_rules = self.compiled_rules.read().unwrap_or_else(|e| e.into_inner());
+        let mut alerts = Vec::new();
+        for compiled in compiled_rules.iter() {
+            if changed.iter().any(|signal| compiled.depends_on(&signal.path))
+                && let Some(alert) = self.evaluate_rule(compiled)?
+            {
+                alerts.push(alert);
+            }
+        }
+        Ok(alerts)
+    }
+
+    fn load_alert_rules(&self) -> Result<()> {
+        let compiled_rules = self
+            .alert_rules()?
+            .into_iter()
+            .map(CompiledRule::compile)
+            .collect::<Result<Vec<_>>>()?;
+        *self.compiled_rules.write().unwrap_or_else(|e| e.into_inner()) = compiled_rules;
         Ok(())
     }
+
+    fn evaluate_rule(&self, compiled: &CompiledRule) -> Result<Option<Alert>> {
+        let lookup = |pattern: &str| self.input_values(pattern);
+        self.change_alert(&compiled.rule, |active| {
+            let expression = if active { &compiled.hold } else { &compiled.raise };
+            Ok(expression
+                .evaluate(&lookup)?
+                .map(|result| result.truth())
+                .filter(|holds| *holds != active))
+        })
+    }
+
+    // Compare-and-swap loop on the state of `rule`: `decide` gets whether the
+    // alert is active and returns the new activity, or None to leave it.
+    fn change_alert(
+        &self,
+        rule: &AlertRule,
+        decide: impl Fn(bool) -> Result<Option<bool>>,
+    ) -> Result<Option<Alert>> {
+        loop {
+            let current = self.alert_state.get(&rule.id)?;
+            let state = match &current {
+                Some(value) => Some(self.decode::<Alert>(value)?),
+                None => None,
+            };
+            let Some(active) = decide(state.as_ref().is_some_and(|state| state.active))? else {
+                return Ok(None);
+            };
+            let value = match self.get_signal(rule.path.clone()) {
+                Ok(signal) => signal.state.value,
+                Err(_) => signal::Value::NAN,
+            };
+            let alert = Alert {
+                rule_id: rule.id.clone(),
+                active,
+                sequence: state.map_or(0, |state| state.sequence) + 1,
+                timestamp: signal::Timestamp::now(),
+                severity: rule.severity,
+                message: rule.message.clone(),
+                value,
+            };
+            let encoded = encode_to_vec(&alert, self.config)?;
+            if self
+                .alert_state
+                .compare_and_swap(&rule.id, current, Some(encoded.clone()))?
+                .is_ok()
+            {
+                self.alert_log.insert(self.database.generate_id()?.to_be_bytes(), encoded)?;
+                if let Some(item) = self.alert_log.iter().rev().nth(MAX_ALERT_LOG_ENTRIES) {
+                    let (newest_dropped, _) = item?;
+                    for item in self.alert_log.range(..=newest_dropped) {
+                        let (key, _) = item?;
+                        self.alert_log.remove(key)?;
+                    }
+                }
+                return Ok(Some(alert));
+            }
+        }
+    }
+
+    /// Merges freshly loaded VSS signals into an already populated database.
+    ///
+    /// Stored state survives as long as it satisfies the new config, config
+    /// metadata is always taken from `signals`, and stored leaves that are no
+    /// longer part of the VSS tree are removed. Locks never survive a restart
+    /// because their holders are gone.
+    pub fn reconcile(&self, signals: Vec<signal::Signal>) -> Result<ReconcileReport> {
+        let mut report = ReconcileReport::default();
+        let mut loaded_paths = HashSet::new();
+        let now = signal::Timestamp::now();
+
+        for mut signal in signals {
+            loaded_paths.insert(signal.path.clone());
+            match self.get_signal(signal.path.clone()) {
+                Ok(stored) => {
+                    if stored.config == signal.config {
+                        report.unchanged += 1;
+                    } else {
+                        report.updated.push(signal.path.clone());
+                    }
+                    signal.id = stored.id;
+                    if signal.config.validate(&stored.state.value).is_ok() {
+                        signal.state = stored.state;
+                        signal.state.lock_uuid = None;
+                        rebase_monotonic(&mut signal.state, &now);
+                    } else {
+                        // the sequence starts over, so the old history keys
+                        // would collide with the new ones
+                        self.clear_history(&signal.path)?;
+                    }
+                }
+                Err(VehicleShadowError::NotFound(_)) => report.added.push(signal.path.clone()),
+                Err(e) => return Err(e),
+            }
+            self.store_signal(signal)?;
+        }
+
+        self.locks.clear()?;
+        for path in self.list_signals()? {
+            if !loaded_paths.contains(&path) {
+                self.delete_signal(&path)?;
+                report.retired.push(path);
+            }
+        }
+        self.assign_ids(&report.added)?;
+
+        self.database.flush()?;
+        Ok(report)
+    }
+}
+
+/// A node of the signal tree. Branches are not stored; they are derived from
+/// the paths of the leaves below them.
+#[derive(Debug, Clone)]
+pub enum SignalNode {
+    Branch(String),
+    Leaf(Box<signal::Signal>),
+}
+
+impl SignalNode {
+    pub fn path(&self) -> &str {
+        match self {
+            SignalNode::Branch(path) => path,
+            SignalNode::Leaf(signal) => &signal.path,
+        }
+    }
+
+    /// Orders paths segment by segment, so a branch is followed by everything
+    /// below it before its next sibling.
+    pub fn compare_paths(a: &str, b: &str) -> std::cmp::Ordering {
+        a.split('.').cmp(b.split('.'))
+    }
+}
+
+// A signal computed from others, see `signal::Config::expression`.
+struct Derived {
+    path: String,
+    expression: Expression,
+}
+
+/// A lock held on a set of signals until it expires. `expires_at_ns` (UNIX
+/// time) is what clients are told; whether the lease has expired is decided
+/// on the monotonic clock, so a wall clock step neither expires nor extends
+/// leases. Leases do not survive a restart, which resets that clock.
+#[derive(Encode, Decode, Debug, Clone, PartialEq)]
+pub struct Lease {
+    pub token: String,
+    pub holder: String,
+    pub paths: Vec<String>,
+    pub expires_at_ns: u64,
+    pub expires_at_monotonic_ns: u64,
+}
+
+impl Lease {
+    pub fn is_expired(&self, now: &signal::Timestamp) -> bool {
+        now.monotonic_ns >= self.expires_at_monotonic_ns
+    }
+
+    pub fn time_left(&self, now: &signal::Timestamp) -> Duration {
+        Duration::from_nanos(self.expires_at_monotonic_ns.saturating_sub(now.monotonic_ns))
+    }
+
+    fn extend(&mut self, ttl: Duration) {
+        let now = signal::Timestamp::now();
+        let ttl = ttl.as_nanos() as u64;
+        self.expires_at_ns = now.wall_clock_ns.saturating_add(ttl);
+        self.expires_at_monotonic_ns = now.monotonic_ns.saturating_add(ttl);
+    }
+}
+
+// Monotonic timestamps only compare within one run. A state written by an
+// earlier run or by another shadow counts as written at `now`, so the
+// staleness of restored and imported signals is measured from then.
+fn rebase_monotonic(state: &mut signal::State, now: &signal::Timestamp) {
+    if let Some(written) = &mut state.sink_timestamp {
+        written.monotonic_ns = now.monotonic_ns;
+    }
+}
+
+// Checks and stamps one signal write against the currently stored signal and
+// the caller's lease. `stored_lease` is the lease of the stored lock, given
+// when a write without a token must not touch a locked signal. The stored
+// lock is kept so a write cannot undo a concurrent lock or unlock.
+fn prepare_write(
+    mut signal: signal::Signal,
+    stored: Option<signal::Signal>,
+    lease: Option<Lease>,
+    stored_lease: Option<Lease>,
+    token: &Option<String>,
+    now: &signal::Timestamp,
+) -> Result<signal::Signal> {
+    if let Err(violation) = signal.config.validate(&signal.state.value) {
+        return Err(VehicleShadowError::ConstraintViolation {
+            path: signal.path,
+            violation,
+        });
+    }
+    if let Some(target) = &signal.state.target {
+        if signal.config.leaf_type != signal::LeafType::Actuator {
+            return Err(VehicleShadowError::InvalidInput(format!(
+                "Only actuators have a target value: {}",
+                signal.path
+            )));
+        }
+        if let Err(violation) = signal.config.validate(target) {
+            return Err(VehicleShadowError::ConstraintViolation {
+                path: signal.path,
+                violation,
+            });
+        }
+    }
+
+    if stored.as_ref().is_some_and(|s| s.config.expression.is_some()) {
+        return Err(VehicleShadowError::InvalidInput(format!(
+            "Derived signal is read-only: {}",
+            signal.path
+        )));
+    }
+
+    if token.is_none() && stored_lease.is_some_and(|lease| !lease.is_expired(now)) {
+        return Err(VehicleShadowError::Database(format!("Signal locked: {}", signal.path)));
+    }
+    let stored_lock = stored.as_ref().and_then(|s| s.state.lock_uuid.clone());
+    if token.is_some() {
+        let holds_lock = stored_lock == *token
+            && lease.is_some_and(|lease| !lease.is_expired(now));
+        if !holds_lock {
+            return Err(VehicleShadowError::Database("Authentication failed.".to_string()));
+        }
+    }
+
+    signal.state.lock_uuid = stored_lock;
+    signal.id = stored.as_ref().map_or(signal.id, |s| s.id);
+    signal.state.sequence = stored.map_or(0, |s| s.state.sequence) + 1;
+    signal.state.sink_timestamp = Some(now.clone());
+    Ok(signal)
+}
+
+fn not_found(path: &str) -> VehicleShadowError {
+    VehicleShadowError::NotFound(format!("Signal not found: {}", path))
+}
+
+// History keys are `<path>\0<sequence as big endian>` so that one signal's
+// entries are contiguous and ordered by sequence.
+fn history_prefix(path: &str) -> Vec<u8> {
+    let mut prefix = path.as_bytes().to_vec();
+    prefix.push(0);
+    prefix
+}
+
+fn history_key(path: &str, sequence: u64) -> Vec<u8> {
+    let mut key = history_prefix(path);
+    key.extend_from_slice(&sequence.to_be_bytes());
+    key
+}
+
+/// Signals added, changed and removed by [`VehicleShadow::apply_overlay`],
+/// as stored (or last stored, for removed ones).
+#[derive(Debug, Default)]
+pub struct TreeChanges {
+    pub added: Vec<signal::Signal>,
+    pub updated: Vec<signal::Signal>,
+    pub removed: Vec<signal::Signal>,
+}
+
+#[derive(Debug, Default)]
+pub struct ReconcileReport {
+    pub added: Vec<String>,
+    pub updated: Vec<String>,
+    pub retired: Vec<String>,
+    pub unchanged: usize,
+}
+
+impl fmt::Display for ReconcileReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "added: {}, updated: {}, retired: {}, unchanged: {}",
+            self.added.len(),
+            self.updated.len(),
+            self.retired.len(),
+            self.unchanged
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::signal::{Config, LeafType, Signal, State, Value, ValueType};
+
+    fn create_test_signal(path: &str, data_type: ValueType, value: Value) -> Signal {
+        Signal {
+            path: path.to_string(),
+            id: 0,
+            state: State {
+                value,
+                capability: false,
+                availability: false,
+                lock_uuid: None,
+                reserved: String::new(),
+                sequence: 0,
+                source_timestamp: None,
+                sink_timestamp: None,
+                target: None,
+            },
+            config: Config {
+                leaf_type: LeafType::Sensor,
+                data_type,
+                deprecation: None,
+                unit: None,
+                min: None,
+                max: None,
+                description: None,
+                comment: None,
+                allowd: None,
+                default: None,
+                end_point: String::new(),
+                expression: None,
+                update_period: None,
+            },
+        }
+    }
+
+    #[test]
+    fn test_reconcile_keeps_state_and_retires_removed_signals() {
+        let vehicle_shadow = VehicleShadow::create().unwrap();
+        let mut speed = create_test_signal("Vehicle.Speed", ValueType::TypeFloat, Value::Float(42.0));
+        speed.state.lock_uuid = Some("stale".to_string());
+        vehicle_shadow.set_signal(speed, &None).unwrap();
+        vehicle_shadow
+            .set_signal(create_test_signal("Vehicle.Old", ValueType::TypeBool, Value::Bool(true)), &None)
+            .unwrap();
+
+        let mut reloaded_speed = create_test_signal("Vehicle.Speed", ValueType::TypeFloat, Value::NAN);
+        reloaded_speed.config.unit = Some("km/h".to_string());
+        let report = vehicle_shadow
+            .reconcile(vec![
+                reloaded_speed,
+                create_test_signal("Vehicle.New", ValueType::TypeInt32, Value::Int32(1)),
+            ])
+            .unwrap();
+
+        assert_eq!(report.added, vec!["Vehicle.New".to_string()]);
+        assert_eq!(report.updated, vec!["Vehicle.Speed".to_string()]);
+        assert_eq!(report.retired, vec!["Vehicle.Old".to_string()]);
+
+        let speed = vehicle_shadow.get_signal("Vehicle.Speed".to_string()).unwrap();
+        assert_eq!(speed.state.value, Value::Float(42.0));
+        assert_eq!(speed.state.lock_uuid, None);
+        assert_eq!(speed.config.unit, Some("km/h".to_string()));
+        assert!(vehicle_shadow.get_signal("Vehicle.Old".to_string()).is_err());
+    }
+
+    #[test]
+    fn test_reconcile_drops_history_of_reset_state() {
+        let vehicle_shadow = VehicleShadow::create().unwrap().with_history(HistoryConfig {
+            max_entries: Some(3),
+            max_age: None,
+        });
+        for speed in 0..5 {
+            let signal = create_test_signal("Vehicle.Speed", ValueType::TypeFloat, Value::Float(speed as f32));
+            vehicle_shadow.set_signal(signal, &None).unwrap();
+        }
+
+        // the stored float does not fit the new type, so the state is reset
+        let retyped = create_test_signal("Vehicle.Speed", ValueType::TypeBool, Value::NAN);
+        vehicle_shadow.reconcile(vec![retyped]).unwrap();
+        assert!(vehicle_shadow.get_history("Vehicle.Speed", None, None, None).unwrap().is_empty());
+
+        vehicle_shadow
+            .update_signal("Vehicle.Speed", &None, |signal| {
+                signal.state.value = Value::Bool(true);
+                Ok(())
+            })
+            .unwrap();
+        let history = vehicle_shadow.get_history("Vehicle.Speed", None, None, None).unwrap();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].value, Value::Bool(true));
+    }
+
+    #[test]
+    fn test_signal_ids_are_stable() {
+        let vehicle_shadow = VehicleShadow::create().unwrap();
+        let signals = vec![
+            create_test_signal("Vehicle.Speed", ValueType::TypeFloat, Value::Float(0.0)),
+            create_test_signal("Vehicle.Rpm", ValueType::TypeUint32, Value::Uint32(0)),
+        ];
+        let paths: Vec<String> = signals.iter().map(|signal| signal.path.clone()).collect();
+        for signal in signals.clone() {
+            vehicle_shadow.set_signal(signal, &None).unwrap();
+        }
+        vehicle_shadow.assign_ids(&paths).unwrap();
+        let speed = vehicle_shadow.get_signal("Vehicle.Speed".to_string()).unwrap();
+        assert_eq!((speed.id, vehicle_shadow.path_of(speed.id).unwrap()), (1, speed.path.clone()));
+
+        // writes and reloads keep the id; removed ids are not reused
+        let mut speed = vehicle_shadow.set_signal(speed, &None).unwrap();
+        assert_eq!(speed.id, 1);
+        speed.id = 0;
+        vehicle_shadow
+            .reconcile(vec![
+                signals[0].clone(),
+                create_test_signal("Vehicle.Gear", ValueType::TypeInt8, Value::Int8(0)),
+            ])
+            .unwrap();
+        assert_eq!(vehicle_shadow.get_signal("Vehicle.Speed".to_string()).unwrap().id, 1);
+        assert_eq!(vehicle_shadow.get_signal("Vehicle.Gear".to_string()).unwrap().id, 3);
+        assert!(vehicle_shadow.path_of(2).is_err());
+
+        // replicas keep the id assigned by this shadow
+        vehicle_shadow.store_replica(speed.clone()).unwrap();
+        speed.state.sequence += 1;
+        speed.id = 42;
+        assert!(vehicle_shadow.apply_replica(speed).unwrap());
+        assert_eq!(vehicle_shadow.get_signal("Vehicle.Speed".to_string()).unwrap().id, 1);
+    }
+
+    #[test]
+    fn test_set_signal_rejects_constraint_violation() {
+        let vehicle_shadow = VehicleShadow::create().unwrap();
+        let mut speed = create_test_signal("Vehicle.Speed", ValueType::TypeFloat, Value::Float(0.0));
+        speed.config.max = Some(Value::Float(250.0));
+        vehicle_shadow.set_signal(speed.clone(), &None).unwrap();
+
+        speed.state.value = Value::String("fast".to_string());
+        assert!(matches!(
+            vehicle_shadow.set_signal(speed.clone(), &None),
+            Err(VehicleShadowError::ConstraintViolation { .. })
+        ));
+
+        speed.state.value = Value::Float(300.0);
+        assert!(matches!(
+            vehicle_shadow.set_signal(speed, &None),
+            Err(VehicleShadowError::ConstraintViolation { .. })
+        ));
+
+        let stored = vehicle_shadow.get_signal("Vehicle.Speed".to_string()).unwrap();
+        assert_eq!(stored.state.value, Value::Float(0.0));
+    }
+
+    #[test]
+    fn test_set_signal_stamps_sequence_and_time() {
+        let vehicle_shadow = VehicleShadow::create().unwrap();
+        let speed = create_test_signal("Vehicle.Speed", ValueType::TypeFloat, Value::Float(0.0));
+        vehicle_shadow.set_signal(speed.clone(), &None).unwrap();
+        let first = vehicle_shadow.get_signal("Vehicle.Speed".to_string()).unwrap();
+
+        vehicle_shadow
+            .try_locks(vec!["Vehicle.Speed".to_string()], "token", "test", None)
+            .unwrap();
+        let mut speed = vehicle_shadow.get_signal("Vehicle.Speed".to_string()).unwrap();
+        speed.state.value = Value::Float(10.0);
+        vehicle_shadow.set_signal(speed, &Some("token".to_string())).unwrap();
+        let second = vehicle_shadow.get_signal("Vehicle.Speed".to_string()).unwrap();
+
+        assert_eq!(first.state.sequence, 1);
+        assert_eq!(second.state.sequence, 2);
+        let first_time = first.state.sink_timestamp.unwrap();
+        let second_time = second.state.sink_timestamp.unwrap();
+        assert!(second_time.monotonic_ns >= first_time.monotonic_ns);
+    }
+
+    #[test]
+    fn test_concurrent_updates_are_not_lost() {
+        let vehicle_shadow = VehicleShadow::create().unwrap();
+        vehicle_shadow
+            .set_signal(create_test_signal("Vehicle.Odometer", ValueType::TypeUint32, Value::Uint32(0)), &None)
+            .unwrap();
+
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    for _ in 0..50 {
+                        vehicle_shadow
+                            .update_signal("Vehicle.Odometer", &None, |signal| {
+                                if let Value::Uint32(count) = signal.state.value {
+                                    signal.state.value = Value::Uint32(count + 1);
+                                }
+                                Ok(())
+                            })
+                            .unwrap();
+                    }
+                });
+            }
+        });
+
+        let odometer = vehicle_shadow.get_signal("Vehicle.Odometer".to_string()).unwrap();
+        assert_eq!(odometer.state.value, Value::Uint32(200));
+        assert_eq!(odometer.state.sequence, 201);
+    }
+
+    #[test]
+    fn test_history_keeps_latest_entries() {
+        let vehicle_shadow = VehicleShadow::create().unwrap().with_history(HistoryConfig {
+            max_entries: Some(3),
+            max_age: None,
+        });
+        for speed in 0..5 {
+            let signal = create_test_signal("Vehicle.Speed", ValueType::TypeFloat, Value::Float(speed as f32));
+            vehicle_shadow.set_signal(signal, &None).unwrap();
+        }
+
+        let values = |states: Vec<State>| states.into_iter().map(|s| s.value).collect::<Vec<_>>();
+        let history = vehicle_shadow.get_history("Vehicle.Speed", None, None, None).unwrap();
+        assert_eq!(values(history), vec![Value::Float(2.0), Value::Float(3.0), Value::Float(4.0)]);
+
+        let history = vehicle_shadow.get_history("Vehicle.Speed", None, None, Some(2)).unwrap();
+        assert_eq!(values(history), vec![Value::Float(3.0), Value::Float(4.0)]);
+
+        let history = vehicle_shadow.get_history("Vehicle.Speed", Some(u64::MAX), None, None).unwrap();
+        assert!(history.is_empty());
+        assert!(vehicle_shadow.get_history("Vehicle.Unknown", None, None, None).is_err());
+    }
+
+    #[test]
+    fn test_history_of_idle_signal_expires() {
+        let vehicle_shadow = VehicleShadow::create().unwrap().with_history(HistoryConfig {
+            max_entries: None,
+            max_age: Some(Duration::from_millis(20)),
+        });
+        let signal = create_test_signal("Vehicle.Speed", ValueType::TypeFloat, Value::Float(1.0));
+        vehicle_shadow.set_signal(signal, &None).unwrap();
+        assert_eq!(vehicle_shadow.get_history("Vehicle.Speed", None, None, None).unwrap().len(), 1);
+
+        // no later write trims the entry, but it is too old to be returned
+        std::thread::sleep(Duration::from_millis(40));
+        assert!(vehicle_shadow.get_history("Vehicle.Speed", None, None, None).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_set_signals_is_all_or_nothing() {
+        let vehicle_shadow = VehicleShadow::create().unwrap();
+        let mut speed = create_test_signal("Vehicle.Speed", ValueType::TypeFloat, Value::Float(0.0));
+        let mut rpm = create_test_signal("Vehicle.Rpm", ValueType::TypeUint16, Value::Uint16(0));
+        vehicle_shadow.set_signals(vec![speed.clone(), rpm.clone()], &None).unwrap();
+
+        speed.state.value = Value::Float(50.0);
+        rpm.state.value = Value::String("fast".to_string());
+        let result = vehicle_shadow.set_signals(vec![speed, rpm], &None);
+
+        assert!(matches!(
+            result,
+            Err(VehicleShadowError::TransactionAborted { ref path, .. }) if path == "Vehicle.Rpm"
+        ));
+        let stored = vehicle_shadow.get_signal("Vehicle.Speed".to_string()).unwrap();
+        assert_eq!(stored.state.value, Value::Float(0.0));
+        assert_eq!(stored.state.sequence, 1);
+    }
+
+    #[test]
+    fn test_try_locks_is_all_or_nothing() {
+        let vehicle_shadow = VehicleShadow::create().unwrap();
+        for path in ["Vehicle.Speed", "Vehicle.Rpm"] {
+            vehicle_shadow
+                .set_signal(create_test_signal(path, ValueType::TypeFloat, Value::Float(0.0)), &None)
+                .unwrap();
+        }
+        vehicle_shadow
+            .try_locks(vec!["Vehicle.Rpm".to_string()], "first", "a", None)
+            .unwrap();
+
+        let result = vehicle_shadow.try_locks(
+            vec!["Vehicle.Speed".to_string(), "Vehicle.Rpm".to_string()],
+            "second",
+            "b",
+            None,
+        );
+
+        assert!(result.is_err());
+        assert!(!vehicle_shadow.is_locked("Vehicle.Speed".to_string()).unwrap());
+        assert_eq!(vehicle_shadow.list_locks().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_actuator_target_and_current() {
+        let vehicle_shadow = VehicleShadow::create().unwrap();
+        let mut position = create_test_signal("Vehicle.Body.Hood.Position", ValueType::TypeUint8, Value::Uint8(0));
+        position.config.leaf_type = LeafType::Actuator;
+        position.config.max = Some(Value::Uint8(100));
+        vehicle_shadow.set_signal(position, &None).unwrap();
+
+        let signal = vehicle_shadow
+            .set_target("Vehicle.Body.Hood.Position", Value::Uint8(80), &None)
+            .unwrap();
+        assert_eq!(signal.state.target, Some(Value::Uint8(80)));
+        assert_eq!(signal.state.value, Value::Uint8(0));
+
+        let signal = vehicle_shadow
+            .set_current("Vehicle.Body.Hood.Position", Value::Uint8(40), None, &None)
+            .unwrap();
+        assert_eq!(signal.state.target, Some(Value::Uint8(80)));
+        assert_eq!(signal.state.value, Value::Uint8(40));
+
+        assert!(vehicle_shadow
+            .set_target("Vehicle.Body.Hood.Position", Value::Uint8(120), &None)
+            .is_err());
+
+        // a locked actuator only takes writes with the lock's token
+        let path = "Vehicle.Body.Hood.Position";
+        let token = Some("token".to_string());
+        vehicle_shadow.try_locks(vec![path.to_string()], "token", "hmi", None).unwrap();
+        assert!(vehicle_shadow.set_target(path, Value::Uint8(60), &None).is_err());
+        assert!(vehicle_shadow.set_current(path, Value::Uint8(60), None, &None).is_err());
+        let signal = vehicle_shadow.set_target(path, Value::Uint8(60), &token).unwrap();
+        assert_eq!(signal.state.target, Some(Value::Uint8(60)));
+    }
+
+    #[test]
+    fn test_set_target_rejects_sensor() {
+        let vehicle_shadow = VehicleShadow::create().unwrap();
+        vehicle_shadow
+            .set_signal(create_test_signal("Vehicle.Speed", ValueType::TypeFloat, Value::Float(0.0)), &None)
+            .unwrap();
+
+        assert!(matches!(
+            vehicle_shadow.set_target("Vehicle.Speed", Value::Float(10.0), &None),
+            Err(VehicleShadowError::InvalidInput(_))
+        ));
+    }
+
+    #[test]
+    fn test_provider_attach_and_detach() {
+        let vehicle_shadow = VehicleShadow::create().unwrap();
+        vehicle_shadow
+            .set_signal(create_test_signal("Vehicle.Speed", ValueType::TypeFloat, Value::Float(0.0)), &None)
+            .unwrap();
+        let paths = vec!["Vehicle.Speed".to_string()];
+        let token = vehicle_shadow.try_locks(paths.clone(), "token", "client", None).unwrap().token;
+
+        let signals = vehicle_shadow.attach_provider(&paths, "speed-ecu").unwrap();
+        assert_eq!(signals[0].config.end_point, "speed-ecu");
+        assert!(signals[0].state.availability);
+
+        // The provider writes regardless of client locks.
+        let signal = vehicle_shadow.provide_value("Vehicle.Speed", Value::Float(42.0), None).unwrap();
+        assert_eq!(signal.state.value, Value::Float(42.0));
+        assert_eq!(signal.state.lock_uuid, Some(token));
+
+        let signals = vehicle_shadow.detach_provider(&paths).unwrap();
+        assert_eq!(signals[0].config.end_point, "");
+        assert!(!signals[0].state.availability);
+    }
+
+    #[test]
+    fn test_read_only_shadow_takes_only_replicas() {
+        let vehicle_shadow = VehicleShadow::create().unwrap().with_read_only(true);
+        let mut signal = create_test_signal("Vehicle.Speed", ValueType::TypeFloat, Value::Float(10.0));
+        signal.state.sequence = 5;
+        vehicle_shadow.store_replica(signal.clone()).unwrap();
+
+        assert!(matches!(
+            vehicle_shadow.set_signal(signal.clone(), &None),
+            Err(VehicleShadowError::ReadOnly)
+        ));
+        assert!(matches!(
+            vehicle_shadow.try_locks(vec!["Vehicle.Speed".to_string()], "token", "test", None),
+            Err(VehicleShadowError::ReadOnly)
+        ));
+
+        // Replicas older than the stored copy are skipped.
+        signal.state.sequence = 4;
+        signal.state.value = Value::Float(20.0);
+        assert!(!vehicle_shadow.apply_replica(signal.clone()).unwrap());
+        signal.state.sequence = 6;
+        assert!(vehicle_shadow.apply_replica(signal).unwrap());
+
+        let stored = vehicle_shadow.get_signal("Vehicle.Speed".to_string()).unwrap();
+        assert_eq!(stored.state.sequence, 6);
+        assert_eq!(stored.state.value, Value::Float(20.0));
+
+        // a signal gone from the upstream is returned so it can be announced
+        let removed = vehicle_shadow.delete_signal("Vehicle.Speed").unwrap().unwrap();
+        assert_eq!(removed.state.sequence, 6);
+        assert!(vehicle_shadow.delete_signal("Vehicle.Speed").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_lock_lease_lifecycle() {
+        let vehicle_shadow = VehicleShadow::create().unwrap();
+        for path in ["Vehicle.Speed", "Vehicle.Other"] {
+            vehicle_shadow
+                .set_signal(create_test_signal(path, ValueType::TypeFloat, Value::Float(0.0)), &None)
+                .unwrap();
+        }
+        let token = "token".to_string();
+
+        let lease = vehicle_shadow
+            .try_locks(vec!["Vehicle.Speed".to_string()], &token, "hmi", None)
+            .unwrap();
+        assert_eq!(lease.paths, vec!["Vehicle.Speed".to_string()]);
+        assert!(vehicle_shadow.is_locked("Vehicle.Speed".to_string()).unwrap());
+        assert!(!vehicle_shadow.is_locked("Vehicle.Other".to_string()).unwrap());
+        assert_eq!(vehicle_shadow.list_locks().unwrap(), vec![lease.clone()]);
+
+        let renewed = vehicle_shadow.renew_lock(&token, Some(Duration::from_secs(60))).unwrap();
+        assert!(renewed.expires_at_ns > lease.expires_at_ns);
+
+        vehicle_shadow.release_lock(&token).unwrap();
+        assert!(!vehicle_shadow.is_locked("Vehicle.Speed".to_string()).unwrap());
+        assert!(vehicle_shadow.list_locks().unwrap().is_empty());
+        assert!(vehicle_shadow.release_lock(&token).is_err());
+    }
+
+    #[test]
+    fn test_expired_lock_is_released() {
+        let vehicle_shadow = VehicleShadow::create().unwrap();
+        let speed = create_test_signal("Vehicle.Speed", ValueType::TypeFloat, Value::Float(0.0));
+        vehicle_shadow.set_signal(speed.clone(), &None).unwrap();
+        let token = "crashed".to_string();
+        vehicle_shadow
+            .try_locks(vec!["Vehicle.Speed".to_string()], &token, "hmi", Some(Duration::ZERO))
+            .unwrap();
+
+        assert!(!vehicle_shadow.is_locked("Vehicle.Speed".to_string()).unwrap());
+        assert!(vehicle_shadow.set_signal(speed, &Some(token.clone())).is_err());
+        assert!(vehicle_shadow.renew_lock(&token, None).is_err());
+
+        let released = vehicle_shadow.release_expired_locks().unwrap();
+        assert_eq!(released.len(), 1);
+        let stored = vehicle_shadow.get_signal("Vehicle.Speed".to_string()).unwrap();
+        assert_eq!(stored.state.lock_uuid, None);
+    }
+
+    #[test]
+    fn test_lease_expiry_ignores_wall_clock() {
+        let mut lease = Lease {
+            token: "token".to_string(),
+            holder: "hmi".to_string(),
+            paths: vec![],
+            expires_at_ns: 0,
+            expires_at_monotonic_ns: 0,
+        };
+        lease.extend(Duration::from_secs(60));
+        let mut now = signal::Timestamp::now();
+        now.wall_clock_ns = lease.expires_at_ns + 1;
+        assert!(!lease.is_expired(&now));
+        now.monotonic_ns = lease.expires_at_monotonic_ns;
+        assert!(lease.is_expired(&now));
+    }
+
+    #[test]
+    fn test_force_unlock() {
+        let vehicle_shadow = VehicleShadow::create().unwrap();
+        vehicle_shadow
+            .set_signal(create_test_signal("Vehicle.Speed", ValueType::TypeFloat, Value::Float(0.0)), &None)
+            .unwrap();
+        vehicle_shadow
+            .try_locks(vec!["Vehicle.Speed".to_string()], "token", "hmi", None)
+            .unwrap();
+
+        let released = vehicle_shadow.force_unlock(&["Vehicle.Speed".to_string()]).unwrap();
+        assert_eq!(released[0].holder, "hmi");
+        assert!(!vehicle_shadow.is_locked("Vehicle.Speed".to_string()).unwrap());
+    }
+
+    #[test]
+    fn test_resolve_paths() {
+        let vehicle_shadow = VehicleShadow::create().unwrap();
+        for path in [
+            "Vehicle.Cabin.Door.Row1.IsOpen",
+            "Vehicle.Cabin.Door.Row2.IsOpen",
+            "Vehicle.Cabin.Door.Row2.IsLocked",
+            "Vehicle.Body.Hood.IsOpen",
+        ] {
+            vehicle_shadow
+                .set_signal(create_test_signal(path, ValueType::TypeBool, Value::Bool(false)), &None)
+                .unwrap();
+        }
+
+        let paths = vehicle_shadow
+            .resolve_paths(&["Vehicle.Cabin.Door.*.IsOpen".to_string()])
+            .unwrap();
+        assert_eq!(paths, vec!["Vehicle.Cabin.Door.Row1.IsOpen", "Vehicle.Cabin.Door.Row2.IsOpen"]);
+
+        let paths = vehicle_shadow
+            .resolve_paths(&["Vehicle.Body.**".to_string(), "Vehicle.Speed".to_string()])
+            .unwrap();
+        assert_eq!(paths, vec!["Vehicle.Body.Hood.IsOpen", "Vehicle.Speed"]);
+    }
+
+    #[test]
+    fn test_apply_overlay() {
+        let vehicle_shadow = VehicleShadow::create().unwrap();
+        for path in ["Vehicle.Speed", "Vehicle.Legacy"] {
+            vehicle_shadow
+                .set_signal(create_test_signal(path, ValueType::TypeFloat, Value::Float(1.0)), &None)
+                .unwrap();
+        }
+        vehicle_shadow.assign_ids(&["Vehicle.Speed".to_string(), "Vehicle.Legacy".to_string()]).unwrap();
+
+        let overlay = r#"{"Vehicle": {"children": {
+            "Speed": {"unit": "m/s"},
+            "Legacy": {"delete": true},
+            "Private": {"children": {"Mode": {"type": "attribute", "datatype": "string", "default": "eco"}}}
+        }}}"#;
+        let changes = vehicle_shadow.apply_overlay(overlay).unwrap();
+        assert_eq!(changes.added[0].path, "Vehicle.Private.Mode");
+        assert_eq!(changes.added[0].id, 3);
+        assert_eq!(changes.removed[0].path, "Vehicle.Legacy");
+
+        // the stored state survives a config change
+        let speed = &changes.updated[0];
+        assert_eq!(speed.config.unit, Some("m/s".to_string()));
+        assert_eq!(speed.state.value, Value::Float(1.0));
+        assert_eq!(speed.state.sequence, 1);
+        assert_eq!(speed.id, 1);
+
+        assert!(vehicle_shadow.path_of(2).is_err());
+        assert_eq!(vehicle_shadow.overlays().unwrap(), vec![overlay.to_string()]);
+
+        assert!(vehicle_shadow.apply_overlay(r#"{"Vehicle": {"children": {"Legacy": {"delete": true}}}}"#).is_err());
+        assert_eq!(vehicle_shadow.overlays().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_derived_signals() {
+        let vehicle_shadow = VehicleShadow::create().unwrap();
+        for path in ["Vehicle.Cabin.Door.Row1.IsOpen", "Vehicle.Cabin.Door.Row2.IsOpen"] {
+            vehicle_shadow
+                .set_signal(create_test_signal(path, ValueType::TypeBool, Value::Bool(false)), &None)
+                .unwrap();
+        }
+        let mut any_open = create_test_signal("Vehicle.Cabin.Door.AnyOpen", ValueType::TypeBool, Value::NAN);
+        any_open.config.expression = Some("any(Vehicle.Cabin.Door.*.IsOpen)".to_string());
+        vehicle_shadow.set_signal(any_open.clone(), &None).unwrap();
+
+        let derived = vehicle_shadow.refresh_derived().unwrap();
+        assert_eq!(derived.len(), 1);
+        assert_eq!(derived[0].state.value, Value::Bool(false));
+        assert!(derived[0].state.availability);
+
+        let door = vehicle_shadow
+            .update_signal("Vehicle.Cabin.Door.Row2.IsOpen", &None, |signal| {
+                signal.state.value = Value::Bool(true);
+                Ok(())
+            })
+            .unwrap();
+        let derived = vehicle_shadow.update_derived(std::slice::from_ref(&door)).unwrap();
+        assert_eq!(derived[0].path, "Vehicle.Cabin.Door.AnyOpen");
+        assert_eq!(derived[0].state.value, Value::Bool(true));
+        assert_eq!(derived[0].state.sequence, 3);
+
+        // unchanged results are not written again
+        assert!(vehicle_shadow.update_derived(&[door]).unwrap().is_empty());
+        assert!(vehicle_shadow.set_signal(any_open, &None).is_err());
+    }
+
+    #[test]
+    fn test_stale_signal_is_marked_unavailable() {
+        let vehicle_shadow = VehicleShadow::create().unwrap();
+        let mut speed = create_test_signal("Vehicle.Speed", ValueType::TypeFloat, Value::Float(0.0));
+        speed.config.update_period = Some(5);
+        speed.state.availability = true;
+        vehicle_shadow.set_signal(speed, &None).unwrap();
+        assert!(vehicle_shadow.mark_stale(3).unwrap().is_empty());
+
+        std::thread::sleep(Duration::from_millis(20));
+        let marked = vehicle_shadow.mark_stale(3).unwrap();
+        assert_eq!(marked.len(), 1);
+        assert!(!marked[0].state.availability);
+        assert_eq!(marked[0].state.sequence, 2);
+        assert!(vehicle_shadow.mark_stale(3).unwrap().is_empty());
+
+        let written = vehicle_shadow
+            .update_signal("Vehicle.Speed", &None, |signal| {
+                signal.state.value = Value::Float(12.5);
+                Ok(())
+            })
+            .unwrap();
+        assert!(written.state.availability);
+
+        // availability set by a client is left alone
+        let unavailable = vehicle_shadow
+            .update_signals(&["Vehicle.Speed".to_string()], &None, |_, signal| {
+                signal.state.availability = false;
+                Ok(())
+            })
+            .unwrap();
+        assert!(!unavailable[0].state.availability);
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(vehicle_shadow.mark_stale(3).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_staleness_ignores_wall_clock() {
+        let vehicle_shadow = VehicleShadow::create().unwrap();
+        let mut speed = create_test_signal("Vehicle.Speed", ValueType::TypeFloat, Value::Float(0.0));
+        speed.config.update_period = Some(1_000);
+        speed.state.availability = true;
+        let mut speed = vehicle_shadow.set_signal(speed, &None).unwrap();
+
+        // the wall clock stepped forward a day after the write
+        if let Some(written) = &mut speed.state.sink_timestamp {
+            written.wall_clock_ns -= 86_400 * 1_000_000_000;
+        }
+        vehicle_shadow.store_signal(speed.clone()).unwrap();
+        assert!(vehicle_shadow.mark_stale(3).unwrap().is_empty());
+
+        // a state restored from an earlier run counts from the restore
+        if let Some(written) = &mut speed.state.sink_timestamp {
+            written.monotonic_ns = u64::MAX;
+        }
+        vehicle_shadow.store_signal(speed.clone()).unwrap();
+        vehicle_shadow.reconcile(vec![speed]).unwrap();
+        let restored = vehicle_shadow.get_signal("Vehicle.Speed".to_string()).unwrap();
+        let restored_at = restored.state.sink_timestamp.unwrap().monotonic_ns;
+        assert!(restored_at <= signal::Timestamp::now().monotonic_ns);
+    }
+
+    #[test]
+    fn test_reset_signals() {
+        let vehicle_shadow = VehicleShadow::create().unwrap().with_history(HistoryConfig {
+            max_entries: Some(10),
+            max_age: None,
+        });
+        let mut signals = Vec::new();
+        for path in ["Vehicle.Cabin.Door.Row1.IsOpen", "Vehicle.Cabin.Door.Row2.IsOpen"] {
+            let mut signal = create_test_signal(path, ValueType::TypeBool, Value::Bool(false));
+            signal.config.default = Some(Value::Bool(false));
+            signals.push(signal.clone());
+            signal.state.value = Value::Bool(true);
+            vehicle_shadow.set_signal(signal, &None).unwrap();
+        }
+        let token = "token".to_string();
+        vehicle_shadow
+            .try_locks(vec!["Vehicle.Cabin.Door.Row2.IsOpen".to_string()], &token, "hmi", None)
+            .unwrap();
+
+        let pattern = vec!["Vehicle.Cabin.Door.*.IsOpen".to_string()];
+        assert!(vehicle_shadow.reset_signals(&pattern, &None).is_err());
+        let row1 = vehicle_shadow.get_signal("Vehicle.Cabin.Door.Row1.IsOpen".to_string()).unwrap();
+        assert_eq!(row1.state.value, Value::Bool(true));
+
+        let reset = vehicle_shadow
+            .reset_signals(&["Vehicle.Cabin.Door.Row1.IsOpen".to_string()], &None)
+            .unwrap();
+        assert_eq!(reset[0].state.value, Value::Bool(false));
+        assert_eq!(reset[0].state.sequence, 2);
+
+        vehicle_shadow.stale.insert("Vehicle.Cabin.Door.Row1.IsOpen", &[]).unwrap();
+        let changes = vehicle_shadow.factory_reset(signals).unwrap();
+        assert_eq!(changes.updated.len(), 2);
+        assert!(vehicle_shadow.list_locks().unwrap().is_empty());
+        assert!(vehicle_shadow.stale.is_empty());
+        assert!(vehicle_shadow.history.is_empty());
+        let row2 = vehicle_shadow.get_signal("Vehicle.Cabin.Door.Row2.IsOpen".to_string()).unwrap();
+        assert_eq!(row2.state.value, Value::Bool(false));
+        assert_eq!(row2.state.sequence, 2);
+        assert_eq!(row2.state.lock_uuid, None);
+    }
+
+    #[test]
+    fn test_snapshot_export_and_import() {
+        let source = VehicleShadow::create().unwrap();
+        for (path, speed) in [("Vehicle.Speed", 42.0), ("Vehicle.Exported", 1.0)] {
+            source
+                .set_signal(create_test_signal(path, ValueType::TypeFloat, Value::Float(speed)), &None)
+                .unwrap();
+        }
+        let snapshot = source.export_snapshot().unwrap();
+
+        let target = VehicleShadow::create().unwrap();
+        for path in ["Vehicle.Speed", "Vehicle.Retired"] {
+            target
+                .set_signal(create_test_signal(path, ValueType::TypeFloat, Value::Float(0.0)), &None)
+                .unwrap();
+        }
+        target.assign_ids(&["Vehicle.Speed".to_string(), "Vehicle.Retired".to_string()]).unwrap();
+        let speed_id = target.get_signal("Vehicle.Speed".to_string()).unwrap().id;
+        target.update_signal("Vehicle.Speed", &None, |_| Ok(())).unwrap();
+        target.try_locks(vec!["Vehicle.Speed".to_string()], "token", "hmi", None).unwrap();
+
+        let changes = target.import_snapshot(snapshot.clone()).unwrap();
+        assert_eq!(changes.added.len(), 1);
+        assert_eq!(changes.updated.len(), 1);
+        assert_eq!(changes.removed[0].path, "Vehicle.Retired");
+        let speed = target.get_signal("Vehicle.Speed".to_string()).unwrap();
+        assert_eq!(speed.state.value, Value::Float(42.0));
+        assert_eq!(speed.id, speed_id);
+        assert_eq!(speed.state.sequence, 3);
+        let exported = target.get_signal("Vehicle.Exported".to_string()).unwrap();
+        assert_ne!(exported.id, 0);
+        assert_eq!(changes.added[0].id, exported.id);
+        assert_eq!(target.path_of(exported.id).unwrap(), "Vehicle.Exported");
+        assert!(target.list_locks().unwrap().is_empty());
+        assert_eq!(speed.state.lock_uuid, None);
+        assert!(target.get_signal("Vehicle.Retired".to_string()).is_err());
+
+        // a snapshot that does not validate is not loaded at all
+        let mut invalid = snapshot;
+        invalid.signals[1].config.max = Some(Value::Float(10.0));
+        assert!(target.import_snapshot(invalid).is_err());
+        assert_eq!(target.list_signals().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_alert_rules() {
+        let vehicle_shadow = VehicleShadow::create().unwrap();
+        let soc = create_test_signal("Vehicle.Battery.SoC", ValueType::TypeDouble, Value::Double(50.0));
+        vehicle_shadow.set_signal(soc, &None).unwrap();
+        let rule: AlertRule = serde_json::from_value(serde_json::json!({
+            "id": "low_soc",
+            "path": "Vehicle.Battery.SoC",
+            "comparator": "<",
+            "threshold": 10,
+            "hysteresis": 2
+        }))
+        .unwrap();
+        assert!(vehicle_shadow.put_alert_rule(rule).unwrap().is_none());
+
+        let set_soc = |soc: f64| {
+            let signal = vehicle_shadow
+                .update_signal("Vehicle.Battery.SoC", &None, |signal| {
+                    signal.state.value = Value::Double(soc);
+                    Ok(())
+                })
+                .unwrap();
+            vehicle_shadow.update_alerts(&[signal]).unwrap()
+        };
+        let raised = set_soc(9.0);
+        assert_eq!(raised.len(), 1);
+        assert!(raised[0].active);
+        assert_eq!(raised[0].value, Value::Double(9.0));
+        assert_eq!(vehicle_shadow.active_alerts().unwrap(), raised);
+
+        // within the hysteresis the alert stays active
+        assert!(set_soc(11.0).is_empty());
+        let cleared = set_soc(12.5);
+        assert!(!cleared[0].active);
+        assert_eq!(cleared[0].sequence, 2);
+        assert!(vehicle_shadow.active_alerts().unwrap().is_empty());
+
+        assert!(set_soc(5.0)[0].active);
+        let removed = vehicle_shadow.remove_alert_rule("low_soc").unwrap().unwrap();
+        assert!(!removed.active);
+        let log = vehicle_shadow.alert_log(10).unwrap();
+        assert_eq!(log.iter().map(|alert| alert.sequence).collect::<Vec<_>>(), vec![1, 2, 3, 4]);
+        assert_eq!(vehicle_shadow.alert_log(1).unwrap(), vec![removed]);
+        assert!(vehicle_shadow.remove_alert_rule("low_soc").is_err());
+    }
+
+    #[test]
+    fn test_list_tree() {
+        let vehicle_shadow = VehicleShadow::create().unwrap();
+        for path in [
+            "Vehicle.Cabin.Door.Row1.IsOpen",
+            "Vehicle.Cabin.Door-Count",
+            "Vehicle.Cabin.Door.Row2.IsOpen",
+            "Vehicle.Speed",
+        ] {
+            vehicle_shadow
+                .set_signal(create_test_signal(path, ValueType::TypeBool, Value::Bool(false)), &None)
+                .unwrap();
+        }
+        let paths = |nodes: Vec<SignalNode>| -> Vec<String> {
+            nodes.iter().map(|node| node.path().to_string()).collect()
+        };
+
+        let children = vehicle_shadow.list_tree("Vehicle", false).unwrap();
+        assert!(matches!(&children[0], SignalNode::Branch(path) if path == "Vehicle.Cabin"));
+        assert_eq!(paths(children), vec!["Vehicle.Cabin", "Vehicle.Speed"]);
+
+        let tree = vehicle_shadow.list_tree("Vehicle.Cabin", true).unwrap();
+        assert_eq!(
+            paths(tree),
+            vec![
+                "Vehicle.Cabin.Door",
+                "Vehicle.Cabin.Door.Row1",
+                "Vehicle.Cabin.Door.Row1.IsOpen",
+                "Vehicle.Cabin.Door.Row2",
+                "Vehicle.Cabin.Door.Row2.IsOpen",
+                "Vehicle.Cabin.Door-Count",
+            ]
+        );
+
+        assert_eq!(paths(vehicle_shadow.list_tree("", false).unwrap()), vec!["Vehicle"]);
+        assert_eq!(paths(vehicle_shadow.list_tree("Vehicle.Speed", false).unwrap()), vec!["Vehicle.Speed"]);
+        assert!(vehicle_shadow.list_tree("Vehicle.Cab", false).is_err());
+    }
+
+    #[test]
+    fn test_reconcile_resets_state_on_type_change() {
+        let vehicle_shadow = VehicleShadow::create().unwrap();
+        vehicle_shadow
+            .set_signal(create_test_signal("Vehicle.Speed", ValueType::TypeFloat, Value::Float(42.0)), &None)
+            .unwrap();
+
+        vehicle_shadow
+            .reconcile(vec![create_test_signal("Vehicle.Speed", ValueType::TypeDouble, Value::NAN)])
+            .unwrap();
+
+        let speed = vehicle_shadow.get_signal("Vehicle.Speed".to_string()).unwrap();
+        assert_eq!(speed.state.value, Value::NAN);
+    }
+
+    #[test]
+    fn test_database_of_another_schema_is_dropped() {
+        let database = sled::Config::new().temporary(true).open().unwrap();
+        let vehicle_shadow = VehicleShadow::open(database.clone()).unwrap();
+        vehicle_shadow
+            .set_signal(create_test_signal("Vehicle.Speed", ValueType::TypeFloat, Value::Float(1.0)), &None)
+            .unwrap();
+        vehicle_shadow.overlays.insert("1", "{}").unwrap();
+        drop(vehicle_shadow);
+
+        // same version: kept
+        let vehicle_shadow = VehicleShadow::open(database.clone()).unwrap();
+        assert_eq!(vehicle_shadow.list_signals().unwrap().len(), 1);
+        vehicle_shadow
+            .meta
+            .insert(SCHEMA_VERSION_KEY, encode_to_vec(SCHEMA_VERSION - 1, standard()).unwrap())
+            .unwrap();
+        drop(vehicle_shadow);
+
+        let vehicle_shadow = VehicleShadow::open(database.clone()).unwrap();
+        assert!(vehicle_shadow.list_signals().unwrap().is_empty());
+        assert_eq!(vehicle_shadow.overlays().unwrap(), vec!["{}".to_string()]);
+
+        // no signal stored: the other trees are still dropped
+        vehicle_shadow.locks.insert("token", "old layout").unwrap();
+        vehicle_shadow
+            .meta
+            .insert(SCHEMA_VERSION_KEY, encode_to_vec(SCHEMA_VERSION - 1, standard()).unwrap())
+            .unwrap();
+        drop(vehicle_shadow);
+
+        let vehicle_shadow = VehicleShadow::open(database).unwrap();
+        assert!(vehicle_shadow.locks.is_empty());
+    }
 }
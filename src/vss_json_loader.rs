@@ -1,19 +1,32 @@
+use crate::expression::Expression;
 use crate::signal;
 
 use std::fs;
 use std::io;
 
 const TAG_CHILDREN: &str = "children";
-//const TAG_DESCRIPTION: &str = "description";
+const TAG_DESCRIPTION: &str = "description";
 const TAG_TYPE: &str = "type";
 const TAG_DATATYPE: &str = "datatype";
-//const TAG_ALLOWED: &str = "allowed";
-//const TAG_COMMENT: &str = "comment";
-//const TAG_UNIT: &str = "unit";
+const TAG_ALLOWED: &str = "allowed";
+const TAG_COMMENT: &str = "comment";
+const TAG_UNIT: &str = "unit";
 const TAG_DEFAULT: &str = "default";
-//const TAG_DEPRECATION: &str = "deprecation";
-//const TAG_MIN: &str = "min";
-//const TAG_MAX: &str = "max";
+const TAG_DEPRECATION: &str = "deprecation";
+const TAG_MIN: &str = "min";
+const TAG_MAX: &str = "max";
+const TAG_EXPRESSION: &str = "expression";
+const TAG_UPDATE_PERIOD: &str = "update_period";
+// overlay only: removes a leaf or a whole branch
+const TAG_DELETE: &str = "delete";
+
+/// One change of a VSS overlay: a leaf to add or update, or a leaf or branch
+/// to remove.
+#[derive(Debug, Clone)]
+pub enum OverlayEntry {
+    Leaf { path: String, node: serde_json::Value },
+    Delete(String),
+}
 
 pub fn load_vss_json(
     vss_json_path: String,
@@ -34,6 +47,114 @@ pub fn load_vss_json(
     Ok(result)
 }
 
+/// Reads a VSS overlay. It has the shape of a VSS JSON file, except that
+/// branches may leave out their type, leaves that already exist only need
+/// the fields that change, and `"delete": true` removes a leaf or branch.
+pub fn read_overlay(overlay_json: &str) -> Result<Vec<OverlayEntry>, Box<dyn std::error::Error>> {
+    let overlay: serde_json::Value = serde_json::from_str(overlay_json)?;
+    let serde_json::Value::Object(map) = overlay else {
+        return Err(invalid_overlay("Top-level JSON is not an object".to_string()));
+    };
+
+    let mut entries = Vec::new();
+    for (path, node) in map {
+        read_overlay_node(path, &node, &mut entries)?;
+    }
+    Ok(entries)
+}
+
+/// Applies overlay `entries` to `signals` in order. Changed leaves get the
+/// initial state of their new config. On error `signals` is left untouched.
+pub fn apply_overlay(
+    signals: &mut Vec<signal::Signal>,
+    entries: &[OverlayEntry],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut merged = signals.clone();
+    for entry in entries {
+        match entry {
+            OverlayEntry::Delete(path) => {
+                let before = merged.len();
+                merged.retain(|signal| !is_within(&signal.path, path));
+                if merged.len() == before {
+                    return Err(invalid_overlay(format!("nothing to delete at {}", path)));
+                }
+            }
+            OverlayEntry::Leaf { path, node } => {
+                let position = merged.iter().position(|signal| &signal.path == path);
+                let signal = match position {
+                    Some(index) => {
+                        let config = overlay_config(node, Some(&merged[index].config))?;
+                        signal::Signal {
+                            path: path.clone(),
+                            id: merged[index].id,
+                            state: create_state(&config),
+                            config,
+                        }
+                    }
+                    None => {
+                        if let Some(signal) = merged.iter().find(|signal| is_within(&signal.path, path)) {
+                            return Err(invalid_overlay(format!("{} is a branch above {}", path, signal.path)));
+                        }
+                        if let Some(signal) = merged.iter().find(|signal| is_within(path, &signal.path)) {
+                            return Err(invalid_overlay(format!("{} is a leaf above {}", signal.path, path)));
+                        }
+                        create_signal(path.clone(), node)?
+                    }
+                };
+                if let Some(default) = &signal.config.default
+                    && let Err(violation) = signal.config.validate(default)
+                {
+                    return Err(invalid_overlay(format!("invalid default of {}: {}", path, violation)));
+                }
+                if let Some(expression) = &signal.config.expression {
+                    Expression::parse(expression)?;
+                }
+                match position {
+                    Some(index) => merged[index] = signal,
+                    None => merged.push(signal),
+                }
+            }
+        }
+    }
+
+    *signals = merged;
+    Ok(())
+}
+
+fn read_overlay_node(
+    path: String,
+    node: &serde_json::Value,
+    entries: &mut Vec<OverlayEntry>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if node.get(TAG_DELETE).and_then(|v| v.as_bool()) == Some(true) {
+        entries.push(OverlayEntry::Delete(path));
+        return Ok(());
+    }
+
+    let is_branch = match node.get(TAG_TYPE) {
+        Some(_) => read_type(node)? == signal::LeafType::Branch,
+        None => node.get(TAG_CHILDREN).is_some(),
+    };
+    if !is_branch {
+        entries.push(OverlayEntry::Leaf { path, node: node.clone() });
+        return Ok(());
+    }
+    for (child_key, child) in read_children(node)? {
+        read_overlay_node(path.clone() + "." + &child_key, &child, entries)?;
+    }
+    Ok(())
+}
+
+// true if `path` is `prefix` itself or lies below it
+fn is_within(path: &str, prefix: &str) -> bool {
+    path.strip_prefix(prefix)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('.'))
+}
+
+fn invalid_overlay(message: String) -> Box<dyn std::error::Error> {
+    Box::new(io::Error::new(io::ErrorKind::InvalidData, message))
+}
+
 fn load_branch(
     path: String,
     node: &serde_json::Value,
@@ -58,7 +179,7 @@ fn load_leaf(
     node: &serde_json::Value,
     result: &mut Vec<signal::Signal>,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let signal = create_signal(path, &node)?;
+    let signal = create_signal(path, node)?;
     result.push(signal);
     Ok(())
 }
@@ -67,7 +188,7 @@ fn read_children(
     node: &serde_json::Value,
 ) -> Result<serde_json::map::Map<String, serde_json::Value>, Box<dyn std::error::Error>> {
     let tag_children_value = node.get(TAG_CHILDREN);
-    if None == tag_children_value {
+    if tag_children_value.is_none() {
         return Err(Box::new(io::Error::new(
             io::ErrorKind::NotFound,
             "children not found",
@@ -75,14 +196,14 @@ fn read_children(
     }
 
     let children = tag_children_value.unwrap();
-    return if let serde_json::Value::Object(map) = children {
+    if let serde_json::Value::Object(map) = children {
         Ok(map.clone())
     } else {
         Err(Box::new(io::Error::new(
             io::ErrorKind::InvalidData,
             "TODO: write error message",
         )))
-    };
+    }
 }
 
 fn read_type(node: &serde_json::Value) -> Result<signal::LeafType, Box<dyn std::error::Error>> {
@@ -101,7 +222,7 @@ fn read_datatype(
     node: &serde_json::Value,
 ) -> Result<signal::ValueType, Box<dyn std::error::Error>> {
     let data_type_value = node.get(TAG_DATATYPE);
-    if None == data_type_value {
+    if data_type_value.is_none() {
         return Err(Box::new(io::Error::new(
             io::ErrorKind::NotFound,
             format!("datatype not found: {}", node),
@@ -113,61 +234,249 @@ fn read_datatype(
     Ok(value_type)
 }
 
-fn read_default_value(node: &serde_json::Value) -> Option<signal::Value> {
-    let default_value = node.get(TAG_DEFAULT);
-    if default_value.is_none() {
-        return None;
-    }
+fn read_default_value(node: &serde_json::Value, value_type: &signal::ValueType) -> Option<signal::Value> {
+    let value = node.get(TAG_DEFAULT)?;
+    Some(value_type.build_value(value))
+}
 
-    let value_type = read_datatype(node);
-    if let Err(_) = value_type {
-        return None;
+fn read_string(node: &serde_json::Value, tag: &str) -> Option<String> {
+    node.get(tag).and_then(|v| v.as_str()).map(String::from)
+}
+
+// min/max/allowed constrain each element of an array signal, so they are typed
+// with the element type rather than the array type.
+fn read_constraint_value(
+    node: &serde_json::Value,
+    tag: &str,
+    value_type: &signal::ValueType,
+) -> Option<signal::Value> {
+    let value = node.get(tag)?;
+    match value_type.element_type().build_value(value) {
+        signal::Value::NAN => None,
+        value => Some(value),
     }
-    let value_type = value_type.unwrap();
-    let value = default_value.unwrap();
-    let default_value = value_type.build_value(value);
-    Some(default_value)
+}
+
+fn read_allowed_values(
+    node: &serde_json::Value,
+    value_type: &signal::ValueType,
+) -> Option<Vec<signal::Value>> {
+    let allowed = node.get(TAG_ALLOWED)?.as_array()?;
+    let element_type = value_type.element_type();
+    let values = allowed
+        .iter()
+        .map(|value| element_type.build_value(value))
+        .filter(|value| *value != signal::Value::NAN)
+        .collect();
+    Some(values)
 }
 
 fn create_signal(
     path: String,
     node: &serde_json::Value,
 ) -> Result<signal::Signal, Box<dyn std::error::Error>> {
+    let config = create_config(node)?;
     let signal = signal::Signal {
-        path: path,
-        state: create_state(node)?,
-        config: create_config(node)?,
+        path,
+        id: 0,
+        state: create_state(&config),
+        config,
     };
     Ok(signal)
 }
 
-fn create_state(node: &serde_json::Value) -> Result<signal::State, Box<dyn std::error::Error>> {
-    let default_value = read_default_value(node).unwrap_or(signal::Value::NAN);
-    let ret = signal::State {
+fn create_state(config: &signal::Config) -> signal::State {
+    let default_value = config.default.clone().unwrap_or(signal::Value::NAN);
+    signal::State {
         value: default_value,
         capability: false,
         availability: false,
         lock_uuid: None,
         reserved: String::from("reserved"),
-    };
-    Ok(ret)
+        sequence: 0,
+        source_timestamp: None,
+        sink_timestamp: None,
+        target: None,
+    }
 }
 
 fn create_config(node: &serde_json::Value) -> Result<signal::Config, Box<dyn std::error::Error>> {
-    let leaf_type = read_type(&node)?;
-    let value_type = read_datatype(node)?;
+    overlay_config(node, None)
+}
+
+// Fields missing from `node` are kept from `base`. Values are typed by the
+// data type, so they are only kept while it stays the same.
+fn overlay_config(
+    node: &serde_json::Value,
+    base: Option<&signal::Config>,
+) -> Result<signal::Config, Box<dyn std::error::Error>> {
+    let leaf_type = match base {
+        Some(base) if node.get(TAG_TYPE).is_none() => base.leaf_type.clone(),
+        _ => read_type(node)?,
+    };
+    let value_type = match base {
+        Some(base) if node.get(TAG_DATATYPE).is_none() => base.data_type.clone(),
+        _ => read_datatype(node)?,
+    };
+    let typed_base = base.filter(|base| base.data_type == value_type);
+    let given = |tag: &str| node.get(tag).is_some();
+
     let ret = signal::Config {
-        leaf_type: leaf_type,
+        leaf_type,
+        deprecation: if given(TAG_DEPRECATION) {
+            read_string(node, TAG_DEPRECATION)
+        } else {
+            base.and_then(|base| base.deprecation.clone())
+        },
+        unit: if given(TAG_UNIT) {
+            read_string(node, TAG_UNIT)
+        } else {
+            base.and_then(|base| base.unit.clone())
+        },
+        min: if given(TAG_MIN) {
+            read_constraint_value(node, TAG_MIN, &value_type)
+        } else {
+            typed_base.and_then(|base| base.min.clone())
+        },
+        max: if given(TAG_MAX) {
+            read_constraint_value(node, TAG_MAX, &value_type)
+        } else {
+            typed_base.and_then(|base| base.max.clone())
+        },
+        description: if given(TAG_DESCRIPTION) {
+            read_string(node, TAG_DESCRIPTION)
+        } else {
+            base.and_then(|base| base.description.clone())
+        },
+        comment: if given(TAG_COMMENT) {
+            read_string(node, TAG_COMMENT)
+        } else {
+            base.and_then(|base| base.comment.clone())
+        },
+        allowd: if given(TAG_ALLOWED) {
+            read_allowed_values(node, &value_type)
+        } else {
+            typed_base.and_then(|base| base.allowd.clone())
+        },
+        default: if given(TAG_DEFAULT) {
+            read_default_value(node, &value_type)
+        } else {
+            typed_base.and_then(|base| base.default.clone())
+        },
         data_type: value_type,
-        deprecation: None,
-        unit: None,
-        min: None,
-        max: None,
-        description: None,
-        comment: None,
-        allowd: None,
-        default: None,
-        end_point: String::new(),
+        end_point: base.map(|base| base.end_point.clone()).unwrap_or_default(),
+        expression: if given(TAG_EXPRESSION) {
+            read_string(node, TAG_EXPRESSION)
+        } else {
+            base.and_then(|base| base.expression.clone())
+        },
+        update_period: if given(TAG_UPDATE_PERIOD) {
+            node.get(TAG_UPDATE_PERIOD).and_then(|v| v.as_u64()).filter(|period| *period > 0)
+        } else {
+            base.and_then(|base| base.update_period)
+        },
     };
     Ok(ret)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_create_config_reads_metadata() {
+        let node = serde_json::json!({
+            "type": "actuator",
+            "datatype": "uint8",
+            "unit": "percent",
+            "min": 0,
+            "max": 100,
+            "default": 50,
+            "description": "Item position.",
+            "comment": "Item dependent.",
+            "deprecation": "v5.0 moved to Position",
+            "update_period": 100
+        });
+
+        let config = create_config(&node).unwrap();
+        assert_eq!(config.unit, Some("percent".to_string()));
+        assert_eq!(config.min, Some(signal::Value::Uint8(0)));
+        assert_eq!(config.max, Some(signal::Value::Uint8(100)));
+        assert_eq!(config.default, Some(signal::Value::Uint8(50)));
+        assert_eq!(config.description, Some("Item position.".to_string()));
+        assert_eq!(config.comment, Some("Item dependent.".to_string()));
+        assert_eq!(config.deprecation, Some("v5.0 moved to Position".to_string()));
+        assert_eq!(config.allowd, None);
+        assert_eq!(config.update_period, Some(100));
+    }
+
+    #[test]
+    fn test_create_config_types_allowed_values_per_element() {
+        let node = serde_json::json!({
+            "type": "sensor",
+            "datatype": "string[]",
+            "allowed": ["INACTIVE", "OPEN"]
+        });
+
+        let config = create_config(&node).unwrap();
+        assert_eq!(
+            config.allowd,
+            Some(vec![
+                signal::Value::String("INACTIVE".to_string()),
+                signal::Value::String("OPEN".to_string()),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_overlay_adds_updates_and_deletes() {
+        let base = serde_json::json!({
+            "Vehicle": {"type": "branch", "children": {
+                "Speed": {"type": "sensor", "datatype": "float", "unit": "km/h"},
+                "Cabin": {"type": "branch", "children": {
+                    "Light": {"type": "actuator", "datatype": "uint8", "max": 100, "default": 0},
+                    "Legacy": {"type": "sensor", "datatype": "boolean"}
+                }}
+            }}
+        });
+        let mut signals = Vec::new();
+        load_branch("Vehicle".to_string(), &base["Vehicle"], &mut signals).unwrap();
+
+        let overlay = r#"{"Vehicle": {"children": {
+            "Private": {"children": {"Mode": {"type": "attribute", "datatype": "string", "default": "eco"}}},
+            "Cabin": {"children": {"Light": {"default": 50}, "Legacy": {"delete": true}}}
+        }}}"#;
+        apply_overlay(&mut signals, &read_overlay(overlay).unwrap()).unwrap();
+
+        let mut paths: Vec<&str> = signals.iter().map(|signal| signal.path.as_str()).collect();
+        paths.sort();
+        assert_eq!(paths, vec!["Vehicle.Cabin.Light", "Vehicle.Private.Mode", "Vehicle.Speed"]);
+        let light = signals.iter().find(|signal| signal.path == "Vehicle.Cabin.Light").unwrap();
+        assert_eq!(light.config.default, Some(signal::Value::Uint8(50)));
+        assert_eq!(light.config.max, Some(signal::Value::Uint8(100)));
+        assert_eq!(light.state.value, signal::Value::Uint8(50));
+    }
+
+    #[test]
+    fn test_overlay_is_rejected_as_a_whole() {
+        let base = serde_json::json!({"type": "branch", "children": {
+            "Speed": {"type": "sensor", "datatype": "uint8", "max": 200}
+        }});
+        let mut signals = Vec::new();
+        load_branch("Vehicle".to_string(), &base, &mut signals).unwrap();
+
+        for overlay in [
+            // new leaves need a type and a data type
+            r#"{"Vehicle": {"children": {"Gear": {"unit": "gear"}}}}"#,
+            // a leaf cannot become a branch
+            r#"{"Vehicle": {"children": {"Speed": {"children": {"Max": {"type": "sensor", "datatype": "uint8"}}}}}}"#,
+            r#"{"Vehicle": {"children": {"Speed": {"default": 250}}}}"#,
+            r#"{"Vehicle": {"children": {"Odometer": {"delete": true}}}}"#,
+        ] {
+            let result = read_overlay(overlay).and_then(|entries| apply_overlay(&mut signals, &entries));
+            assert!(result.is_err(), "{}", overlay);
+        }
+        assert_eq!(signals.len(), 1);
+        assert_eq!(signals[0].config.default, None);
+    }
+}
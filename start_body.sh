@@ -1,3 +1,4 @@
 export VSS_SERVER_ADDR="[::1]:50051"
+export VSS_ROUTES="Vehicle.Cabin=http://[::1]:50052"
 ./target/debug/vehicle-signal-shadow --vss data/body.json  
 